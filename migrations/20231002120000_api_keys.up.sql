@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS "ApiKeys"
+(
+    name VARCHAR PRIMARY KEY,
+    key_hash CHAR(64) NOT NULL UNIQUE,
+    deployments VARCHAR[],
+    routes VARCHAR[],
+    requests_per_minute BIGINT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
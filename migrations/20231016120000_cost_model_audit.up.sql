@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS "CostModelAudit"
+(
+    id BIGSERIAL PRIMARY KEY,
+    deployment VARCHAR NOT NULL,
+    action VARCHAR NOT NULL,
+    model TEXT,
+    variables JSONB,
+    previous_model TEXT,
+    previous_variables JSONB,
+    changed_by VARCHAR NOT NULL,
+    client_address VARCHAR,
+    changed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS cost_model_audit_deployment_idx ON "CostModelAudit" (deployment);
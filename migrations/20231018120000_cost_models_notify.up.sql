@@ -0,0 +1,21 @@
+CREATE FUNCTION cost_models_update_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    IF TG_OP = 'DELETE' OR TG_OP = 'UPDATE' THEN
+        PERFORM pg_notify('cost_models_update_notification', json_build_object('deployment', OLD.deployment)::text);
+    END IF;
+    IF TG_OP = 'INSERT' THEN
+        PERFORM pg_notify('cost_models_update_notification', json_build_object('deployment', NEW.deployment)::text);
+    ELSIF TG_OP = 'UPDATE' THEN
+        IF NEW.deployment <> OLD.deployment THEN
+            PERFORM pg_notify('cost_models_update_notification', json_build_object('deployment', NEW.deployment)::text);
+        END IF;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE TRIGGER cost_models_update AFTER INSERT OR UPDATE OR DELETE
+    ON "CostModels"
+    FOR EACH ROW EXECUTE PROCEDURE cost_models_update_notify();
@@ -2,15 +2,21 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use alloy_primitives::Address;
 use anyhow::Result;
 use log::{info, warn};
 use tokio::sync::watch::{Receiver, Sender};
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
 
-use crate::{common::allocation::Allocation, common::network_subgraph::NetworkSubgraph};
+use crate::{
+    common::allocation::{Allocation, SubgraphDeployment},
+    common::network_subgraph::NetworkSubgraph,
+    common::types::SubgraphDeploymentID,
+    metrics,
+};
 
 #[derive(Debug)]
 struct AllocationMonitorInner {
@@ -19,6 +25,9 @@ struct AllocationMonitorInner {
     interval_ms: u64,
     graph_network_id: u64,
     eligible_allocations: Arc<RwLock<HashMap<Address, Allocation>>>,
+    synced: AtomicBool,
+    resync: Notify,
+    shutdown: Notify,
     watch_sender: Sender<()>,
     watch_receiver: Receiver<()>,
 }
@@ -47,6 +56,9 @@ impl AllocationMonitor {
             interval_ms,
             graph_network_id,
             eligible_allocations: Arc::new(RwLock::new(HashMap::new())),
+            synced: AtomicBool::new(false),
+            resync: Notify::new(),
+            shutdown: Notify::new(),
             watch_sender,
             watch_receiver,
         });
@@ -203,12 +215,27 @@ impl AllocationMonitor {
     async fn update_allocations(inner: &Arc<AllocationMonitorInner>) -> Result<(), anyhow::Error> {
         let current_epoch =
             Self::current_epoch(&inner.network_subgraph, inner.graph_network_id).await?;
-        *(inner.eligible_allocations.write().await) = Self::current_eligible_allocations(
+        let eligible_allocations = Self::current_eligible_allocations(
             &inner.network_subgraph,
             &inner.indexer_address,
             current_epoch - 1,
         )
         .await?;
+
+        let mut eligible_allocations_write = inner.eligible_allocations.write().await;
+        let previously_denied = denied_deployments(&eligible_allocations_write);
+        for deployment in denied_deployments(&eligible_allocations) {
+            if !previously_denied.contains(&deployment) {
+                warn!(
+                    "Allocated deployment {} has been denied, refusing paid queries",
+                    deployment.ipfs_hash()
+                );
+                metrics::DENIED_DEPLOYMENTS
+                    .with_label_values(&[&deployment.ipfs_hash()])
+                    .inc();
+            }
+        }
+        *eligible_allocations_write = eligible_allocations;
         Ok(())
     }
 
@@ -216,6 +243,7 @@ impl AllocationMonitor {
         loop {
             match Self::update_allocations(inner).await {
                 Ok(_) => {
+                    inner.synced.store(true, Ordering::Relaxed);
                     if inner.watch_sender.send(()).is_err() {
                         warn!(
                             "Failed to notify subscribers that the allocations have been updated"
@@ -255,22 +283,65 @@ impl AllocationMonitor {
                     .join(", ")
             );
 
-            tokio::time::sleep(tokio::time::Duration::from_millis(inner.interval_ms)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(tokio::time::Duration::from_millis(inner.interval_ms)) => {}
+                _ = inner.resync.notified() => {}
+                _ = inner.shutdown.notified() => {
+                    info!("Allocation monitor stopped");
+                    return Ok(());
+                }
+            }
         }
     }
 
+    /// Stops the monitor loop. Eligible allocations are kept as they were last synced.
+    pub fn stop(&self) {
+        self.inner.shutdown.notify_one();
+    }
+
+    /// Syncs the eligible allocations right away instead of waiting for the next interval. A resync
+    /// requested while syncing runs as soon as the current one completes.
+    pub fn resync(&self) {
+        self.inner.resync.notify_one();
+    }
+
     pub async fn get_eligible_allocations(
         &self,
     ) -> tokio::sync::RwLockReadGuard<'_, HashMap<Address, Allocation>> {
         self.inner.eligible_allocations.read().await
     }
 
-    pub async fn is_allocation_eligible(&self, allocation_id: &Address) -> bool {
+    /// Returns the deployment of an eligible allocation
+    pub async fn allocation_deployment(
+        &self,
+        allocation_id: &Address,
+    ) -> Option<SubgraphDeployment> {
+        self.inner
+            .eligible_allocations
+            .read()
+            .await
+            .get(allocation_id)
+            .map(|allocation| allocation.subgraph_deployment.clone())
+    }
+
+    pub async fn is_deployment_allocated(&self, deployment: &SubgraphDeploymentID) -> bool {
         self.inner
             .eligible_allocations
             .read()
             .await
-            .contains_key(allocation_id)
+            .values()
+            .any(|allocation| &allocation.subgraph_deployment.id == deployment)
+    }
+
+    /// Deployments of eligible allocations that have been denied
+    pub async fn denied_deployments(&self) -> Vec<SubgraphDeploymentID> {
+        denied_deployments(&*self.inner.eligible_allocations.read().await)
+    }
+
+    /// Returns true once the eligible allocations have been synced from the network subgraph at
+    /// least once.
+    pub fn has_synced(&self) -> bool {
+        self.inner.synced.load(Ordering::Relaxed)
     }
 
     pub fn subscribe(&self) -> Receiver<()> {
@@ -278,6 +349,17 @@ impl AllocationMonitor {
     }
 }
 
+fn denied_deployments(allocations: &HashMap<Address, Allocation>) -> Vec<SubgraphDeploymentID> {
+    let mut denied = Vec::new();
+    for allocation in allocations.values() {
+        let deployment = &allocation.subgraph_deployment;
+        if deployment.is_denied() && !denied.contains(&deployment.id) {
+            denied.push(deployment.id.clone());
+        }
+    }
+    denied
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
@@ -291,6 +373,24 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_denied_deployments() {
+        let mut allocations = test_vectors::expected_eligible_allocations();
+        assert!(denied_deployments(&allocations).is_empty());
+
+        let denied = SubgraphDeploymentID::new(
+            "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a",
+        )
+        .unwrap();
+        for allocation in allocations.values_mut() {
+            if allocation.subgraph_deployment.id == denied {
+                allocation.subgraph_deployment.denied_at = Some(900);
+            }
+        }
+        // Two allocations share the denied deployment
+        assert_eq!(denied_deployments(&allocations), vec![denied]);
+    }
+
     #[test(tokio::test)]
     async fn test_current_epoch() {
         let mock_server = MockServer::start().await;
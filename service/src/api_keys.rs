@@ -0,0 +1,425 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use ethers_core::rand::{thread_rng, RngCore};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+use sqlx::{PgPool, Row};
+use tokio::sync::Notify;
+
+use crate::{
+    common::types::SubgraphDeploymentID, config::RateLimit, metrics,
+    server::rate_limit::KeyedRateLimiter,
+};
+
+/// Routes an API key can be restricted to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiRoute {
+    Subgraphs,
+    Network,
+    Status,
+}
+
+impl ApiRoute {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiRoute::Subgraphs => "subgraphs",
+            ApiRoute::Network => "network",
+            ApiRoute::Status => "status",
+        }
+    }
+
+    fn parse(route: &str) -> Option<ApiRoute> {
+        match route {
+            "subgraphs" => Some(ApiRoute::Subgraphs),
+            "network" => Some(ApiRoute::Network),
+            "status" => Some(ApiRoute::Status),
+            _ => None,
+        }
+    }
+}
+
+/// A named API key for free queries and its scopes. Missing scopes are unrestricted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKey {
+    pub name: String,
+    /// Deployments (IPFS hashes) the key can query
+    pub deployments: Option<Vec<String>>,
+    /// Routes the key can be used on
+    pub routes: Option<Vec<ApiRoute>>,
+    /// Maximum number of requests per minute
+    pub requests_per_minute: Option<u64>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiKeyError {
+    #[error("API key {0} is not allowed on /{1}")]
+    RouteNotAllowed(String, &'static str),
+    #[error("API key {0} is not allowed to query deployment {1}")]
+    DeploymentNotAllowed(String, String),
+    #[error("API key {0} exceeded its rate limit")]
+    RateLimited(String, Duration),
+}
+
+#[derive(Debug)]
+struct ApiKeyEntry {
+    key: ApiKey,
+    rate_limiter: Option<KeyedRateLimiter>,
+}
+
+impl ApiKeyEntry {
+    fn new(key: ApiKey) -> Self {
+        let rate_limiter = key.requests_per_minute.map(|limit| {
+            KeyedRateLimiter::new(
+                "api_key",
+                &RateLimit {
+                    limit,
+                    period_ms: 60_000,
+                    keys: vec![],
+                },
+            )
+        });
+        ApiKeyEntry { key, rate_limiter }
+    }
+
+    /// Replaces the key and its scopes, keeping the rate limit windows unless the limit changed
+    fn update(self, key: ApiKey) -> Self {
+        if key.requests_per_minute != self.key.requests_per_minute {
+            return ApiKeyEntry::new(key);
+        }
+        ApiKeyEntry {
+            key,
+            rate_limiter: self.rate_limiter,
+        }
+    }
+
+    fn authorize(
+        &self,
+        route: ApiRoute,
+        deployment: Option<&SubgraphDeploymentID>,
+        now: Instant,
+    ) -> Result<(), ApiKeyError> {
+        let name = &self.key.name;
+        if let Some(routes) = &self.key.routes {
+            if !routes.contains(&route) {
+                return Err(ApiKeyError::RouteNotAllowed(name.clone(), route.as_str()));
+            }
+        }
+        if let (Some(deployments), Some(deployment)) = (&self.key.deployments, deployment) {
+            let deployment = deployment.ipfs_hash();
+            if !deployments.contains(&deployment) {
+                return Err(ApiKeyError::DeploymentNotAllowed(name.clone(), deployment));
+            }
+        }
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter
+                .check(name.clone(), now)
+                .map_err(|retry_after| ApiKeyError::RateLimited(name.clone(), retry_after))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ApiKeysInner {
+    database: PgPool,
+    keys: RwLock<HashMap<String, ApiKeyEntry>>,
+    shutdown: Notify,
+}
+
+/// API keys stored in the `ApiKeys` table, keyed by the hash of the key. Keys themselves
+/// are never stored, they are only returned once when created. Keys are reloaded
+/// periodically to pick up the changes made through other service instances.
+#[derive(Debug, Clone)]
+pub struct ApiKeys {
+    _reload_handle: Arc<tokio::task::JoinHandle<()>>,
+    inner: Arc<ApiKeysInner>,
+}
+
+impl ApiKeys {
+    pub fn new(database: PgPool, reload_interval_ms: u64) -> Self {
+        let inner = Arc::new(ApiKeysInner {
+            database,
+            keys: RwLock::new(HashMap::new()),
+            shutdown: Notify::new(),
+        });
+
+        let inner_clone = inner.clone();
+        ApiKeys {
+            _reload_handle: Arc::new(tokio::spawn(async move {
+                ApiKeys::reload_loop(&inner_clone, reload_interval_ms).await;
+            })),
+            inner,
+        }
+    }
+
+    async fn reload_loop(inner: &Arc<ApiKeysInner>, interval_ms: u64) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_millis(interval_ms)) => {}
+                _ = inner.shutdown.notified() => {
+                    info!("API key reloading stopped");
+                    return;
+                }
+            }
+
+            if let Err(e) = Self::load(inner).await {
+                warn!("Failed to reload API keys: {}", e);
+            }
+        }
+    }
+
+    /// Stops reloading API keys. Keys are kept as they were last loaded.
+    pub fn stop(&self) {
+        self.inner.shutdown.notify_one();
+    }
+
+    /// Loads all API keys from the database, replacing the ones in memory
+    pub async fn reload(&self) -> Result<(), sqlx::Error> {
+        Self::load(&self.inner).await
+    }
+
+    async fn load(inner: &ApiKeysInner) -> Result<(), sqlx::Error> {
+        let rows = sqlx::query(
+            r#"
+                SELECT name, key_hash, deployments, routes, requests_per_minute
+                FROM "ApiKeys"
+            "#,
+        )
+        .fetch_all(&inner.database)
+        .await?;
+
+        let mut keys = HashMap::new();
+        for row in rows {
+            let routes: Option<Vec<String>> = row.try_get("routes")?;
+            let requests_per_minute: Option<i64> = row.try_get("requests_per_minute")?;
+            let key = ApiKey {
+                name: row.try_get("name")?,
+                deployments: row.try_get("deployments")?,
+                routes: routes.map(|routes| {
+                    routes
+                        .iter()
+                        .filter_map(|route| ApiRoute::parse(route))
+                        .collect()
+                }),
+                requests_per_minute: requests_per_minute.map(|limit| limit.max(0) as u64),
+            };
+            keys.insert(row.try_get("key_hash")?, key);
+        }
+
+        let mut entries = inner.keys.write().unwrap();
+        *entries = merge_entries(std::mem::take(&mut *entries), keys);
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<ApiKey> {
+        let mut keys = self
+            .inner
+            .keys
+            .read()
+            .unwrap()
+            .values()
+            .map(|entry| entry.key.clone())
+            .collect::<Vec<ApiKey>>();
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        keys
+    }
+
+    /// Stores a new API key, returning the generated key
+    pub async fn create(&self, key: ApiKey) -> Result<String, sqlx::Error> {
+        let mut secret = [0u8; 32];
+        thread_rng().fill_bytes(&mut secret);
+        let secret = hex::encode(secret);
+
+        sqlx::query(
+            r#"
+                INSERT INTO "ApiKeys" (name, key_hash, deployments, routes, requests_per_minute)
+                VALUES ($1, $2, $3, $4, $5)
+            "#,
+        )
+        .bind(&key.name)
+        .bind(key_hash(&secret))
+        .bind(&key.deployments)
+        .bind(key.routes.as_ref().map(|routes| {
+            routes
+                .iter()
+                .map(|route| route.as_str().to_string())
+                .collect::<Vec<String>>()
+        }))
+        .bind(key.requests_per_minute.map(|limit| limit as i64))
+        .execute(&self.inner.database)
+        .await?;
+
+        self.reload().await?;
+        Ok(secret)
+    }
+
+    /// Deletes an API key, returning whether it existed
+    pub async fn delete(&self, name: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(r#"DELETE FROM "ApiKeys" WHERE name = $1"#)
+            .bind(name)
+            .execute(&self.inner.database)
+            .await?;
+
+        self.reload().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Whether the `Authorization` header value is a known API key
+    pub fn contains(&self, auth_token: &str) -> bool {
+        self.inner
+            .keys
+            .read()
+            .unwrap()
+            .contains_key(&key_hash(bearer(auth_token)))
+    }
+
+    /// Checks an `Authorization` header value against the API keys and their scopes.
+    /// Returns None if it is not an API key, otherwise the name of the authorized key.
+    pub fn authorize(
+        &self,
+        auth_token: &str,
+        route: ApiRoute,
+        deployment: Option<&SubgraphDeploymentID>,
+    ) -> Option<Result<String, ApiKeyError>> {
+        let keys = self.inner.keys.read().unwrap();
+        let entry = keys.get(&key_hash(bearer(auth_token)))?;
+        let result = entry.authorize(route, deployment, Instant::now());
+
+        let outcome = match &result {
+            Ok(()) => "authorized",
+            Err(ApiKeyError::RateLimited(..)) => "rate_limited",
+            Err(_) => "forbidden",
+        };
+        metrics::API_KEY_REQUESTS
+            .with_label_values(&[&entry.key.name, route.as_str(), outcome])
+            .inc();
+
+        Some(result.map(|_| entry.key.name.clone()))
+    }
+}
+
+/// Entries of the loaded keys, carrying over the rate limit windows of the keys that were
+/// already loaded so that reloading does not reset them
+fn merge_entries(
+    mut previous: HashMap<String, ApiKeyEntry>,
+    keys: HashMap<String, ApiKey>,
+) -> HashMap<String, ApiKeyEntry> {
+    keys.into_iter()
+        .map(|(key_hash, key)| {
+            let entry = match previous.remove(&key_hash) {
+                Some(entry) => entry.update(key),
+                None => ApiKeyEntry::new(key),
+            };
+            (key_hash, entry)
+        })
+        .collect()
+}
+
+/// API keys may be sent with or without the `Bearer` scheme
+fn bearer(auth_token: &str) -> &str {
+    auth_token.strip_prefix("Bearer ").unwrap_or(auth_token)
+}
+
+fn key_hash(key: &str) -> String {
+    hex::encode(Keccak256::digest(key.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment() -> SubgraphDeploymentID {
+        SubgraphDeploymentID::new("QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ").unwrap()
+    }
+
+    #[test]
+    fn checks_scopes() {
+        let entry = ApiKeyEntry::new(ApiKey {
+            name: "dashboards".to_string(),
+            deployments: Some(vec![deployment().ipfs_hash()]),
+            routes: Some(vec![ApiRoute::Subgraphs]),
+            requests_per_minute: None,
+        });
+        let now = Instant::now();
+
+        assert!(entry
+            .authorize(ApiRoute::Subgraphs, Some(&deployment()), now)
+            .is_ok());
+        assert!(matches!(
+            entry.authorize(ApiRoute::Network, None, now),
+            Err(ApiKeyError::RouteNotAllowed(..))
+        ));
+        let other =
+            SubgraphDeploymentID::new("QmacQnSgia4iDPWHpeY6aWxesRFdb8o5DKZUx96zZqEWrB").unwrap();
+        assert!(matches!(
+            entry.authorize(ApiRoute::Subgraphs, Some(&other), now),
+            Err(ApiKeyError::DeploymentNotAllowed(..))
+        ));
+    }
+
+    #[test]
+    fn rate_limits_keys() {
+        let entry = ApiKeyEntry::new(ApiKey {
+            name: "analytics".to_string(),
+            deployments: None,
+            routes: None,
+            requests_per_minute: Some(2),
+        });
+        let now = Instant::now();
+
+        assert!(entry.authorize(ApiRoute::Status, None, now).is_ok());
+        assert!(entry.authorize(ApiRoute::Network, None, now).is_ok());
+        assert!(matches!(
+            entry.authorize(ApiRoute::Subgraphs, Some(&deployment()), now),
+            Err(ApiKeyError::RateLimited(..))
+        ));
+        assert!(entry
+            .authorize(ApiRoute::Status, None, now + Duration::from_secs(60))
+            .is_ok());
+    }
+
+    #[test]
+    fn reloading_keeps_rate_limits() {
+        let key = |requests_per_minute| ApiKey {
+            name: "analytics".to_string(),
+            deployments: None,
+            routes: None,
+            requests_per_minute: Some(requests_per_minute),
+        };
+        let now = Instant::now();
+        let previous = HashMap::from([("a".to_string(), ApiKeyEntry::new(key(1)))]);
+        assert!(previous["a"].authorize(ApiRoute::Status, None, now).is_ok());
+
+        // Still limited with the same limit, other keys are added and removed keys dropped
+        let entries = merge_entries(
+            previous,
+            HashMap::from([("a".to_string(), key(1)), ("b".to_string(), key(1))]),
+        );
+        assert_eq!(entries.len(), 2);
+        assert!(matches!(
+            entries["a"].authorize(ApiRoute::Status, None, now),
+            Err(ApiKeyError::RateLimited(..))
+        ));
+        assert!(entries["b"].authorize(ApiRoute::Status, None, now).is_ok());
+
+        // A new limit starts new windows
+        let entries = merge_entries(entries, HashMap::from([("a".to_string(), key(2))]));
+        assert_eq!(entries.len(), 1);
+        assert!(entries["a"].authorize(ApiRoute::Status, None, now).is_ok());
+    }
+
+    #[test]
+    fn bearer_scheme_is_optional() {
+        assert_eq!(bearer("Bearer abc"), "abc");
+        assert_eq!(bearer("abc"), "abc");
+        assert_eq!(key_hash(bearer("Bearer abc")), key_hash("abc"));
+    }
+}
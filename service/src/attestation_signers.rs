@@ -7,7 +7,7 @@ use log::{error, info, warn};
 use native::attestation::AttestationSigner;
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
 
 use crate::{
     allocation_monitor::AllocationMonitor, common::allocation::allocation_signer,
@@ -27,6 +27,7 @@ struct AttestationSignersInner {
     indexer_mnemonic: String,
     chain_id: U256,
     dispute_manager: Address,
+    shutdown: Notify,
 }
 
 impl AttestationSigners {
@@ -42,6 +43,7 @@ impl AttestationSigners {
             indexer_mnemonic,
             chain_id,
             dispute_manager,
+            shutdown: Notify::new(),
         });
 
         let _update_loop_handle = {
@@ -97,20 +99,31 @@ impl AttestationSigners {
         let mut watch_receiver = inner.allocation_monitor.subscribe();
 
         loop {
-            match watch_receiver.changed().await {
-                Ok(_) => {
-                    Self::update_attestation_signers(inner.clone()).await;
-                }
-                Err(e) => {
-                    error!(
-                        "Error receiving allocation monitor subscription update: {}",
-                        e
-                    );
+            tokio::select! {
+                changed = watch_receiver.changed() => match changed {
+                    Ok(_) => {
+                        Self::update_attestation_signers(inner.clone()).await;
+                    }
+                    Err(e) => {
+                        error!(
+                            "Error receiving allocation monitor subscription update: {}",
+                            e
+                        );
+                    }
+                },
+                _ = inner.shutdown.notified() => {
+                    info!("Attestation signers update loop stopped");
+                    return;
                 }
             }
         }
     }
 
+    /// Stops the update loop. Attestation signers that were already loaded are kept.
+    pub fn stop(&self) {
+        self.inner.shutdown.notify_one();
+    }
+
     pub async fn read(
         &self,
     ) -> tokio::sync::RwLockReadGuard<'_, HashMap<Address, AttestationSigner>> {
@@ -152,6 +165,7 @@ mod tests {
                 indexer_mnemonic: test_vectors::INDEXER_OPERATOR_MNEMONIC.to_string(),
                 chain_id: U256::from(1),
                 dispute_manager: Address::from_str(test_vectors::DISPUTE_MANAGER_ADDRESS).unwrap(),
+                shutdown: Notify::new(),
             });
 
             AttestationSigners::update_attestation_signers(inner.clone()).await;
@@ -40,7 +40,7 @@ pub enum AllocationStatus {
     Claimed,
 }
 
-#[derive(Debug, Eq, PartialEq, Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
 pub struct SubgraphDeployment {
     pub id: SubgraphDeploymentID,
     #[serde(rename = "deniedAt")]
@@ -53,6 +53,13 @@ pub struct SubgraphDeployment {
     pub query_fees_amount: U256,
 }
 
+impl SubgraphDeployment {
+    /// The network subgraph reports `deniedAt` as 0 for deployments that were never denied
+    pub fn is_denied(&self) -> bool {
+        self.denied_at.map_or(false, |denied_at| denied_at > 0)
+    }
+}
+
 impl<'d> Deserialize<'d> for Allocation {
     fn deserialize<D>(deserializer: D) -> Result<Allocation, D::Error>
     where
@@ -0,0 +1,912 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use bigdecimal::{BigDecimal, Zero};
+use graphql_parser::query::{
+    Definition, Field, OperationDefinition, Selection, SelectionSet, Value as QueryValue,
+};
+use serde_json::{Map, Value};
+
+use crate::common::types::GraphQLQuery;
+
+/// Maximum depth of nested fragments in priced queries, which also stops fragment cycles
+const MAX_FRAGMENT_DEPTH: usize = 16;
+/// Costs are written in GRT and charged in wei
+const WEI_PER_GRT: u64 = 1_000_000_000_000_000_000;
+
+/// Binary operators by increasing precedence. Longer operators come first so that they are
+/// not read as their prefix.
+const BINARY_OPERATORS: [(&str, u8); 12] = [
+    ("||", 1),
+    ("&&", 2),
+    ("==", 3),
+    ("!=", 3),
+    ("<=", 3),
+    (">=", 3),
+    ("<", 3),
+    (">", 3),
+    ("+", 4),
+    ("-", 4),
+    ("*", 5),
+    ("/", 5),
+];
+
+/// An Agora cost model: `;`-terminated statements of either `default => <cost>` or
+/// `<query> [when <condition>] => <cost>`, with `#` comments. A query pattern selects a
+/// single top level field, whose arguments may capture the queried values as `$variables`.
+/// Costs and conditions are expressions over numbers, booleans, strings and `$variables`,
+/// either captured or given with the model.
+#[derive(Debug, Clone)]
+pub struct Model {
+    statements: Vec<Statement>,
+    variables: Map<String, Value>,
+}
+
+#[derive(Debug, Clone)]
+struct Statement {
+    /// Top level field priced by the statement, None for `default`
+    pattern: Option<Field<'static, String>>,
+    condition: Option<Expression>,
+    cost: Expression,
+}
+
+#[derive(Debug, Clone)]
+enum Expression {
+    Constant(Constant),
+    Variable(String),
+    Unary(&'static str, Box<Expression>),
+    Binary(&'static str, Box<Expression>, Box<Expression>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Constant {
+    Number(BigDecimal),
+    Boolean(bool),
+    String(String),
+}
+
+impl Model {
+    /// Parses a model and its variables, a JSON object of the values of `$names` that are
+    /// not captured from queries
+    pub fn parse(model: &str, variables: Option<&Value>) -> Result<Model, String> {
+        let variables = match variables {
+            None | Some(Value::Null) => Map::new(),
+            Some(Value::Object(variables)) => variables.clone(),
+            Some(_) => return Err("Cost model variables must be a JSON object".to_string()),
+        };
+        Ok(Model {
+            statements: Parser::new(model).statements()?,
+            variables,
+        })
+    }
+
+    /// Cost in wei of a `{ query, variables }` request: the sum of the costs of its top level
+    /// fields, each priced by the first statement that matches it and whose condition holds
+    pub fn cost(&self, request: &GraphQLQuery) -> Result<u128, String> {
+        let document = graphql_parser::parse_query::<String>(&request.query)
+            .map_err(|e| format!("Invalid GraphQL query: {}", e))?
+            .into_static();
+
+        let mut fragments = HashMap::new();
+        let mut operations = vec![];
+        for definition in &document.definitions {
+            match definition {
+                Definition::Fragment(fragment) => {
+                    fragments.insert(fragment.name.as_str(), &fragment.selection_set);
+                }
+                Definition::Operation(OperationDefinition::SelectionSet(selection_set)) => {
+                    operations.push((&[][..], selection_set))
+                }
+                Definition::Operation(OperationDefinition::Query(query)) => {
+                    operations.push((&query.variable_definitions[..], &query.selection_set))
+                }
+                Definition::Operation(_) => return Err("Only queries can be priced".to_string()),
+            }
+        }
+        let (variable_definitions, selection_set) = match operations[..] {
+            [operation] => operation,
+            _ => return Err("Expected a single query operation".to_string()),
+        };
+
+        let mut variables = match &request.variables {
+            None | Some(Value::Null) => Map::new(),
+            Some(Value::Object(variables)) => variables.clone(),
+            Some(_) => return Err("Query variables must be a JSON object".to_string()),
+        };
+        for definition in variable_definitions {
+            if let Some(default) = &definition.default_value {
+                variables
+                    .entry(definition.name.clone())
+                    .or_insert_with(|| json_value(default, &Map::new()));
+            }
+        }
+
+        let query = PricedQuery {
+            fragments,
+            variables,
+        };
+        let mut cost = BigDecimal::zero();
+        for field in query.fields(selection_set, 0)? {
+            cost += self.field_cost(&query, field)?;
+        }
+
+        if cost < BigDecimal::zero() {
+            return Err(format!("Negative cost {}", cost));
+        }
+        (cost * BigDecimal::from(WEI_PER_GRT))
+            .with_scale(0)
+            .to_string()
+            .parse::<u128>()
+            .map_err(|_| "Cost is too large".to_string())
+    }
+
+    fn field_cost(
+        &self,
+        query: &PricedQuery,
+        field: &Field<'static, String>,
+    ) -> Result<BigDecimal, String> {
+        for statement in &self.statements {
+            let mut captures = HashMap::new();
+            if let Some(pattern) = &statement.pattern {
+                if !query.match_field(pattern, field, &mut captures)? {
+                    continue;
+                }
+            }
+
+            let scope = Scope {
+                captures: &captures,
+                globals: &self.variables,
+            };
+            if let Some(condition) = &statement.condition {
+                match condition.evaluate(&scope)? {
+                    Constant::Boolean(true) => {}
+                    Constant::Boolean(false) => continue,
+                    _ => return Err("Conditions must be booleans".to_string()),
+                }
+            }
+            return match statement.cost.evaluate(&scope)? {
+                Constant::Number(cost) => Ok(cost),
+                _ => Err("Costs must be numbers".to_string()),
+            };
+        }
+        Err(format!("No statement prices the `{}` field", field.name))
+    }
+}
+
+/// Checks that a model parses
+pub fn validate_model(model: &str) -> Result<(), String> {
+    Parser::new(model).statements().map(|_| ())
+}
+
+/// Cost model variables are a JSON object of values referenced as `$name` in the model
+pub fn validate_variables(variables: &Value) -> Result<(), String> {
+    match variables {
+        Value::Object(_) => Ok(()),
+        _ => Err("Cost model variables must be a JSON object".to_string()),
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Parser { text, position: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    /// Skips whitespace and `#` comments
+    fn skip(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.position += rest.len() - trimmed.len();
+            if !trimmed.starts_with('#') {
+                return;
+            }
+            self.position += trimmed.find('\n').unwrap_or(trimmed.len());
+        }
+    }
+
+    /// Description of what is next, for errors
+    fn found(&self) -> String {
+        let next = self.rest().split_whitespace().next();
+        match next {
+            Some(next) => format!("but found `{}`", next),
+            None => "at the end of the model".to_string(),
+        }
+    }
+
+    /// Consumes the keyword if it is the next word
+    fn keyword(&mut self, keyword: &str) -> bool {
+        self.skip();
+        let rest = self.rest();
+        let found = rest.starts_with(keyword) && !rest[keyword.len()..].starts_with(is_name_char);
+        if found {
+            self.position += keyword.len();
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        self.skip();
+        if !self.rest().starts_with(symbol) {
+            return Err(format!("Expected `{}` {}", symbol, self.found()));
+        }
+        self.position += symbol.len();
+        Ok(())
+    }
+
+    fn statements(&mut self) -> Result<Vec<Statement>, String> {
+        let mut statements = vec![];
+        loop {
+            self.skip();
+            if self.rest().is_empty() {
+                break;
+            }
+            let statement = self
+                .statement()
+                .map_err(|e| format!("Statement {}: {}", statements.len() + 1, e))?;
+            statements.push(statement);
+        }
+
+        if statements.is_empty() {
+            return Err("Cost model has no statements".to_string());
+        }
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Statement, String> {
+        let pattern = if self.keyword("default") {
+            None
+        } else {
+            Some(self.query_pattern()?)
+        };
+        let condition = if pattern.is_some() && self.keyword("when") {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.expect("=>")?;
+        let cost = self.expression()?;
+        self.expect(";")?;
+        Ok(Statement {
+            pattern,
+            condition,
+            cost,
+        })
+    }
+
+    fn query_pattern(&mut self) -> Result<Field<'static, String>, String> {
+        self.skip();
+        let rest = self.rest();
+        if !rest.starts_with('{') && !self.keyword("query") {
+            return Err(format!(
+                "Expected `default` or a GraphQL query {}",
+                self.found()
+            ));
+        }
+        let length = query_length(rest)?;
+        self.position = self.text.len() - rest.len() + length;
+
+        let mut definitions = graphql_parser::parse_query::<String>(&rest[..length])
+            .map_err(|e| format!("Invalid GraphQL query: {}", e))?
+            .into_static()
+            .definitions;
+        let selection_set = match (definitions.pop(), definitions.is_empty()) {
+            (Some(Definition::Operation(OperationDefinition::Query(query))), true) => {
+                query.selection_set
+            }
+            (
+                Some(Definition::Operation(OperationDefinition::SelectionSet(selection_set))),
+                true,
+            ) => selection_set,
+            _ => return Err("Expected a single query".to_string()),
+        };
+        let mut items = selection_set.items;
+        match (items.pop(), items.is_empty()) {
+            (Some(Selection::Field(field)), true) => Ok(field),
+            _ => Err("Queries must select exactly one top level field".to_string()),
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expression, String> {
+        self.binary(1)
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> Result<Expression, String> {
+        let mut left = self.unary()?;
+        loop {
+            self.skip();
+            let rest = self.rest();
+            let (operator, precedence) = match BINARY_OPERATORS
+                .iter()
+                .find(|(operator, _)| rest.starts_with(operator))
+            {
+                Some(&(operator, precedence)) if precedence >= min_precedence => {
+                    (operator, precedence)
+                }
+                _ => return Ok(left),
+            };
+            self.position += operator.len();
+            let right = self.binary(precedence + 1)?;
+            left = Expression::Binary(operator, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        self.skip();
+        for operator in ["-", "!"] {
+            if self.rest().starts_with(operator) {
+                self.position += operator.len();
+                return Ok(Expression::Unary(operator, Box::new(self.unary()?)));
+            }
+        }
+        self.operand()
+    }
+
+    fn operand(&mut self) -> Result<Expression, String> {
+        self.skip();
+        let rest = self.rest();
+        let c = rest.chars().next().ok_or("Unexpected end of expression")?;
+
+        if c == '(' {
+            self.position += 1;
+            let expression = self.expression()?;
+            self.expect(")")?;
+            return Ok(expression);
+        }
+        if c.is_ascii_digit() {
+            let length = number_length(rest);
+            let number = BigDecimal::from_str(&rest[..length])
+                .map_err(|_| format!("Invalid number `{}`", &rest[..length]))?;
+            self.position += length;
+            return Ok(Expression::Constant(Constant::Number(number)));
+        }
+        if c == '"' {
+            let (string, length) = string_literal(rest)?;
+            self.position += length;
+            return Ok(Expression::Constant(Constant::String(string)));
+        }
+
+        let (sigil, name) = match rest.strip_prefix('$') {
+            Some(variable) => ("$", variable),
+            None => ("", rest),
+        };
+        let length = name.find(|c| !is_name_char(c)).unwrap_or(name.len());
+        match (sigil, &name[..length]) {
+            (_, "") => Err(format!("Unexpected `{}`", c)),
+            ("$", variable) => {
+                self.position += 1 + length;
+                Ok(Expression::Variable(variable.to_string()))
+            }
+            (_, "true") | (_, "false") => {
+                self.position += length;
+                Ok(Expression::Constant(Constant::Boolean(
+                    &name[..length] == "true",
+                )))
+            }
+            (_, word) => Err(format!("Unexpected `{}`", word)),
+        }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Length of the number at the start of `text`: digits with an optional fraction and exponent
+fn number_length(text: &str) -> usize {
+    let digits = |text: &str| {
+        text.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len())
+    };
+
+    let mut length = digits(text);
+    if let Some(fraction) = text[length..].strip_prefix('.') {
+        if digits(fraction) > 0 {
+            length += 1 + digits(fraction);
+        }
+    }
+    if let Some(exponent) = text[length..].strip_prefix(['e', 'E']) {
+        let sign = usize::from(exponent.starts_with(['+', '-']));
+        if digits(&exponent[sign..]) > 0 {
+            length += 1 + sign + digits(&exponent[sign..]);
+        }
+    }
+    length
+}
+
+/// Value and length of the `"`-quoted string at the start of `text`
+fn string_literal(text: &str) -> Result<(String, usize), String> {
+    let mut value = String::new();
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, i + 1)),
+            '\\' => match chars.next() {
+                Some((_, c)) => value.push(c),
+                None => break,
+            },
+            c => value.push(c),
+        }
+    }
+    Err("Unterminated string".to_string())
+}
+
+/// Length of the GraphQL query at the start of `text`, up to the `}` closing its selection set
+fn query_length(text: &str) -> Result<usize, String> {
+    let mut depth = 0;
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(i + 1);
+                }
+            }
+            '"' => loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => {
+                        chars.next();
+                    }
+                    Some(_) => {}
+                    None => return Err("Unterminated string".to_string()),
+                }
+            },
+            '#' => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Err("Unterminated GraphQL query".to_string())
+}
+
+/// Variables of an expression: the values captured from the query, then the model's
+struct Scope<'a> {
+    captures: &'a HashMap<String, Value>,
+    globals: &'a Map<String, Value>,
+}
+
+impl Expression {
+    fn evaluate(&self, scope: &Scope) -> Result<Constant, String> {
+        match self {
+            Expression::Constant(constant) => Ok(constant.clone()),
+            Expression::Variable(name) => {
+                let value = scope
+                    .captures
+                    .get(name)
+                    .or_else(|| scope.globals.get(name))
+                    .ok_or_else(|| format!("Missing variable ${}", name))?;
+                constant(value)
+                    .ok_or_else(|| format!("${} is not a number, boolean or string", name))
+            }
+            Expression::Unary(operator, operand) => match (*operator, operand.evaluate(scope)?) {
+                ("-", Constant::Number(number)) => Ok(Constant::Number(-number)),
+                ("!", Constant::Boolean(boolean)) => Ok(Constant::Boolean(!boolean)),
+                (operator, operand) => Err(format!("Cannot apply `{}` to {:?}", operator, operand)),
+            },
+            Expression::Binary(operator @ ("&&" | "||"), left, right) => {
+                let boolean = |expression: &Expression| -> Result<bool, String> {
+                    match expression.evaluate(scope)? {
+                        Constant::Boolean(boolean) => Ok(boolean),
+                        operand => Err(format!("Cannot apply `{}` to {:?}", operator, operand)),
+                    }
+                };
+                // Short-circuits like the operators it is written with
+                Ok(Constant::Boolean(match *operator {
+                    "&&" => boolean(left)? && boolean(right)?,
+                    _ => boolean(left)? || boolean(right)?,
+                }))
+            }
+            Expression::Binary(operator, left, right) => {
+                match (*operator, left.evaluate(scope)?, right.evaluate(scope)?) {
+                    ("==", left, right) => Ok(Constant::Boolean(left == right)),
+                    ("!=", left, right) => Ok(Constant::Boolean(left != right)),
+                    (operator, Constant::Number(left), Constant::Number(right)) => {
+                        Ok(match operator {
+                            "<" => Constant::Boolean(left < right),
+                            ">" => Constant::Boolean(left > right),
+                            "<=" => Constant::Boolean(left <= right),
+                            ">=" => Constant::Boolean(left >= right),
+                            "+" => Constant::Number(left + right),
+                            "-" => Constant::Number(left - right),
+                            "*" => Constant::Number(left * right),
+                            _ if right.is_zero() => return Err("Division by zero".to_string()),
+                            _ => Constant::Number(left / right),
+                        })
+                    }
+                    (operator, left, right) => Err(format!(
+                        "Cannot apply `{}` to {:?} and {:?}",
+                        operator, left, right
+                    )),
+                }
+            }
+        }
+    }
+}
+
+fn constant(value: &Value) -> Option<Constant> {
+    match value {
+        Value::Number(number) => BigDecimal::from_str(&number.to_string())
+            .ok()
+            .map(Constant::Number),
+        Value::Bool(boolean) => Some(Constant::Boolean(*boolean)),
+        Value::String(string) => Some(Constant::String(string.clone())),
+        _ => None,
+    }
+}
+
+/// JSON value of a GraphQL value, with its variables replaced. Enum values are strings.
+fn json_value(value: &QueryValue<'static, String>, variables: &Map<String, Value>) -> Value {
+    match value {
+        QueryValue::Variable(name) => variables.get(name).cloned().unwrap_or(Value::Null),
+        QueryValue::Int(number) => number.as_i64().map_or(Value::Null, Value::from),
+        QueryValue::Float(number) => Value::from(*number),
+        QueryValue::String(string) | QueryValue::Enum(string) => Value::String(string.clone()),
+        QueryValue::Boolean(boolean) => Value::Bool(*boolean),
+        QueryValue::Null => Value::Null,
+        QueryValue::List(values) => Value::Array(
+            values
+                .iter()
+                .map(|value| json_value(value, variables))
+                .collect(),
+        ),
+        QueryValue::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, value)| (name.clone(), json_value(value, variables)))
+                .collect(),
+        ),
+    }
+}
+
+/// A query being priced, with its fragments and variables
+struct PricedQuery<'d> {
+    fragments: HashMap<&'d str, &'d SelectionSet<'static, String>>,
+    variables: Map<String, Value>,
+}
+
+impl<'d> PricedQuery<'d> {
+    /// Fields of a selection set, with its fragments expanded
+    fn fields<'s>(
+        &'s self,
+        selection_set: &'s SelectionSet<'static, String>,
+        fragment_depth: usize,
+    ) -> Result<Vec<&'s Field<'static, String>>, String> {
+        if fragment_depth > MAX_FRAGMENT_DEPTH {
+            return Err("Fragments are nested too deeply".to_string());
+        }
+
+        let mut fields = vec![];
+        for selection in &selection_set.items {
+            match selection {
+                Selection::Field(field) => fields.push(field),
+                Selection::FragmentSpread(spread) => {
+                    let fragment = self
+                        .fragments
+                        .get(spread.fragment_name.as_str())
+                        .ok_or_else(|| format!("Unknown fragment `{}`", spread.fragment_name))?;
+                    fields.extend(self.fields(fragment, fragment_depth + 1)?);
+                }
+                Selection::InlineFragment(fragment) => {
+                    fields.extend(self.fields(&fragment.selection_set, fragment_depth + 1)?);
+                }
+            }
+        }
+        Ok(fields)
+    }
+
+    /// Whether the field has the name and arguments of the pattern, and selects fields
+    /// matching the ones selected by the pattern. Values of `$variables` in the arguments of
+    /// the pattern are captured.
+    fn match_field(
+        &self,
+        pattern: &Field<'static, String>,
+        field: &Field<'static, String>,
+        captures: &mut HashMap<String, Value>,
+    ) -> Result<bool, String> {
+        if pattern.name != field.name {
+            return Ok(false);
+        }
+        for (name, pattern_value) in &pattern.arguments {
+            let value = match field
+                .arguments
+                .iter()
+                .find(|(argument, _)| argument == name)
+            {
+                Some((_, value)) => json_value(value, &self.variables),
+                None => return Ok(false),
+            };
+            if !match_value(pattern_value, &value, captures) {
+                return Ok(false);
+            }
+        }
+
+        for selection in &pattern.selection_set.items {
+            let pattern = match selection {
+                Selection::Field(pattern) => pattern,
+                _ => return Err("Patterns can only select fields".to_string()),
+            };
+            let mut matched = false;
+            for field in self.fields(&field.selection_set, 0)? {
+                let mut field_captures = captures.clone();
+                if self.match_field(pattern, field, &mut field_captures)? {
+                    *captures = field_captures;
+                    matched = true;
+                    break;
+                }
+            }
+            if !matched {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Whether the value matches the pattern. Objects match if they have the fields of the
+/// pattern, lists if their elements match the ones of the pattern.
+fn match_value(
+    pattern: &QueryValue<'static, String>,
+    value: &Value,
+    captures: &mut HashMap<String, Value>,
+) -> bool {
+    match (pattern, value) {
+        (QueryValue::Variable(name), value) => {
+            captures.insert(name.clone(), value.clone());
+            true
+        }
+        (QueryValue::Object(pattern), Value::Object(value)) => {
+            pattern.iter().all(|(name, pattern)| {
+                value
+                    .get(name)
+                    .map_or(false, |value| match_value(pattern, value, captures))
+            })
+        }
+        (QueryValue::List(pattern), Value::Array(values)) => {
+            pattern.len() == values.len()
+                && pattern
+                    .iter()
+                    .zip(values)
+                    .all(|(pattern, value)| match_value(pattern, value, captures))
+        }
+        (QueryValue::Object(_) | QueryValue::List(_), _) => false,
+        (pattern, value) => {
+            let pattern = json_value(pattern, &Map::new());
+            match (constant(&pattern), constant(value)) {
+                (Some(pattern), Some(value)) => pattern == value,
+                _ => pattern == *value,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Models and whether they are valid, for this parser and for Agora alike
+    const GRAMMAR_VECTORS: &[(&str, bool)] = &[
+        ("default => 0.00025;", true),
+        ("default => 1e-5;", true),
+        ("default => 2.5E+3 * 1e2;", true),
+        ("default => -1 + 2;", true),
+        (
+            "query { pairs(skip: $skip) { id } } when $skip > 2000 => 0.0001 * $skip * $SYSTEM_LOAD;",
+            true,
+        ),
+        (
+            r#"query { tokens(first: $first, where: { symbol: "GRT" }) { id } } => $first * 0.00001;"#,
+            true,
+        ),
+        (
+            r#"query { a(orderBy: id, where: { b_in: ["}", "=>;"] }) { id } } => 1;"#,
+            true,
+        ),
+        (
+            r#"query { a(b: $b) { id } } when $b == "a \"quoted\" string" => 1;"#,
+            true,
+        ),
+        (
+            "{ _meta { block { number } } } when $meta && !($free == false) => 0;",
+            true,
+        ),
+        (
+            "query { a # a } comment\n { id } } => 1; # another } comment",
+            true,
+        ),
+        ("", false),
+        ("# only a comment", false),
+        ("default => 0.1", false),
+        ("default 0.1;", false),
+        ("default => 0.1 *;", false),
+        ("default => (0.1;", false),
+        ("default => 0.1.2;", false),
+        ("default => 1e;", false),
+        ("default => price;", false),
+        ("default => \"unterminated;", false),
+        ("default when $a => 1;", false),
+        ("query { a } if $b => 1;", false),
+        ("query { a => 1;", false),
+        ("query { a(first: } => 1;", false),
+        ("query { a b } => 1;", false),
+        ("mutation { a } => 1;", false),
+        ("query { a } when => 1;", false),
+    ];
+
+    const MODEL: &str = r#"
+        # Pairs are priced by how many are skipped
+        query { pairs(skip: $skip) { id } } when $skip > 2000 => 0.0001 * $skip * $SYSTEM_LOAD;
+        query { pairs(skip: $skip) { id } } => 0.0001 * $skip;
+        query { tokens(where: { symbol: "GRT" }) { id } } => 1e-5;
+        query { swaps(first: $first) { id } } when $first > 100 && !$free => $first / 1000;
+        default => 0.1 * $SYSTEM_LOAD;
+    "#;
+
+    /// Queries, their variables and their cost in wei under `MODEL`, for this parser and for
+    /// Agora alike
+    const COST_VECTORS: &[(&str, &str, u128)] = &[
+        (
+            "{ pairs(skip: 3000) { id name } }",
+            "{}",
+            600_000_000_000_000_000,
+        ),
+        ("{ pairs(skip: 10) { id } }", "{}", 1_000_000_000_000_000),
+        (
+            "query pairs($skip: Int) { pairs(skip: $skip) { id } }",
+            r#"{ "skip": 3000 }"#,
+            600_000_000_000_000_000,
+        ),
+        (
+            "query pairs($skip: Int = 10) { pairs(skip: $skip) { id } }",
+            "{}",
+            1_000_000_000_000_000,
+        ),
+        // Without the selected `id`, the pairs statements do not match
+        (
+            "{ pairs(skip: 10) { name } }",
+            "{}",
+            200_000_000_000_000_000,
+        ),
+        ("{ pairs { id } }", "{}", 200_000_000_000_000_000),
+        (
+            "{ pairs(skip: 10) { id } tokens { id } }",
+            "{}",
+            201_000_000_000_000_000,
+        ),
+        (
+            "query { ...Pairs } fragment Pairs on Query { pairs(skip: 10) { id } }",
+            "{}",
+            1_000_000_000_000_000,
+        ),
+        (
+            r#"{ tokens(where: { symbol: "GRT", decimals: 18 }) { id } }"#,
+            "{}",
+            10_000_000_000_000,
+        ),
+        (
+            r#"{ tokens(where: { symbol: "ETH" }) { id } }"#,
+            "{}",
+            200_000_000_000_000_000,
+        ),
+        (
+            "{ swaps(first: 300) { id } }",
+            "{}",
+            300_000_000_000_000_000,
+        ),
+        ("{ swaps(first: 50) { id } }", "{}", 200_000_000_000_000_000),
+    ];
+
+    fn request(query: &str, variables: &str) -> GraphQLQuery {
+        GraphQLQuery {
+            query: query.to_string(),
+            variables: Some(serde_json::from_str(variables).unwrap()),
+        }
+    }
+
+    #[test]
+    fn grammar_vectors() {
+        for (model, valid) in GRAMMAR_VECTORS {
+            assert_eq!(
+                validate_model(model).is_ok(),
+                *valid,
+                "{}: {:?}",
+                model,
+                validate_model(model)
+            );
+        }
+    }
+
+    #[test]
+    fn cost_vectors() {
+        let variables = json!({ "SYSTEM_LOAD": 2, "free": false });
+        let model = Model::parse(MODEL, Some(&variables)).unwrap();
+        for (query, query_variables, cost) in COST_VECTORS {
+            assert_eq!(
+                model.cost(&request(query, query_variables)),
+                Ok(*cost),
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[test]
+    fn unpriced_queries() {
+        let model = Model::parse("query { a { id } } => 1;", None).unwrap();
+        assert!(model.cost(&request("{ b { id } }", "{}")).is_err());
+        assert!(model.cost(&request("mutation { a { id } }", "{}")).is_err());
+        assert!(model.cost(&request("{ a { id }", "{}")).is_err());
+
+        // Errors while evaluating are not priced as zero
+        for model in [
+            "default => 1 / 0;",
+            "default => -1;",
+            "default => $missing;",
+            "default => true;",
+            "query { a { id } } when 1 => 1;",
+        ] {
+            let model = Model::parse(model, None).unwrap();
+            assert!(
+                model.cost(&request("{ a { id } }", "{}")).is_err(),
+                "{:?}",
+                model
+            );
+        }
+    }
+
+    #[test]
+    fn variables_are_objects() {
+        validate_variables(&json!({ "SYSTEM_LOAD": 1.5 })).unwrap();
+        assert!(validate_variables(&json!([1.5])).is_err());
+        assert!(validate_variables(&json!("SYSTEM_LOAD")).is_err());
+        assert!(Model::parse("default => 1;", Some(&json!([1.5]))).is_err());
+    }
+
+    /// Gateways price queries with Agora, so receipts are checked against the cost it would
+    /// compute: the vectors must give the same results with both parsers
+    #[test]
+    fn agrees_with_agora() {
+        for (model, valid) in GRAMMAR_VECTORS {
+            assert_eq!(
+                cost_model::CostModel::compile(*model, "").is_ok(),
+                *valid,
+                "Agora: {}",
+                model
+            );
+        }
+
+        let variables = json!({ "SYSTEM_LOAD": 2, "free": false });
+        let model = cost_model::CostModel::compile(MODEL, &variables.to_string()).unwrap();
+        for (query, query_variables, cost) in COST_VECTORS {
+            assert_eq!(
+                model
+                    .cost(query, query_variables)
+                    .map(|cost| cost.to_string())
+                    .ok(),
+                Some(cost.to_string()),
+                "Agora: {}",
+                query
+            );
+        }
+    }
+}
@@ -86,6 +86,11 @@ pub enum IndexerErrorCode {
     IE074,
     IE075,
     IE076,
+    IE077,
+    IE078,
+    IE079,
+    IE080,
+    IE081,
 }
 
 impl fmt::Display for IndexerErrorCode {
@@ -167,6 +172,11 @@ impl fmt::Display for IndexerErrorCode {
             IndexerErrorCode::IE074 => write!(f, "IE074"),
             IndexerErrorCode::IE075 => write!(f, "IE075"),
             IndexerErrorCode::IE076 => write!(f, "IE076"),
+            IndexerErrorCode::IE077 => write!(f, "IE077"),
+            IndexerErrorCode::IE078 => write!(f, "IE078"),
+            IndexerErrorCode::IE079 => write!(f, "IE079"),
+            IndexerErrorCode::IE080 => write!(f, "IE080"),
+            IndexerErrorCode::IE081 => write!(f, "IE081"),
         }
     }
 }
@@ -252,6 +262,11 @@ impl IndexerErrorCode {
             Self::IE074 => "Failed to resolve the release version",
             Self::IE075 => "Failed to parse response body to query string",
             Self::IE076 => "Database read failed",
+            Self::IE077 => "Request timed out",
+            Self::IE078 => "Failed to query network subgraph",
+            Self::IE079 => "Invalid cost model",
+            Self::IE080 => "Database write failed",
+            Self::IE081 => "Cost model audit table is missing",
         }
     }
 
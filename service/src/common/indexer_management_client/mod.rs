@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use sqlx::PgPool;
+use tracing::error;
 
 use reqwest::Client;
 
@@ -12,6 +13,18 @@ pub mod schema;
 #[derive(Default)]
 pub struct QueryRoot;
 
+// Unified mutation object for resolvers
+#[derive(Default)]
+pub struct MutationRoot;
+
+/// Who is changing cost models, recorded in the audit log of the changes
+#[derive(Debug, Clone)]
+pub struct CostModelEditor {
+    /// The operator auth token's fingerprint, as `operator:<fingerprint>`
+    pub name: String,
+    pub client_address: Option<String>,
+}
+
 /// Indexer management client
 #[derive(Debug, Clone)]
 pub struct IndexerManagementClient {
@@ -25,6 +38,17 @@ impl IndexerManagementClient {
             .user_agent("indexer-service")
             .build()
             .expect("Could not build a HTTP client");
+
+        // The indexer agent does not create the audit table, cost model changes fail until
+        // the operator does
+        match resolver::audit_table_exists(&database).await {
+            Ok(true) => {}
+            Ok(false) => error!(
+                "The \"CostModelAudit\" table is missing, cost models cannot be changed until \
+                 it is created with migrations/20231016120000_cost_model_audit.up.sql"
+            ),
+            Err(e) => error!("Failed to check for the \"CostModelAudit\" table: {}", e),
+        }
         IndexerManagementClient { database, client }
     }
 
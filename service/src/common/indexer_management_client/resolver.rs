@@ -1,91 +1,46 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use sqlx::PgPool;
+use std::collections::HashMap;
 
-use super::schema::CostModel;
+use serde_json::Value;
+use sqlx::{PgPool, Postgres, Transaction};
+
+use super::{schema::CostModel, CostModelEditor};
 use crate::common::{
+    cost_model_language::{validate_model, validate_variables},
     indexer_error::{IndexerError, IndexerErrorCause, IndexerErrorCode},
     types::SubgraphDeploymentID,
 };
 
+/// Cost models by deployment, as stored: the hex form of the deployment or `global`
+pub type CostModels = HashMap<String, CostModel>;
+
 /// Query postgres indexer management server's cost models
-/// If specific deployments is provided, then global fallback is applied to all
-/// deployments regardless of its presence in the database. Otherwise, all cost
-/// models are returned without merging fields with the global cost model
-pub async fn cost_models(
-    pool: &PgPool,
-    deployments: &[String],
-) -> Result<Vec<CostModel>, IndexerError> {
-    let deployment_ids = deployments
-        .iter()
-        .map(|d| SubgraphDeploymentID::new(d).unwrap().to_string())
-        .collect::<Vec<String>>();
-    let models = if deployment_ids.is_empty() {
-        sqlx::query_as!(
-            CostModel,
-            r#"
+pub async fn load_cost_models(pool: &PgPool) -> Result<CostModels, IndexerError> {
+    let models = sqlx::query_as!(
+        CostModel,
+        r#"
         SELECT deployment, model, variables
         FROM "CostModels"
         ORDER BY deployment ASC
     "#
-        )
-        .fetch_all(pool)
-        .await
-        .map_err(|e| IndexerError::new(IndexerErrorCode::IE076, Some(IndexerErrorCause::new(e))))?
-    } else {
-        sqlx::query_as!(
-            CostModel,
-            r#"
-            SELECT deployment, model, variables
-            FROM "CostModels"
-            WHERE deployment = ANY($1)
-            ORDER BY deployment ASC
-        "#,
-            &deployment_ids
-        )
-        .fetch_all(pool)
-        .await
-        .map_err(|e| IndexerError::new(IndexerErrorCode::IE076, Some(IndexerErrorCause::new(e))))?
-    };
-
-    // Merge deployment cost models with global cost model
-    let models = match (deployment_ids.is_empty(), global_cost_model(pool).await?) {
-        (false, Some(global)) => {
-            let m = deployment_ids
-                .iter()
-                .map(|d| {
-                    let m = models.iter().find(|&m| &m.deployment == d).map_or_else(
-                        || {
-                            merge_global(
-                                CostModel {
-                                    deployment: d.clone(),
-                                    model: None,
-                                    variables: None,
-                                },
-                                &global,
-                            )
-                        },
-                        |m| merge_global(m.clone(), &global),
-                    );
-                    m
-                })
-                .collect::<Vec<CostModel>>();
-
-            m
-        }
-        _ => models,
-    };
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| IndexerError::new(IndexerErrorCode::IE076, Some(IndexerErrorCause::new(e))))?;
 
-    Ok(models)
+    Ok(models
+        .into_iter()
+        .map(|model| (model.deployment.clone(), model))
+        .collect())
 }
 
-/// Make database query for a cost model indexed by deployment id
-pub async fn cost_model(
+/// Make database query for a cost model indexed by its stored deployment
+pub async fn load_cost_model(
     pool: &PgPool,
     deployment: &str,
 ) -> Result<Option<CostModel>, IndexerError> {
-    let deployment_id = SubgraphDeploymentID::new(deployment).unwrap().to_string();
     let model = sqlx::query_as!(
         CostModel,
         r#"
@@ -93,14 +48,63 @@ pub async fn cost_model(
         FROM "CostModels"
         WHERE deployment = $1
     "#,
-        deployment_id
+        deployment
     )
     .fetch_optional(pool)
     .await
     .map_err(|e| IndexerError::new(IndexerErrorCode::IE076, Some(IndexerErrorCause::new(e))))?;
+    Ok(model)
+}
+
+/// Look up cost models of deployments
+/// If specific deployments is provided, then global fallback is applied to all
+/// deployments regardless of its presence in the cost models. Otherwise, all cost
+/// models are returned without merging fields with the global cost model
+pub fn cost_models(models: &CostModels, deployments: &[String]) -> Vec<CostModel> {
+    let deployment_ids = deployments
+        .iter()
+        .map(|d| SubgraphDeploymentID::new(d).unwrap().to_string())
+        .collect::<Vec<String>>();
+
+    // Merge deployment cost models with global cost model
+    match (deployment_ids.is_empty(), models.get("global")) {
+        (false, Some(global)) => deployment_ids
+            .iter()
+            .map(|d| {
+                models.get(d).cloned().map_or_else(
+                    || {
+                        merge_global(
+                            CostModel {
+                                deployment: d.clone(),
+                                model: None,
+                                variables: None,
+                            },
+                            global,
+                        )
+                    },
+                    |m| merge_global(m, global),
+                )
+            })
+            .collect(),
+        (empty, _) => {
+            let mut models = models
+                .values()
+                .filter(|m| empty || deployment_ids.contains(&m.deployment))
+                .cloned()
+                .collect::<Vec<CostModel>>();
+            models.sort_by(|a, b| a.deployment.cmp(&b.deployment));
+            models
+        }
+    }
+}
+
+/// Look up a cost model indexed by deployment id
+pub fn cost_model(models: &CostModels, deployment: &str) -> Option<CostModel> {
+    let deployment_id = SubgraphDeploymentID::new(deployment).unwrap().to_string();
+    let model = models.get(&deployment_id).cloned();
 
     // Fallback with global cost model
-    let model = if let Some(global) = global_cost_model(pool).await? {
+    if let Some(global) = models.get("global") {
         model.map_or_else(
             || {
                 Some(merge_global(
@@ -109,33 +113,189 @@ pub async fn cost_model(
                         model: None,
                         variables: None,
                     },
-                    &global,
+                    global,
                 ))
             },
-            |m| Some(merge_global(m, &global)),
+            |m| Some(merge_global(m, global)),
         )
     } else {
         model
-    };
-
-    Ok(model)
+    }
 }
 
-/// Query global cost model
-pub async fn global_cost_model(pool: &PgPool) -> Result<Option<CostModel>, IndexerError> {
-    let model = sqlx::query_as!(
-        CostModel,
+/// Sets the model and variables of a cost model, leaving the fields without an update as
+/// they are. Both are validated before writing, and the change is recorded in the audit log.
+pub async fn set_cost_model(
+    pool: &PgPool,
+    deployment: &str,
+    model: Option<Option<String>>,
+    variables: Option<Option<Value>>,
+    editor: &CostModelEditor,
+) -> Result<CostModel, IndexerError> {
+    let deployment = stored_deployment(deployment)?;
+    if let Some(Some(model)) = &model {
+        validate_model(model).map_err(invalid_cost_model)?;
+    }
+    if let Some(Some(variables)) = &variables {
+        validate_variables(variables).map_err(invalid_cost_model)?;
+    }
+
+    let mut tx = pool.begin().await.map_err(write_error)?;
+    let previous = sqlx::query_as::<_, CostModel>(
         r#"
         SELECT deployment, model, variables
         FROM "CostModels"
         WHERE deployment = $1
+        FOR UPDATE
     "#,
-        "global"
     )
-    .fetch_optional(pool)
+    .bind(&deployment)
+    .fetch_optional(&mut *tx)
     .await
-    .map_err(|e| IndexerError::new(IndexerErrorCode::IE076, Some(IndexerErrorCause::new(e))))?;
-    Ok(model)
+    .map_err(write_error)?;
+
+    let updated = CostModel {
+        deployment: deployment.clone(),
+        model: model.unwrap_or_else(|| previous.as_ref().and_then(|p| p.model.clone())),
+        variables: variables.unwrap_or_else(|| previous.as_ref().and_then(|p| p.variables.clone())),
+    };
+    sqlx::query(
+        r#"
+        INSERT INTO "CostModels" (deployment, model, variables)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (deployment)
+        DO UPDATE SET model = EXCLUDED.model, variables = EXCLUDED.variables
+    "#,
+    )
+    .bind(&updated.deployment)
+    .bind(&updated.model)
+    .bind(&updated.variables)
+    .execute(&mut *tx)
+    .await
+    .map_err(write_error)?;
+
+    audit(
+        &mut tx,
+        "set",
+        &deployment,
+        Some(&updated),
+        previous.as_ref(),
+        editor,
+    )
+    .await?;
+    tx.commit().await.map_err(write_error)?;
+    Ok(updated)
+}
+
+/// Deletes the cost models of the deployments, recording each deleted model in the audit log.
+/// Returns how many of them existed.
+pub async fn delete_cost_models(
+    pool: &PgPool,
+    deployments: &[String],
+    editor: &CostModelEditor,
+) -> Result<u64, IndexerError> {
+    let deployments = deployments
+        .iter()
+        .map(|deployment| stored_deployment(deployment))
+        .collect::<Result<Vec<String>, IndexerError>>()?;
+
+    let mut tx = pool.begin().await.map_err(write_error)?;
+    let deleted = sqlx::query_as::<_, CostModel>(
+        r#"
+        DELETE FROM "CostModels"
+        WHERE deployment = ANY($1)
+        RETURNING deployment, model, variables
+    "#,
+    )
+    .bind(&deployments)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(write_error)?;
+
+    for previous in &deleted {
+        audit(
+            &mut tx,
+            "delete",
+            &previous.deployment,
+            None,
+            Some(previous),
+            editor,
+        )
+        .await?;
+    }
+    tx.commit().await.map_err(write_error)?;
+    Ok(deleted.len() as u64)
+}
+
+/// Whether the "CostModelAudit" table exists. The indexer agent migrations do not create it,
+/// operators do with `migrations/20231016120000_cost_model_audit.up.sql`.
+pub async fn audit_table_exists(pool: &PgPool) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(r#"SELECT to_regclass('"CostModelAudit"') IS NOT NULL"#)
+        .fetch_one(pool)
+        .await
+}
+
+/// Records who changed a cost model, and from what to what, in the "CostModelAudit" table.
+/// Changes are not made without their record, so they fail while the table is missing.
+async fn audit(
+    tx: &mut Transaction<'_, Postgres>,
+    action: &str,
+    deployment: &str,
+    model: Option<&CostModel>,
+    previous: Option<&CostModel>,
+    editor: &CostModelEditor,
+) -> Result<(), IndexerError> {
+    sqlx::query(
+        r#"
+        INSERT INTO "CostModelAudit" (
+            deployment, action, model, variables, previous_model, previous_variables,
+            changed_by, client_address
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+    "#,
+    )
+    .bind(deployment)
+    .bind(action)
+    .bind(model.and_then(|m| m.model.as_ref()))
+    .bind(model.and_then(|m| m.variables.as_ref()))
+    .bind(previous.and_then(|m| m.model.as_ref()))
+    .bind(previous.and_then(|m| m.variables.as_ref()))
+    .bind(&editor.name)
+    .bind(&editor.client_address)
+    .execute(&mut **tx)
+    .await
+    .map_err(
+        |e| match e.as_database_error().and_then(|e| e.code()).as_deref() {
+            // undefined_table
+            Some("42P01") => IndexerError::new(
+                IndexerErrorCode::IE081,
+                Some(IndexerErrorCause::new(
+                    "Cost models cannot be changed until the \"CostModelAudit\" table is \
+                 created with migrations/20231016120000_cost_model_audit.up.sql",
+                )),
+            ),
+            _ => write_error(e),
+        },
+    )?;
+    Ok(())
+}
+
+/// Cost models are stored by the hex form of their deployment, besides the global one
+pub fn stored_deployment(deployment: &str) -> Result<String, IndexerError> {
+    if deployment == "global" {
+        return Ok(deployment.to_string());
+    }
+    SubgraphDeploymentID::new(deployment)
+        .map(|deployment| deployment.to_string())
+        .map_err(|e| invalid_cost_model(format!("Invalid deployment {}: {}", deployment, e)))
+}
+
+fn invalid_cost_model(error: String) -> IndexerError {
+    IndexerError::new(IndexerErrorCode::IE079, Some(error.into()))
+}
+
+fn write_error(error: sqlx::Error) -> IndexerError {
+    IndexerError::new(IndexerErrorCode::IE080, Some(IndexerErrorCause::new(error)))
 }
 
 fn merge_global(model: CostModel, global_model: &CostModel) -> CostModel {
@@ -170,6 +330,28 @@ mod test {
         .expect("Create test instance in db");
     }
 
+    async fn setup_cost_model_audit_table(pool: &PgPool) {
+        sqlx::query(
+            r#"
+            CREATE TABLE "CostModelAudit"(
+                id BIGSERIAL PRIMARY KEY,
+                deployment VARCHAR NOT NULL,
+                action VARCHAR NOT NULL,
+                model TEXT,
+                variables JSONB,
+                previous_model TEXT,
+                previous_variables JSONB,
+                changed_by VARCHAR NOT NULL,
+                client_address VARCHAR,
+                changed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+            );
+            "#,
+        )
+        .execute(pool)
+        .await
+        .expect("Create test instance in db");
+    }
+
     async fn add_cost_models(pool: &PgPool, models: Vec<CostModel>) {
         for model in models {
             sqlx::query!(
@@ -186,6 +368,10 @@ mod test {
         }
     }
 
+    async fn loaded(pool: &PgPool) -> CostModels {
+        load_cost_models(pool).await.expect("Cost models query")
+    }
+
     fn global_cost_model() -> CostModel {
         CostModel {
             deployment: "global".to_string(),
@@ -217,11 +403,9 @@ mod test {
         let expected_models = simple_cost_models();
         add_cost_models(&pool, expected_models.clone()).await;
         let res = cost_models(
-            &pool,
+            &loaded(&pool).await,
             &["Qmb5Ysp5oCUXhLA8NmxmYKDAX2nCMnh7Vvb5uffb9n5vss".to_string()],
-        )
-        .await
-        .expect("Cost models query");
+        );
 
         assert_eq!(res.len(), 1);
         assert_eq!(
@@ -234,11 +418,9 @@ mod test {
         );
 
         let res = cost_models(
-            &pool,
+            &loaded(&pool).await,
             &["0xbd499f7673ca32ef4a642207a8bebdd0fb03888cf2678b298438e3a1ae5206ea".to_string()],
-        )
-        .await
-        .expect("Cost models query");
+        );
 
         assert_eq!(res.len(), 1);
         assert_eq!(
@@ -259,9 +441,7 @@ mod test {
         add_cost_models(&pool, simple_cost_models()).await;
         let global = global_cost_model();
         add_cost_models(&pool, vec![global.clone()]).await;
-        let res = cost_models(&pool, &[])
-            .await
-            .expect("Cost models query without deployments filter");
+        let res = cost_models(&loaded(&pool).await, &[]);
 
         assert_eq!(res.len(), 3);
         let incomplete_model = res.iter().find(|m| m.deployment == deployment_id.clone());
@@ -269,14 +449,12 @@ mod test {
         assert_ne!(incomplete_model.unwrap().model, global.model);
 
         let res = cost_models(
-            &pool,
+            &loaded(&pool).await,
             &[
                 deployment_id.clone(),
                 "0xbd499f7673ca32ef4a642207a8bebdd0fb03888cf2678b298438e3a1ae5206ea".to_string(),
             ],
-        )
-        .await
-        .expect("Cost models query without deployments filter");
+        );
 
         assert_eq!(res.len(), 2);
         let incomplete_model = res.iter().find(|m| m.deployment == deployment_id.clone());
@@ -290,9 +468,7 @@ mod test {
         assert_ne!(complete_model.unwrap().model, global.model);
 
         let missing_deployment = "Qmaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
-        let res = cost_models(&pool, &[missing_deployment.to_string()])
-            .await
-            .expect("Cost models query without deployments filter");
+        let res = cost_models(&loaded(&pool).await, &[missing_deployment.to_string()]);
 
         let missing_model = res.iter().find(|m| {
             m.deployment == *"0xb5ddb473e202a7abba81803ad153fd93a9b18d07ab38a711f7c2bd79435e50d7"
@@ -307,9 +483,7 @@ mod test {
         let deployment_hash = "Qmb5Ysp5oCUXhLA8NmxmYKDAX2nCMnh7Vvb5uffb9n5vss".to_string();
         setup_cost_models_table(&pool).await;
         add_cost_models(&pool, simple_cost_models()).await;
-        let res = cost_model(&pool, &deployment_hash)
-            .await
-            .expect("Cost model query")
+        let res = cost_model(&loaded(&pool).await, &deployment_hash)
             .expect("Cost model match deployment");
 
         assert_eq!(res.deployment, deployment_id.to_string());
@@ -321,21 +495,119 @@ mod test {
         setup_cost_models_table(&pool).await;
         add_cost_models(&pool, simple_cost_models()).await;
 
-        let res = cost_model(&pool, deployment_hash)
-            .await
-            .expect("Cost model query");
+        let res = cost_model(&loaded(&pool).await, deployment_hash);
 
         assert!(res.is_none());
 
         let global = global_cost_model();
         add_cost_models(&pool, vec![global.clone()]).await;
 
-        let res = cost_model(&pool, deployment_hash)
-            .await
-            .expect("Cost model query")
-            .expect("Global cost model fallback");
+        let res =
+            cost_model(&loaded(&pool).await, deployment_hash).expect("Global cost model fallback");
 
         assert_eq!(res.model, global.model);
         assert_eq!(&res.deployment, deployment_hash);
     }
+
+    #[sqlx::test]
+    async fn set_and_delete_cost_models(pool: PgPool) {
+        let deployment_id = "0xbd499f7673ca32ef4a642207a8bebdd0fb03888cf2678b298438e3a1ae5206ea";
+        let deployment_hash = "Qmb5Ysp5oCUXhLA8NmxmYKDAX2nCMnh7Vvb5uffb9n5vss";
+        setup_cost_models_table(&pool).await;
+        setup_cost_model_audit_table(&pool).await;
+        let editor = CostModelEditor {
+            name: "operator:1c8aff95".to_string(),
+            client_address: Some("127.0.0.1".to_string()),
+        };
+
+        let res = set_cost_model(
+            &pool,
+            deployment_hash,
+            Some(Some("default => 0.00025;".to_string())),
+            None,
+            &editor,
+        )
+        .await
+        .expect("Set cost model");
+        assert_eq!(res.deployment, deployment_id);
+
+        // Only the variables are updated, the model is kept
+        let variables = serde_json::json!({ "SYSTEM_LOAD": 1.5 });
+        set_cost_model(
+            &pool,
+            deployment_hash,
+            None,
+            Some(Some(variables.clone())),
+            &editor,
+        )
+        .await
+        .expect("Update cost model variables");
+        let res =
+            cost_model(&loaded(&pool).await, deployment_hash).expect("Cost model match deployment");
+        assert_eq!(res.model.as_deref(), Some("default => 0.00025;"));
+        assert_eq!(res.variables, Some(variables));
+
+        // Invalid models are not written
+        assert!(set_cost_model(
+            &pool,
+            deployment_hash,
+            Some(Some("default => ;".to_string())),
+            None,
+            &editor
+        )
+        .await
+        .is_err());
+
+        let deleted = delete_cost_models(
+            &pool,
+            &[deployment_hash.to_string(), "global".to_string()],
+            &editor,
+        )
+        .await
+        .expect("Delete cost models");
+        assert_eq!(deleted, 1);
+        assert!(cost_model(&loaded(&pool).await, deployment_hash).is_none());
+
+        let actions: Vec<(String, String)> = sqlx::query_as(
+            r#"SELECT action, changed_by FROM "CostModelAudit" WHERE deployment = $1 ORDER BY id"#,
+        )
+        .bind(deployment_id)
+        .fetch_all(&pool)
+        .await
+        .expect("Audit log query");
+        assert_eq!(
+            actions,
+            vec![
+                ("set".to_string(), "operator:1c8aff95".to_string()),
+                ("set".to_string(), "operator:1c8aff95".to_string()),
+                ("delete".to_string(), "operator:1c8aff95".to_string()),
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn changes_need_the_audit_table(pool: PgPool) {
+        let deployment_hash = "Qmb5Ysp5oCUXhLA8NmxmYKDAX2nCMnh7Vvb5uffb9n5vss";
+        setup_cost_models_table(&pool).await;
+        assert!(!audit_table_exists(&pool).await.unwrap());
+        let editor = CostModelEditor {
+            name: "operator:1c8aff95".to_string(),
+            client_address: None,
+        };
+
+        let error = set_cost_model(
+            &pool,
+            deployment_hash,
+            Some(Some("default => 0.00025;".to_string())),
+            None,
+            &editor,
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("IE081"), "{}", error);
+        assert!(cost_model(&loaded(&pool).await, deployment_hash).is_none());
+
+        setup_cost_model_audit_table(&pool).await;
+        assert!(audit_table_exists(&pool).await.unwrap());
+    }
 }
@@ -1,14 +1,17 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Schema, SimpleObject};
+use async_graphql::{Context, EmptySubscription, MaybeUndefined, Object, Schema, SimpleObject};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sqlx::FromRow;
 
-use crate::{common::indexer_error::IndexerError, server::ServerOptions};
+use crate::{
+    common::indexer_error::{IndexerError, IndexerErrorCode},
+    server::ServerOptions,
+};
 
-use super::{resolver, QueryRoot};
+use super::{resolver, CostModelEditor, MutationRoot, QueryRoot};
 
 #[derive(Debug, FromRow, Clone, Serialize, Deserialize, SimpleObject)]
 pub struct CostModel {
@@ -17,35 +20,72 @@ pub struct CostModel {
     pub variables: Option<Value>,
 }
 
-pub type CostSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+pub type CostSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
 
 #[Object]
 impl QueryRoot {
-    async fn cost_models(
-        &self,
-        ctx: &Context<'_>,
-        deployments: Vec<String>,
-    ) -> Result<Vec<CostModel>, IndexerError> {
-        let pool = ctx
-            .data_unchecked::<ServerOptions>()
-            .indexer_management_client
-            .database();
-
-        let models: Vec<CostModel> = resolver::cost_models(pool, &deployments).await?;
-        Ok(models)
+    async fn cost_models(&self, ctx: &Context<'_>, deployments: Vec<String>) -> Vec<CostModel> {
+        ctx.data_unchecked::<ServerOptions>()
+            .cost_model_cache
+            .cost_models(&deployments)
     }
 
-    async fn cost_model(
+    async fn cost_model(&self, ctx: &Context<'_>, deployment: String) -> Option<CostModel> {
+        ctx.data_unchecked::<ServerOptions>()
+            .cost_model_cache
+            .cost_model(&deployment)
+    }
+}
+
+#[Object]
+impl MutationRoot {
+    /// Sets the model and variables of a deployment's cost model, or of the global cost
+    /// model with deployment `global`. Omitted fields are kept and null clears them.
+    async fn set_cost_model(
         &self,
         ctx: &Context<'_>,
         deployment: String,
-    ) -> Result<Option<CostModel>, IndexerError> {
-        let pool = ctx
-            .data_unchecked::<ServerOptions>()
-            .indexer_management_client
-            .database();
+        model: MaybeUndefined<String>,
+        variables: MaybeUndefined<Value>,
+    ) -> Result<CostModel, IndexerError> {
+        let editor = cost_model_editor(ctx)?;
+        let server = ctx.data_unchecked::<ServerOptions>();
+        let pool = server.indexer_management_client.database();
 
-        let model = resolver::cost_model(pool, &deployment).await?;
+        let model =
+            resolver::set_cost_model(pool, &deployment, update(model), update(variables), editor)
+                .await?;
+        server.cost_model_cache.set(model.clone());
         Ok(model)
     }
+
+    /// Deletes the cost models of the deployments, returning how many existed
+    async fn delete_cost_models(
+        &self,
+        ctx: &Context<'_>,
+        deployments: Vec<String>,
+    ) -> Result<u64, IndexerError> {
+        let editor = cost_model_editor(ctx)?;
+        let server = ctx.data_unchecked::<ServerOptions>();
+        let pool = server.indexer_management_client.database();
+
+        let deleted = resolver::delete_cost_models(pool, &deployments, editor).await?;
+        server.cost_model_cache.remove(&deployments);
+        Ok(deleted)
+    }
+}
+
+/// Mutations are only available to requests authenticated with the operator auth token
+fn cost_model_editor<'a>(ctx: &Context<'a>) -> Result<&'a CostModelEditor, IndexerError> {
+    ctx.data_opt::<CostModelEditor>()
+        .ok_or_else(|| IndexerError::new(IndexerErrorCode::IE034, None))
+}
+
+/// None if the field is omitted, otherwise the value to set it to
+fn update<T>(value: MaybeUndefined<T>) -> Option<Option<T>> {
+    match value {
+        MaybeUndefined::Undefined => None,
+        MaybeUndefined::Null => Some(None),
+        MaybeUndefined::Value(value) => Some(Some(value)),
+    }
 }
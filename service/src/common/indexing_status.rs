@@ -0,0 +1,498 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use reqwest::{header, Client};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::common::{
+    indexer_error::{IndexerError, IndexerErrorCause, IndexerErrorCode},
+    types::SubgraphName,
+};
+
+/// Indexing status of a deployment, as reported by the index node server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexingStatus {
+    pub subgraph: String,
+    /// ID of the graph node the deployment is assigned to
+    pub node: Option<String>,
+    pub health: SubgraphHealth,
+    pub fatal_error: Option<SubgraphError>,
+    pub chains: Vec<ChainStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[allow(non_camel_case_types)] // Need exact field names to match with GQL response
+pub enum SubgraphHealth {
+    healthy,
+    unhealthy,
+    failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubgraphError {
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChainStatus {
+    pub network: String,
+    // Both blocks are missing until the deployment has started indexing the chain
+    pub latest_block: Option<Block>,
+    pub chain_head_block: Option<Block>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Block {
+    pub number: String,
+    pub hash: String,
+}
+
+/// Indexing status that could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidIndexingStatus {
+    /// Deployment of the status, if it could be read
+    pub subgraph: Option<String>,
+    pub error: String,
+}
+
+/// Deployment of an indexing status, whether or not the rest of it could be parsed
+pub fn status_deployment(status: &Result<IndexingStatus, InvalidIndexingStatus>) -> Option<&str> {
+    match status {
+        Ok(status) => Some(&status.subgraph),
+        Err(invalid) => invalid.subgraph.as_deref(),
+    }
+}
+
+/// Health of a single chain indexed by a deployment
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChainHealth {
+    pub network: String,
+    pub latest_block: Option<u64>,
+    pub chain_head_block: Option<u64>,
+    pub lag: Option<u64>,
+    pub healthy: bool,
+}
+
+/// Health of a deployment across all the chains it indexes
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentHealth {
+    pub deployment: String,
+    pub health: SubgraphHealth,
+    pub healthy: bool,
+    pub fatal_error: Option<String>,
+    pub max_block_lag: u64,
+    pub chains: Vec<ChainHealth>,
+}
+
+impl IndexingStatus {
+    /// Evaluates the deployment health. A deployment is healthy if it has not failed and every
+    /// chain it indexes is at most `max_block_lag` blocks behind its chain head.
+    pub fn evaluate(&self, max_block_lag: u64) -> Result<DeploymentHealth, IndexerError> {
+        let chains = self
+            .chains
+            .iter()
+            .map(|chain| {
+                let latest_block = chain.latest_block.as_ref().map(block_number).transpose()?;
+                let chain_head_block = chain
+                    .chain_head_block
+                    .as_ref()
+                    .map(block_number)
+                    .transpose()?;
+                let lag = latest_block
+                    .zip(chain_head_block)
+                    .map(|(latest, head)| head.saturating_sub(latest));
+
+                Ok(ChainHealth {
+                    network: chain.network.clone(),
+                    latest_block,
+                    chain_head_block,
+                    lag,
+                    healthy: lag.map_or(false, |lag| lag <= max_block_lag),
+                })
+            })
+            .collect::<Result<Vec<ChainHealth>, IndexerError>>()?;
+
+        Ok(DeploymentHealth {
+            deployment: self.subgraph.clone(),
+            health: self.health.clone(),
+            healthy: self.health != SubgraphHealth::failed
+                && !chains.is_empty()
+                && chains.iter().all(|chain| chain.healthy),
+            fatal_error: self.fatal_error.as_ref().map(|e| e.message.clone()),
+            max_block_lag,
+            chains,
+        })
+    }
+}
+
+fn block_number(block: &Block) -> Result<u64, IndexerError> {
+    block.number.parse::<u64>().map_err(|_| {
+        IndexerError::new(
+            IndexerErrorCode::IE018,
+            Some(IndexerErrorCause::new(
+                "Ill formatted block numbers from indexing status",
+            )),
+        )
+    })
+}
+
+fn status_query(deployments: &[String]) -> serde_json::Value {
+    json!({
+        "query": r#"query indexingStatuses($subgraphs: [String!]!) {
+            indexingStatuses(subgraphs: $subgraphs) {
+                subgraph
+                node
+                health
+                fatalError { message }
+                chains {
+                    network
+                    latestBlock { number hash }
+                    chainHeadBlock { number hash }
+                }
+            }
+        }"#,
+        "variables": {
+            "subgraphs": deployments,
+        },
+    })
+}
+
+async fn status_request(
+    client: &Client,
+    graph_node_status_endpoint: &str,
+    body: &serde_json::Value,
+) -> Result<serde_json::Value, IndexerError> {
+    let query_error = |e: reqwest::Error| {
+        IndexerError::new(IndexerErrorCode::IE018, Some(IndexerErrorCause::new(e)))
+    };
+
+    client
+        .post(graph_node_status_endpoint)
+        .header(header::CONTENT_TYPE, "application/json")
+        .json(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(query_error)?
+        .json()
+        .await
+        .map_err(query_error)
+}
+
+/// Query the indexing statuses of the given deployments (IPFS hashes) in a single request.
+/// Statuses are parsed one by one, so a status that cannot be parsed (e.g. with a health
+/// this service does not know) is returned as an error without failing the others.
+pub async fn indexing_statuses(
+    client: &Client,
+    graph_node_status_endpoint: &str,
+    deployments: &[String],
+) -> Result<Vec<Result<IndexingStatus, InvalidIndexingStatus>>, IndexerError> {
+    let mut data = status_request(
+        client,
+        graph_node_status_endpoint,
+        &status_query(deployments),
+    )
+    .await?;
+
+    match data["data"]["indexingStatuses"].take() {
+        serde_json::Value::Array(statuses) => Ok(statuses.into_iter().map(parse_status).collect()),
+        statuses => Err(IndexerError::new(
+            IndexerErrorCode::IE018,
+            Some(IndexerErrorCause::new(format!(
+                "Missing or invalid indexing statuses: {}",
+                statuses
+            ))),
+        )),
+    }
+}
+
+fn parse_status(status: serde_json::Value) -> Result<IndexingStatus, InvalidIndexingStatus> {
+    let subgraph = status["subgraph"].as_str().map(str::to_string);
+    serde_json::from_value(status).map_err(|e| InvalidIndexingStatus {
+        subgraph,
+        error: format!("Invalid indexing status: {}", e),
+    })
+}
+
+/// Resolve a subgraph name to the deployment (IPFS hash) of its current version, if it has one
+pub async fn current_deployment(
+    client: &Client,
+    graph_node_status_endpoint: &str,
+    subgraph_name: &SubgraphName,
+) -> Result<Option<String>, IndexerError> {
+    let data = status_request(
+        client,
+        graph_node_status_endpoint,
+        &json!({
+            "query": r#"query currentVersion($subgraphName: String!) {
+                indexingStatusForCurrentVersion(subgraphName: $subgraphName) { subgraph }
+            }"#,
+            "variables": {
+                "subgraphName": subgraph_name.to_string(),
+            },
+        }),
+    )
+    .await?;
+
+    if let Some(errors) = data.get("errors") {
+        return Err(IndexerError::new(
+            IndexerErrorCode::IE018,
+            Some(IndexerErrorCause::new(format!(
+                "Failed to resolve subgraph name: {}",
+                errors
+            ))),
+        ));
+    }
+    Ok(data["data"]["indexingStatusForCurrentVersion"]["subgraph"]
+        .as_str()
+        .map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::{body_string_contains, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    fn chain(network: &str, latest: Option<&str>, head: Option<&str>) -> ChainStatus {
+        let block = |number: &str| Block {
+            number: number.to_string(),
+            hash: "0x00".to_string(),
+        };
+        ChainStatus {
+            network: network.to_string(),
+            latest_block: latest.map(block),
+            chain_head_block: head.map(block),
+        }
+    }
+
+    fn status(health: SubgraphHealth, chains: Vec<ChainStatus>) -> IndexingStatus {
+        IndexingStatus {
+            subgraph: "QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ".to_string(),
+            node: None,
+            health,
+            fatal_error: None,
+            chains,
+        }
+    }
+
+    #[test]
+    fn evaluates_every_chain() {
+        let status = status(
+            SubgraphHealth::healthy,
+            vec![
+                chain("mainnet", Some("100"), Some("103")),
+                chain("gnosis", Some("100"), Some("120")),
+            ],
+        );
+
+        let health = status.evaluate(5).unwrap();
+        assert!(!health.healthy);
+        assert_eq!(health.chains[0].lag, Some(3));
+        assert!(health.chains[0].healthy);
+        assert_eq!(health.chains[1].lag, Some(20));
+        assert!(!health.chains[1].healthy);
+
+        assert!(status.evaluate(20).unwrap().healthy);
+    }
+
+    #[test]
+    fn young_chains_do_not_underflow() {
+        let status = status(
+            SubgraphHealth::healthy,
+            vec![chain("mainnet", Some("3"), Some("2"))],
+        );
+
+        let health = status.evaluate(5).unwrap();
+        assert!(health.healthy);
+        assert_eq!(health.chains[0].lag, Some(0));
+    }
+
+    #[test]
+    fn failed_or_unstarted_deployments_are_unhealthy() {
+        let mut failed = status(
+            SubgraphHealth::failed,
+            vec![chain("mainnet", Some("100"), Some("100"))],
+        );
+        failed.fatal_error = Some(SubgraphError {
+            message: "Mapping aborted".to_string(),
+        });
+        let health = failed.evaluate(5).unwrap();
+        assert!(!health.healthy);
+        assert_eq!(health.fatal_error.as_deref(), Some("Mapping aborted"));
+
+        let unstarted = status(
+            SubgraphHealth::healthy,
+            vec![chain("mainnet", None, Some("100"))],
+        );
+        assert!(!unstarted.evaluate(5).unwrap().healthy);
+    }
+
+    #[test]
+    fn invalid_block_numbers() {
+        let status = status(
+            SubgraphHealth::healthy,
+            vec![chain("mainnet", Some("latest"), Some("100"))],
+        );
+        assert!(status.evaluate(5).is_err());
+    }
+
+    #[tokio::test]
+    async fn queries_blocks_of_every_chain_kind() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": {
+                    "indexingStatuses": [{
+                        "subgraph": "QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ",
+                        "node": "default",
+                        "health": "healthy",
+                        "fatalError": null,
+                        "chains": [{
+                            "network": "near-mainnet",
+                            "latestBlock": { "number": "100", "hash": "0x01" },
+                            "chainHeadBlock": { "number": "101", "hash": "0x02" }
+                        }]
+                    }]
+                }
+            })))
+            .mount(&mock_server)
+            .await;
+
+        // Blocks are fields of the `ChainIndexingStatus` interface, not only of Ethereum chains
+        let query = status_query(&[]);
+        assert!(!query["query"]
+            .as_str()
+            .unwrap()
+            .contains("EthereumIndexingStatus"));
+
+        let statuses = indexing_statuses(
+            &Client::new(),
+            &mock_server.uri(),
+            &["QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ".to_string()],
+        )
+        .await
+        .unwrap();
+        assert!(statuses[0].as_ref().unwrap().evaluate(5).unwrap().healthy);
+    }
+
+    #[tokio::test]
+    async fn invalid_statuses_do_not_fail_the_batch() {
+        let mock_server = MockServer::start().await;
+        let status = |subgraph: &str, health: &str| {
+            json!({
+                "subgraph": subgraph,
+                "node": "default",
+                "health": health,
+                "fatalError": null,
+                "chains": [{
+                    "network": "mainnet",
+                    "latestBlock": { "number": "100", "hash": "0x01" },
+                    "chainHeadBlock": { "number": "101", "hash": "0x02" }
+                }]
+            })
+        };
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": {
+                    "indexingStatuses": [
+                        status("QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ", "healthy"),
+                        status("QmVhiE4nax9i86UBnBmQCYDzvjWuwHShYh7aspGPQhU5Sj", "paused"),
+                        { "health": "healthy" },
+                    ]
+                }
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let statuses = indexing_statuses(
+            &Client::new(),
+            &mock_server.uri(),
+            &[
+                "QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ".to_string(),
+                "QmVhiE4nax9i86UBnBmQCYDzvjWuwHShYh7aspGPQhU5Sj".to_string(),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(statuses.len(), 3);
+        assert!(statuses[0].as_ref().unwrap().evaluate(5).unwrap().healthy);
+        assert_eq!(
+            status_deployment(&statuses[1]),
+            Some("QmVhiE4nax9i86UBnBmQCYDzvjWuwHShYh7aspGPQhU5Sj")
+        );
+        assert!(statuses[1].is_err());
+        assert_eq!(status_deployment(&statuses[2]), None);
+        assert!(statuses[2].is_err());
+    }
+
+    #[tokio::test]
+    async fn missing_statuses_are_errors() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "errors": [{ "message": "Store error" }]
+            })))
+            .mount(&mock_server)
+            .await;
+
+        assert!(indexing_statuses(
+            &Client::new(),
+            &mock_server.uri(),
+            &["QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ".to_string()],
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn resolves_subgraph_names() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_string_contains("graphprotocol/graph-network-mainnet"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": {
+                    "indexingStatusForCurrentVersion": {
+                        "subgraph": "QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ"
+                    }
+                }
+            })))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": { "indexingStatusForCurrentVersion": null }
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let client = Client::new();
+        assert_eq!(
+            current_deployment(
+                &client,
+                &mock_server.uri(),
+                &SubgraphName::new("graphprotocol/graph-network-mainnet"),
+            )
+            .await
+            .unwrap()
+            .as_deref(),
+            Some("QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ")
+        );
+        assert_eq!(
+            current_deployment(
+                &client,
+                &mock_server.uri(),
+                &SubgraphName::new("unknown/name")
+            )
+            .await
+            .unwrap(),
+            None
+        );
+    }
+}
@@ -3,8 +3,11 @@
 
 pub mod address;
 pub mod allocation;
+pub mod cost_model_language;
 pub mod database;
 pub mod indexer_error;
 pub mod indexer_management_client;
+pub mod indexing_status;
 pub mod network_subgraph;
+pub mod query_validation;
 pub mod types;
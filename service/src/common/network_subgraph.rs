@@ -4,17 +4,10 @@
 use std::sync::Arc;
 
 use reqwest::{header, Client, Url};
-use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::common::types::GraphQLQuery;
-use crate::query_processor::{QueryError, UnattestedQueryResult};
-
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-pub struct Response<T> {
-    pub result: T,
-    pub status: i64,
-}
+use crate::query_processor::UnattestedQueryResult;
 
 /// Network subgraph query wrapper
 ///
@@ -61,17 +54,24 @@ impl NetworkSubgraph {
             .expect("Could not parse graph node query endpoint for the network subgraph deployment")
     }
 
-    pub async fn network_query_raw(
+    /// Send a raw query body to the network subgraph, returning the HTTP response as is
+    pub async fn network_query_response(
         &self,
         body: String,
-    ) -> Result<UnattestedQueryResult, reqwest::Error> {
-        let request = self
-            .client
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        self.client
             .post(Url::clone(&self.network_subgraph_url))
-            .body(body.clone())
-            .header(header::CONTENT_TYPE, "application/json");
+            .body(body)
+            .header(header::CONTENT_TYPE, "application/json")
+            .send()
+            .await
+    }
 
-        let response = request.send().await?;
+    pub async fn network_query_raw(
+        &self,
+        body: String,
+    ) -> Result<UnattestedQueryResult, reqwest::Error> {
+        let response = self.network_query_response(body).await?;
 
         // actually parse the JSON for the graphQL schema
         let response_text = response.text().await?;
@@ -93,18 +93,6 @@ impl NetworkSubgraph {
         )
         .await
     }
-
-    pub async fn execute_network_free_query(
-        &self,
-        query: String,
-    ) -> Result<Response<UnattestedQueryResult>, QueryError> {
-        let response = self.network_query_raw(query).await?;
-
-        Ok(Response {
-            result: response,
-            status: 200,
-        })
-    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,498 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+
+use graphql_parser::query::{
+    Definition, OperationDefinition, Query, Selection, SelectionSet, Value, VariableDefinition,
+};
+use serde_json::{json, value::RawValue};
+
+use crate::{common::types::GraphQLQuery, config::QueryLimits};
+
+/// Maximum depth of nested fragments, which also stops fragment cycles
+const MAX_FRAGMENT_DEPTH: usize = 16;
+/// Maximum number of selections visited while resolving fragments
+const MAX_SELECTIONS: usize = 10_000;
+
+/// Parses a `{ query, variables }` request body and checks its query against the limits
+pub fn validate_request(body: &str, limits: &QueryLimits) -> Result<GraphQLQuery, String> {
+    let request: GraphQLQuery =
+        serde_json::from_str(body).map_err(|e| format!("Invalid GraphQL request: {}", e))?;
+    validate_query(&request.query, request.variables.as_ref(), limits)?;
+    Ok(request)
+}
+
+/// Splits a batched request body, a JSON array of `{ query, variables }` requests, into the
+/// bodies of its requests and checks each of them against the limits. The bodies are kept
+/// as sent, so that attestations of raw requests cover the bytes the client signed for.
+pub fn validate_batch(body: &str, limits: &QueryLimits) -> Result<Vec<String>, String> {
+    let requests: Vec<&RawValue> =
+        serde_json::from_str(body).map_err(|e| format!("Invalid GraphQL batch: {}", e))?;
+    if requests.is_empty() {
+        return Err("Empty GraphQL batch".to_string());
+    }
+    if requests.len() > limits.max_batch_size {
+        return Err(format!(
+            "Batch of {} queries exceeds the maximum of {}",
+            requests.len(),
+            limits.max_batch_size
+        ));
+    }
+
+    requests
+        .iter()
+        .map(|request| {
+            let body = request.get().to_string();
+            validate_request(&body, limits)?;
+            Ok(body)
+        })
+        .collect()
+}
+
+/// Canonical form of a `{ query, variables, operationName }` request body: the query printed
+/// back from its parsed document, which drops comments and formatting, the variables with
+/// sorted keys, and the operation name if any. Requests that only differ in formatting have
+/// the same canonical form.
+///
+/// The form is defined by this service, other indexers only attest to the same request CIDs
+/// if they run the same normalization.
+pub fn canonical_request(body: &str) -> Result<String, String> {
+    let request: GraphQLQuery =
+        serde_json::from_str(body).map_err(|e| format!("Invalid GraphQL request: {}", e))?;
+    // Documents with several operations run the one named by the request
+    let operation_name = match serde_json::from_str::<serde_json::Value>(body)
+        .map_err(|e| format!("Invalid GraphQL request: {}", e))?
+        .get("operationName")
+    {
+        None | Some(serde_json::Value::Null) => None,
+        Some(serde_json::Value::String(name)) => Some(name.clone()),
+        Some(_) => return Err("Invalid GraphQL request: operationName is not a string".into()),
+    };
+    let mut document = graphql_parser::parse_query::<&str>(&request.query)
+        .map_err(|e| format!("Invalid GraphQL query: {}", e))?;
+
+    // `{ ... }` and `query { ... }` are the same operation
+    for definition in document.definitions.iter_mut() {
+        if let Definition::Operation(OperationDefinition::SelectionSet(selection_set)) = definition
+        {
+            *definition = Definition::Operation(OperationDefinition::Query(Query {
+                position: selection_set.span.0,
+                name: None,
+                variable_definitions: vec![],
+                directives: vec![],
+                selection_set: selection_set.clone(),
+            }));
+        }
+    }
+
+    let variables = match request.variables {
+        Some(serde_json::Value::Object(variables)) if variables.is_empty() => None,
+        variables => variables.map(sort_keys),
+    };
+    let mut canonical = json!({ "query": document.to_string(), "variables": variables });
+    if let Some(operation_name) = operation_name {
+        canonical["operationName"] = json!(operation_name);
+    }
+    Ok(canonical.to_string())
+}
+
+fn sort_keys(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut entries = map.into_iter().collect::<Vec<_>>();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            serde_json::Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, sort_keys(value)))
+                    .collect(),
+            )
+        }
+        serde_json::Value::Array(values) => {
+            serde_json::Value::Array(values.into_iter().map(sort_keys).collect())
+        }
+        value => value,
+    }
+}
+
+pub fn validate_query(
+    query: &str,
+    variables: Option<&serde_json::Value>,
+    limits: &QueryLimits,
+) -> Result<(), String> {
+    let document = graphql_parser::parse_query::<&str>(query)
+        .map_err(|e| format!("Invalid GraphQL query: {}", e))?;
+
+    let mut validator = Validator {
+        fragments: document
+            .definitions
+            .iter()
+            .filter_map(|definition| match definition {
+                Definition::Fragment(fragment) => Some((fragment.name, &fragment.selection_set)),
+                _ => None,
+            })
+            .collect(),
+        variables,
+        defaults: HashMap::new(),
+        limits,
+        aliases: 0,
+        selections: 0,
+    };
+
+    for definition in &document.definitions {
+        let (variable_definitions, selection_set) = match definition {
+            Definition::Operation(OperationDefinition::SelectionSet(selection_set)) => {
+                (&[][..], selection_set)
+            }
+            Definition::Operation(OperationDefinition::Query(query)) => {
+                (&query.variable_definitions[..], &query.selection_set)
+            }
+            Definition::Operation(OperationDefinition::Mutation(mutation)) => {
+                (&mutation.variable_definitions[..], &mutation.selection_set)
+            }
+            Definition::Operation(OperationDefinition::Subscription(subscription)) => (
+                &subscription.variable_definitions[..],
+                &subscription.selection_set,
+            ),
+            Definition::Fragment(_) => continue,
+        };
+
+        validator.defaults = variable_defaults(variable_definitions);
+        let mut root_fields = 0;
+        validator.visit(selection_set, 1, 0, &mut root_fields)?;
+        if root_fields > limits.max_root_fields {
+            return Err(format!(
+                "Query selects {} root fields, the maximum is {}",
+                root_fields, limits.max_root_fields
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Default values of the variables of an operation, used for variables missing from the request
+fn variable_defaults<'q, 'a>(
+    variable_definitions: &'a [VariableDefinition<'q, &'q str>],
+) -> HashMap<&'q str, &'a Value<'q, &'q str>> {
+    variable_definitions
+        .iter()
+        .filter_map(|definition| Some((definition.name, definition.default_value.as_ref()?)))
+        .collect()
+}
+
+struct Validator<'q, 'a> {
+    fragments: HashMap<&'q str, &'a SelectionSet<'q, &'q str>>,
+    variables: Option<&'a serde_json::Value>,
+    // Variable defaults of the operation being visited
+    defaults: HashMap<&'q str, &'a Value<'q, &'q str>>,
+    limits: &'a QueryLimits,
+    aliases: usize,
+    selections: usize,
+}
+
+impl<'q, 'a> Validator<'q, 'a> {
+    fn visit(
+        &mut self,
+        selection_set: &SelectionSet<'q, &'q str>,
+        depth: usize,
+        fragment_depth: usize,
+        root_fields: &mut usize,
+    ) -> Result<(), String> {
+        if fragment_depth > MAX_FRAGMENT_DEPTH {
+            return Err("Fragments are nested too deeply".to_string());
+        }
+
+        for selection in &selection_set.items {
+            self.selections += 1;
+            if self.selections > MAX_SELECTIONS {
+                return Err("Query is too complex".to_string());
+            }
+
+            match selection {
+                Selection::Field(field) => {
+                    if depth > self.limits.max_depth {
+                        return Err(format!(
+                            "Query is nested deeper than the maximum depth of {}",
+                            self.limits.max_depth
+                        ));
+                    }
+                    if depth == 1 {
+                        *root_fields += 1;
+                    }
+                    if field.alias.is_some() {
+                        self.aliases += 1;
+                        if self.aliases > self.limits.max_aliases {
+                            return Err(format!(
+                                "Query uses more than the maximum of {} aliases",
+                                self.limits.max_aliases
+                            ));
+                        }
+                    }
+                    for (name, value) in &field.arguments {
+                        if *name == "first" {
+                            self.check_first(value)?;
+                        }
+                    }
+                    self.visit(&field.selection_set, depth + 1, fragment_depth, root_fields)?;
+                }
+                Selection::InlineFragment(fragment) => self.visit(
+                    &fragment.selection_set,
+                    depth,
+                    fragment_depth + 1,
+                    root_fields,
+                )?,
+                Selection::FragmentSpread(spread) => {
+                    let selection_set = *self
+                        .fragments
+                        .get(spread.fragment_name)
+                        .ok_or_else(|| format!("Unknown fragment `{}`", spread.fragment_name))?;
+                    self.visit(selection_set, depth, fragment_depth + 1, root_fields)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_first(&self, value: &Value<'q, &'q str>) -> Result<(), String> {
+        // Values of variables are not typed by the document, so anything but an integer
+        // would reach graph node unchecked
+        let first = match value {
+            Value::Int(first) => first.as_i64().map(i128::from),
+            Value::Null => None,
+            Value::Variable(name) => match self.variables.and_then(|v| v.get(name)) {
+                Some(serde_json::Value::Null) => None,
+                Some(first) => Some(json_integer(first).ok_or_else(|| not_integer(first))?),
+                None => match self.defaults.get(name) {
+                    Some(Value::Int(first)) => first.as_i64().map(i128::from),
+                    Some(Value::Null) | None => None,
+                    Some(default) => return Err(not_integer(default)),
+                },
+            },
+            value => return Err(not_integer(value)),
+        };
+
+        match first {
+            Some(first) if first > self.limits.max_first as i128 => Err(format!(
+                "`first: {}` exceeds the maximum of {}",
+                first, self.limits.max_first
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn json_integer(value: &serde_json::Value) -> Option<i128> {
+    value
+        .as_i64()
+        .map(i128::from)
+        .or_else(|| value.as_u64().map(i128::from))
+}
+
+fn not_integer(value: impl std::fmt::Display) -> String {
+    format!("`first: {}` is not an integer", value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> QueryLimits {
+        QueryLimits {
+            max_depth: 3,
+            max_root_fields: 2,
+            max_first: 100,
+            max_aliases: 1,
+            max_batch_size: 2,
+        }
+    }
+
+    #[test]
+    fn parses_requests() {
+        let request = validate_request(
+            r#"{"query": "{ tokens(first: 10) { id } }", "variables": {}}"#,
+            &limits(),
+        )
+        .unwrap();
+        assert_eq!(request.query, "{ tokens(first: 10) { id } }");
+
+        assert!(validate_request("{ tokens { id } }", &limits()).is_err());
+        assert!(validate_request(r#"{"query": "{ tokens { id }"}"#, &limits()).is_err());
+    }
+
+    #[test]
+    fn limits_depth_and_root_fields() {
+        assert!(validate_query("{ a { b { c } } }", None, &limits()).is_ok());
+        assert!(validate_query("{ a { b { c { d } } } }", None, &limits()).is_err());
+        assert!(validate_query(
+            "query { ...F } fragment F on Query { a { b { c { d } } } }",
+            None,
+            &limits()
+        )
+        .is_err());
+
+        assert!(validate_query("{ a b }", None, &limits()).is_ok());
+        assert!(validate_query("{ a b c }", None, &limits()).is_err());
+        assert!(validate_query("{ a ... on Query { b c } }", None, &limits()).is_err());
+    }
+
+    #[test]
+    fn limits_first_and_aliases() {
+        assert!(validate_query("{ a(first: 100) { id } }", None, &limits()).is_ok());
+        assert!(validate_query("{ a(first: 101) { id } }", None, &limits()).is_err());
+        assert!(validate_query(
+            "query q($n: Int) { a(first: $n) { id } }",
+            Some(&json!({ "n": 1000 })),
+            &limits()
+        )
+        .is_err());
+
+        // Variables missing from the request take their default value
+        assert!(validate_query(
+            "query q($n: Int = 100000) { a(first: $n) }",
+            None,
+            &limits()
+        )
+        .is_err());
+        assert!(validate_query(
+            "query q($n: Int = 100000) { a(first: $n) { id } }",
+            Some(&json!({})),
+            &limits()
+        )
+        .is_err());
+        assert!(validate_query(
+            "query q($n: Int = 100000) { a(first: $n) { id } }",
+            Some(&json!({ "n": 10 })),
+            &limits()
+        )
+        .is_ok());
+
+        assert!(validate_query("{ x: a { id } }", None, &limits()).is_ok());
+        assert!(validate_query("{ x: a { y: id } }", None, &limits()).is_err());
+    }
+
+    #[test]
+    fn rejects_first_values_that_are_not_integers() {
+        let query = "query q($n: Int) { a(first: $n) { id } }";
+        for n in [json!("1000"), json!(10.5), json!({ "n": 1 }), json!([1])] {
+            assert!(
+                validate_query(query, Some(&json!({ "n": n })), &limits()).is_err(),
+                "{}",
+                n
+            );
+        }
+        assert!(validate_query(query, Some(&json!({ "n": u64::MAX })), &limits()).is_err());
+        assert!(validate_query(query, Some(&json!({ "n": null })), &limits()).is_ok());
+        assert!(validate_query(query, Some(&json!({ "n": 10 })), &limits()).is_ok());
+
+        assert!(validate_query(
+            r#"query q($n: String = "1000") { a(first: $n) { id } }"#,
+            None,
+            &limits()
+        )
+        .is_err());
+        assert!(validate_query(r#"{ a(first: "1000") { id } }"#, None, &limits()).is_err());
+    }
+
+    #[test]
+    fn rejects_fragment_cycles() {
+        assert!(validate_query(
+            "{ ...A } fragment A on Query { ...B } fragment B on Query { ...A }",
+            None,
+            &limits()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn validates_batches() {
+        let queries = validate_batch(
+            r#"[{"query": "{ a }"}, {"query": "{ b }", "variables": {"x": 1}}]"#,
+            &limits(),
+        )
+        .unwrap();
+        assert_eq!(queries.len(), 2);
+        assert_eq!(
+            validate_request(&queries[1], &limits()).unwrap().variables,
+            Some(json!({ "x": 1 }))
+        );
+
+        // Requests are split out as sent, not re-serialized
+        let queries = validate_batch(
+            r#"[ {"variables": {"x": 1.0},  "query": "{ a }"} ,{"query":"{ b }"}]"#,
+            &limits(),
+        )
+        .unwrap();
+        assert_eq!(
+            queries,
+            vec![
+                r#"{"variables": {"x": 1.0},  "query": "{ a }"}"#,
+                r#"{"query":"{ b }"}"#
+            ]
+        );
+
+        assert!(validate_batch("[]", &limits()).is_err());
+        assert!(validate_batch(
+            r#"[{"query": "{ a }"}, {"query": "{ b }"}, {"query": "{ c }"}]"#,
+            &limits()
+        )
+        .is_err());
+        assert!(
+            validate_batch(r#"[{"query": "{ a }"}, {"query": "{ a b c }"}]"#, &limits()).is_err()
+        );
+    }
+
+    #[test]
+    fn canonical_requests() {
+        let canonical = canonical_request(
+            r#"{"query": "{ tokens(first: 10) { id } }", "variables": {"b": 1, "a": {"d": 2, "c": 3}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            canonical,
+            canonical_request(
+                r##"{"variables": {"a": {"c": 3, "d": 2}, "b": 1},
+                    "query": "# tokens\nquery {\n  tokens(first: 10)\n  {\n    id\n  }\n}"}"##,
+            )
+            .unwrap()
+        );
+        assert!(canonical.ends_with(r#""variables":{"a":{"c":3,"d":2},"b":1}}"#));
+
+        // Missing and empty variables are equivalent
+        assert_eq!(
+            canonical_request(r#"{"query": "{ a }"}"#).unwrap(),
+            canonical_request(r#"{"query": "{ a }", "variables": {}}"#).unwrap()
+        );
+        assert_ne!(
+            canonical_request(r#"{"query": "{ a }"}"#).unwrap(),
+            canonical_request(r#"{"query": "{ b }"}"#).unwrap()
+        );
+        assert!(canonical_request(r#"{"query": "{ a"}"#).is_err());
+    }
+
+    #[test]
+    fn canonical_requests_keep_operation_name() {
+        let a =
+            canonical_request(r#"{"query": "query A { a } query B { b }", "operationName": "A"}"#)
+                .unwrap();
+        let b =
+            canonical_request(r#"{"query": "query A { a } query B { b }", "operationName": "B"}"#)
+                .unwrap();
+        assert_ne!(a, b);
+        assert!(a.contains(r#""operationName":"A""#));
+        assert_eq!(
+            a,
+            canonical_request(
+                r#"{"operationName": "A", "query": "query A {\n  a\n}\nquery B {\n  b\n}"}"#
+            )
+            .unwrap()
+        );
+
+        // Requests without an operation name keep their canonical form
+        assert_eq!(
+            canonical_request(r#"{"query": "{ a }", "operationName": null}"#).unwrap(),
+            canonical_request(r#"{"query": "{ a }"}"#).unwrap()
+        );
+        assert!(canonical_request(r#"{"query": "{ a }", "operationName": 1}"#).is_err());
+    }
+}
@@ -19,7 +19,7 @@ pub struct SubgraphName {
 
 /// Implement SubgraphName constructor
 impl SubgraphName {
-    fn new(name: &str) -> SubgraphName {
+    pub fn new(name: &str) -> SubgraphName {
         SubgraphName {
             // or call this field name
             value: name.to_owned(),
@@ -1,6 +1,8 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::HashMap;
+
 use clap::{command, Args, Parser, ValueEnum};
 
 use alloy_primitives::Address;
@@ -26,6 +28,30 @@ pub struct Cli {
     pub network_subgraph: NetworkSubgraph,
     #[command(flatten)]
     pub escrow_subgraph: EscrowSubgraph,
+    /// Per-route rate limits, only configurable through the config file
+    #[clap(skip)]
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    /// Per-route request timeouts and body size limits, only configurable through the config file
+    #[clap(skip)]
+    #[serde(default)]
+    pub request_limits: RequestLimits,
+    /// Query response cache, disabled unless configured in the config file
+    #[clap(skip)]
+    #[serde(default)]
+    pub query_cache: Option<QueryCacheConfig>,
+    /// WebSocket subscriptions proxy for free queries, disabled unless configured in the config file
+    #[clap(skip)]
+    #[serde(default)]
+    pub subscriptions: Option<SubscriptionsConfig>,
+    /// Graph node query client and backends, only configurable through the config file
+    #[clap(skip)]
+    #[serde(default)]
+    pub graph_node: GraphNodeConfig,
+    /// Limits on the queries forwarded to graph node, only configurable through the config file
+    #[clap(skip)]
+    #[serde(default)]
+    pub query_limits: QueryLimits,
 
     #[arg(
         short,
@@ -105,6 +131,15 @@ pub struct IndexerInfrastructure {
         help = "Graph node endpoint for the index node server"
     )]
     pub graph_node_status_endpoint: String,
+    #[clap(
+        long,
+        value_name = "status-allowed-root-fields",
+        env = "STATUS_ALLOWED_ROOT_FIELDS",
+        value_delimiter = ',',
+        default_values_t = default_status_allowed_root_fields(),
+        help = "Root fields of the index node server that can be queried through /status"
+    )]
+    pub status_allowed_root_fields: Vec<String>,
     #[clap(
         long,
         value_name = "log-level",
@@ -125,9 +160,116 @@ pub struct IndexerInfrastructure {
         long,
         value_name = "free-query-auth-token",
         env = "FREE_QUERY_AUTH_TOKEN",
-        help = "Auth token that clients can use to query for free"
+        help = "Auth token that clients can use to query for free, in plaintext or pre-hashed as keccak256:<hash>"
     )]
     pub free_query_auth_token: Option<String>,
+    #[clap(
+        long,
+        value_name = "operator-auth-token",
+        env = "OPERATOR_AUTH_TOKEN",
+        help = "Bearer token to require for the /operator management API, in plaintext or pre-hashed as keccak256:<hash>. The management API is disabled without it"
+    )]
+    pub operator_auth_token: Option<String>,
+    #[clap(
+        long,
+        value_name = "free-query-require-allocation",
+        env = "FREE_QUERY_REQUIRE_ALLOCATION",
+        default_value_t = false,
+        help = "Only serve free queries on deployments with an eligible allocation or in free-query-allowed-deployments"
+    )]
+    pub free_query_require_allocation: bool,
+    #[clap(
+        long,
+        value_name = "free-query-allowed-deployments",
+        env = "FREE_QUERY_ALLOWED_DEPLOYMENTS",
+        value_delimiter = ',',
+        help = "Deployments (IPFS hashes) that can be queried for free without an allocation"
+    )]
+    #[serde(default)]
+    pub free_query_allowed_deployments: Vec<String>,
+    #[clap(
+        long,
+        value_name = "shutdown-drain-timeout",
+        env = "SHUTDOWN_DRAIN_TIMEOUT",
+        default_value_t = 30_000,
+        help = "Time (in ms) to wait for in-flight queries to finish on shutdown"
+    )]
+    pub shutdown_drain_timeout: u64,
+    #[clap(
+        long,
+        value_name = "deployment-max-block-lag",
+        env = "DEPLOYMENT_MAX_BLOCK_LAG",
+        default_value_t = 5,
+        help = "Maximum number of blocks a deployment may lag behind a chain head to be considered healthy"
+    )]
+    pub deployment_max_block_lag: u64,
+    #[clap(
+        long,
+        value_name = "indexing-status-syncing-interval",
+        env = "INDEXING_STATUS_SYNCING_INTERVAL",
+        default_value_t = 30_000,
+        help = "Interval (in ms) for syncing the indexing statuses of allocated deployments"
+    )]
+    pub indexing_status_syncing_interval: u64,
+    #[clap(
+        long,
+        value_name = "api-keys-reload-interval",
+        env = "API_KEYS_RELOAD_INTERVAL",
+        default_value_t = 60_000,
+        help = "Interval (in ms) for reloading API keys from the database, picking up the changes made through other service instances"
+    )]
+    pub api_keys_reload_interval: u64,
+    #[clap(
+        long,
+        value_name = "cost-models-reload-interval",
+        env = "COST_MODELS_RELOAD_INTERVAL",
+        default_value_t = 300_000,
+        help = "Interval (in ms) for reloading all cost models from the database, in case their change notifications are missed"
+    )]
+    pub cost_models_reload_interval: u64,
+    #[clap(
+        long,
+        value_name = "paid-query-max-block-lag",
+        env = "PAID_QUERY_MAX_BLOCK_LAG",
+        default_value_t = 50,
+        help = "Maximum number of blocks a deployment may lag behind a chain head to serve paid queries"
+    )]
+    pub paid_query_max_block_lag: u64,
+    #[clap(
+        long,
+        value_name = "unhealthy-deployment-queries",
+        env = "UNHEALTHY_DEPLOYMENT_QUERIES",
+        value_enum,
+        default_value_t = UnhealthyDeploymentQueries::Reject,
+        help = "Whether paid queries on failed or lagging deployments are rejected or served without attestation"
+    )]
+    pub unhealthy_deployment_queries: UnhealthyDeploymentQueries,
+    #[clap(
+        long,
+        value_name = "attestation-request-form",
+        env = "ATTESTATION_REQUEST_FORM",
+        value_enum,
+        default_value_t = AttestationRequestForm::Raw,
+        help = "Whether attestations are over the raw request body or its canonical form"
+    )]
+    pub attestation_request_form: AttestationRequestForm,
+}
+
+/// Index node server root fields that are safe to expose publicly through /status
+fn default_status_allowed_root_fields() -> Vec<String> {
+    [
+        "indexingStatuses",
+        "publicProofsOfIndexing",
+        "entityChangesInBlock",
+        "blockData",
+        "blockHashFromNumber",
+        "cachedEthereumCalls",
+        "subgraphFeatures",
+        "apiVersions",
+    ]
+    .iter()
+    .map(|field| field.to_string())
+    .collect()
 }
 
 #[derive(Clone, Debug, Args, Serialize, Deserialize, Default)]
@@ -196,7 +338,7 @@ pub struct NetworkSubgraph {
         long,
         value_name = "network-subgraph-auth-token",
         env = "NETWORK_SUBGRAPH_AUTH_TOKEN",
-        help = "Bearer token to require for /network queries"
+        help = "Bearer token to require for /network queries, in plaintext or pre-hashed as keccak256:<hash>"
     )]
     pub network_subgraph_auth_token: Option<String>,
     #[clap(
@@ -270,6 +412,249 @@ pub struct EscrowSubgraph {
     pub escrow_syncing_interval: u64,
 }
 
+/// What a rate limit counts requests by. Combining several keys counts each
+/// combination separately, e.g. `["client_ip", "deployment"]` limits every client
+/// on every deployment. Requests without a key (no known auth token or API key, no
+/// receipt from an eligible sender, no deployment) are counted by client IP for that
+/// key instead.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    ClientIp,
+    AuthToken,
+    ReceiptSender,
+    Deployment,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Maximum number of requests per key within a period, 0 disables the rate limit
+    pub limit: u64,
+    /// Length of the rate limiting period (ms)
+    pub period_ms: u64,
+    #[serde(default = "RateLimit::default_keys")]
+    pub keys: Vec<RateLimitKey>,
+}
+
+impl RateLimit {
+    fn default_keys() -> Vec<RateLimitKey> {
+        vec![RateLimitKey::ClientIp]
+    }
+
+    /// Limit requests to 9000/30min (5/s) per client
+    fn slow() -> Self {
+        RateLimit {
+            limit: 9000,
+            period_ms: 30 * 60 * 1000,
+            keys: Self::default_keys(),
+        }
+    }
+
+    /// Limit requests to 90000/30min (50/s) per client
+    fn network() -> Self {
+        RateLimit {
+            limit: 90000,
+            period_ms: 30 * 60 * 1000,
+            keys: Self::default_keys(),
+        }
+    }
+}
+
+/// Rate limits per route, routes without a limit are not rate limited
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub subgraphs: Option<RateLimit>,
+    pub status: Option<RateLimit>,
+    pub network: Option<RateLimit>,
+    pub cost: Option<RateLimit>,
+    pub deployment_health: Option<RateLimit>,
+    pub operator: Option<RateLimit>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            subgraphs: None,
+            status: Some(RateLimit::network()),
+            network: Some(RateLimit::network()),
+            cost: Some(RateLimit::slow()),
+            deployment_health: Some(RateLimit::slow()),
+            operator: Some(RateLimit::slow()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestLimit {
+    /// Time (in ms) after which a request is aborted
+    pub timeout_ms: u64,
+    /// Maximum size (in bytes) of a request body
+    pub max_body_size: u64,
+}
+
+impl Default for RequestLimit {
+    fn default() -> Self {
+        RequestLimit {
+            timeout_ms: 10_000,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+/// Request limits per route. `default` applies to the basic routes (index, health and version)
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestLimits {
+    pub default: RequestLimit,
+    pub subgraphs: RequestLimit,
+    pub status: RequestLimit,
+    pub network: RequestLimit,
+    pub cost: RequestLimit,
+    pub deployment_health: RequestLimit,
+    pub operator: RequestLimit,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueryLimits {
+    /// Maximum nesting depth of fields, root fields being at depth 1
+    pub max_depth: usize,
+    /// Maximum number of root fields per operation
+    pub max_root_fields: usize,
+    /// Maximum value of `first` arguments
+    pub max_first: u64,
+    /// Maximum number of aliased fields
+    pub max_aliases: usize,
+    /// Maximum number of queries in a batched request
+    pub max_batch_size: usize,
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        QueryLimits {
+            max_depth: 32,
+            max_root_fields: 32,
+            max_first: 1000,
+            max_aliases: 128,
+            max_batch_size: 10,
+        }
+    }
+}
+
+/// How a graph node query endpoint is selected for each query
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendSelection {
+    #[default]
+    RoundRobin,
+    LeastLatency,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GraphNodeConfig {
+    /// Graph node query endpoints, replacing `graph_node_query_endpoint` if not empty
+    pub query_endpoints: Vec<String>,
+    pub selection: BackendSelection,
+    /// Maximum number of idle connections kept per endpoint
+    pub pool_max_idle_per_host: usize,
+    /// Time (in ms) after which idle connections are closed
+    pub pool_idle_timeout_ms: u64,
+    /// Interval (in ms) of TCP keep-alive probes
+    pub tcp_keepalive_ms: u64,
+    pub connect_timeout_ms: u64,
+    /// Time (in ms) after which a query to graph node is aborted, shorter than the timeout
+    /// of the subgraphs route
+    pub request_timeout_ms: u64,
+    /// Number of times a query is retried on connection errors. Timeouts are not retried.
+    pub max_retries: u32,
+    /// Delay (in ms) before the first retry, doubled for every following retry
+    pub retry_backoff_ms: u64,
+    /// Number of consecutive transport errors after which an endpoint is ejected
+    pub ejection_threshold: u32,
+    /// Time (in ms) an ejected endpoint is left out of the selection
+    pub ejection_ms: u64,
+    /// Query endpoints of each graph node query node, by node ID
+    pub node_endpoints: HashMap<String, Vec<String>>,
+    /// Node ID serving each deployment (IPFS hash), taking precedence over the node assignments
+    /// reported by the indexing status API
+    pub deployment_nodes: HashMap<String, String>,
+}
+
+impl Default for GraphNodeConfig {
+    fn default() -> Self {
+        GraphNodeConfig {
+            query_endpoints: vec![],
+            selection: BackendSelection::default(),
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout_ms: 90_000,
+            tcp_keepalive_ms: 60_000,
+            connect_timeout_ms: 5_000,
+            request_timeout_ms: 8_000,
+            max_retries: 2,
+            retry_backoff_ms: 100,
+            ejection_threshold: 5,
+            ejection_ms: 30_000,
+            node_endpoints: HashMap::new(),
+            deployment_nodes: HashMap::new(),
+        }
+    }
+}
+
+impl GraphNodeConfig {
+    /// Checks that queries to graph node time out before the subgraph route does. Otherwise
+    /// clients are answered with a timeout while graph node keeps working on their query,
+    /// after their receipts have been stored.
+    pub fn validate_timeouts(&self, request_limits: &RequestLimits) -> Result<(), ConfigError> {
+        if self.request_timeout_ms >= request_limits.subgraphs.timeout_ms {
+            return Err(ConfigError::ValidateInput(format!(
+                "graph_node.request_timeout_ms ({}) must be shorter than request_limits.subgraphs.timeout_ms ({})",
+                self.request_timeout_ms, request_limits.subgraphs.timeout_ms
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueryCacheConfig {
+    /// Maximum number of cached responses, least recently used responses are evicted first
+    pub max_entries: usize,
+    /// Time (in ms) after which a cached response expires, at most the indexing status
+    /// syncing interval
+    pub ttl_ms: u64,
+}
+
+impl Default for QueryCacheConfig {
+    fn default() -> Self {
+        QueryCacheConfig {
+            max_entries: 10_000,
+            ttl_ms: 30_000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SubscriptionsConfig {
+    /// Graph node WebSocket endpoint subscriptions are proxied to
+    pub graph_node_endpoint: String,
+    /// Maximum number of concurrent WebSocket connections
+    pub max_connections: usize,
+}
+
+impl Default for SubscriptionsConfig {
+    fn default() -> Self {
+        SubscriptionsConfig {
+            graph_node_endpoint: "ws://localhost:8001".to_string(),
+            max_connections: 100,
+        }
+    }
+}
+
 impl Cli {
     /// Parse config arguments
     /// If environmental variable for config is set to a valid config file path, then parse from config
@@ -308,6 +693,28 @@ pub enum ConfigError {
     Other(anyhow::Error),
 }
 
+/// How paid queries on failed or lagging deployments are handled
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize, Default)]
+pub enum UnhealthyDeploymentQueries {
+    /// Reject the query before its receipt is accepted
+    #[default]
+    Reject,
+    /// Serve the query without an attestation
+    Unattested,
+}
+
+/// Request that attestations of paid queries are made over
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize, Default)]
+pub enum AttestationRequestForm {
+    /// The request body exactly as sent by the client
+    #[default]
+    Raw,
+    /// The normalized query text with sorted variables and the operation name, so that
+    /// equivalent requests attest to the same request CID. The normalization is specific
+    /// to this service.
+    Canonical,
+}
+
 #[derive(
     Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize, Default,
 )]
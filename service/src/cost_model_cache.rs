@@ -0,0 +1,424 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::{postgres::PgListener, PgPool};
+use tokio::sync::Notify;
+
+use crate::common::{
+    cost_model_language::Model,
+    indexer_management_client::{
+        resolver::{self, CostModels},
+        schema::CostModel,
+    },
+};
+
+/// Channel of the notifications sent by the "CostModels" table trigger on every change
+const COST_MODELS_NOTIFICATION_CHANNEL: &str = "cost_models_update_notification";
+/// Delay before listening again after losing the connection to the database
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Name of the "CostModels" table trigger sending the notifications
+const COST_MODELS_TRIGGER: &str = "cost_models_update";
+
+#[derive(Debug, Deserialize)]
+struct CostModelNotification {
+    deployment: String,
+}
+
+/// Cost model of a deployment as parsed, along with the source it was parsed from
+#[derive(Debug)]
+struct ParsedModel {
+    model: String,
+    variables: Option<Value>,
+    parsed: Arc<Model>,
+}
+
+#[derive(Debug)]
+struct CostModelCacheInner {
+    database: PgPool,
+    models: RwLock<CostModels>,
+    // Incremented with every change made by this service, under the `models` write lock, so
+    // that loads from the database that may predate a change are not applied
+    generation: AtomicU64,
+    // Parsed models by deployment, reused for as long as the deployment's model is unchanged
+    parsed_models: Mutex<HashMap<String, ParsedModel>>,
+    reload_interval: Duration,
+    shutdown: Notify,
+}
+
+/// All cost models, loaded at startup and kept current from the notifications of the
+/// "CostModels" table trigger, so that looking up cost models never queries the database.
+/// All cost models are also reloaded periodically, in case notifications are not sent or
+/// the trigger has not been created.
+#[derive(Debug, Clone)]
+pub struct CostModelCache {
+    _listen_handle: Arc<tokio::task::JoinHandle<()>>,
+    inner: Arc<CostModelCacheInner>,
+}
+
+impl CostModelCache {
+    pub async fn new(database: PgPool, reload_interval_ms: u64) -> Result<Self> {
+        let models = resolver::load_cost_models(&database)
+            .await
+            .map_err(|e| anyhow!("Failed to load cost models: {}", e))?;
+        info!("Loaded {} cost models", models.len());
+
+        match Self::trigger_exists(&database).await {
+            Ok(true) => {}
+            Ok(false) => error!(
+                "The \"CostModels\" table has no {} trigger (see \
+                 migrations/20231018120000_cost_models_notify.up.sql), cost model changes made \
+                 outside of this service are only picked up every {} ms",
+                COST_MODELS_TRIGGER, reload_interval_ms
+            ),
+            Err(e) => error!(
+                "Failed to check for the {} trigger: {}",
+                COST_MODELS_TRIGGER, e
+            ),
+        }
+
+        let inner = Arc::new(CostModelCacheInner {
+            database,
+            models: RwLock::new(models),
+            generation: AtomicU64::new(0),
+            parsed_models: Mutex::new(HashMap::new()),
+            reload_interval: Duration::from_millis(reload_interval_ms),
+            shutdown: Notify::new(),
+        });
+
+        let inner_clone = inner.clone();
+        Ok(CostModelCache {
+            _listen_handle: Arc::new(tokio::spawn(async move {
+                CostModelCache::listen_loop(&inner_clone).await;
+            })),
+            inner,
+        })
+    }
+
+    async fn listen_loop(inner: &Arc<CostModelCacheInner>) {
+        loop {
+            match Self::listen(inner).await {
+                Ok(()) => return,
+                Err(e) => warn!(
+                    "Lost cost model notifications, listening again in {:?}: {}",
+                    RECONNECT_DELAY, e
+                ),
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+                _ = inner.shutdown.notified() => {
+                    info!("Cost model cache stopped");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Whether the trigger notifying of "CostModels" changes exists. The indexer agent
+    /// migrations do not create it, operators do with
+    /// `migrations/20231018120000_cost_models_notify.up.sql`.
+    async fn trigger_exists(database: &PgPool) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM pg_trigger
+                WHERE tgname = $1 AND tgrelid = to_regclass('"CostModels"')
+            )
+        "#,
+        )
+        .bind(COST_MODELS_TRIGGER)
+        .fetch_one(database)
+        .await
+    }
+
+    /// Applies cost model notifications until stopped. All cost models are reloaded once
+    /// listening, so that changes made while not listening are not missed, and then
+    /// periodically.
+    async fn listen(inner: &Arc<CostModelCacheInner>) -> Result<()> {
+        let mut listener = PgListener::connect_with(&inner.database).await?;
+        listener.listen(COST_MODELS_NOTIFICATION_CHANNEL).await?;
+
+        Self::reload(inner).await?;
+        let mut reload = tokio::time::interval_at(
+            tokio::time::Instant::now() + inner.reload_interval,
+            inner.reload_interval,
+        );
+
+        loop {
+            tokio::select! {
+                // Notifications are missed while the listener reconnects, so a lost connection
+                // is an error that reloads all cost models
+                notification = listener.try_recv() => {
+                    let notification = notification?
+                        .ok_or_else(|| anyhow!("Lost the connection to the database"))?;
+                    let notification: CostModelNotification =
+                        serde_json::from_str(notification.payload())?;
+                    Self::update(inner, &notification.deployment).await?;
+                }
+                _ = reload.tick() => Self::reload(inner).await?,
+                _ = inner.shutdown.notified() => {
+                    info!("Cost model cache stopped");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Replaces all cached cost models with the ones in the database. Cost models are loaded
+    /// again if this service changed one while loading, as the change may be missing from
+    /// what was loaded.
+    async fn reload(inner: &Arc<CostModelCacheInner>) -> Result<()> {
+        loop {
+            let generation = inner.generation.load(Ordering::SeqCst);
+            let models = resolver::load_cost_models(&inner.database)
+                .await
+                .map_err(|e| anyhow!("{}", e))?;
+
+            let mut current = inner.models.write().unwrap();
+            if inner.generation.load(Ordering::SeqCst) == generation {
+                *current = models;
+                // Parsed models are checked against their source before use, clearing them
+                // only drops the ones of deployments that are no longer queried
+                inner.parsed_models.lock().unwrap().clear();
+                return Ok(());
+            }
+        }
+    }
+
+    /// Replaces the cached cost model of a deployment with the one in the database, loading
+    /// it again if this service changed a cost model meanwhile
+    async fn update(inner: &Arc<CostModelCacheInner>, deployment: &str) -> Result<()> {
+        loop {
+            let generation = inner.generation.load(Ordering::SeqCst);
+            let model = resolver::load_cost_model(&inner.database, deployment)
+                .await
+                .map_err(|e| anyhow!("{}", e))?;
+
+            let mut models = inner.models.write().unwrap();
+            if inner.generation.load(Ordering::SeqCst) == generation {
+                match model {
+                    Some(model) => models.insert(deployment.to_string(), model),
+                    None => models.remove(deployment),
+                };
+                return Ok(());
+            }
+        }
+    }
+
+    /// Caches a cost model as written by this service, without waiting for its notification
+    pub fn set(&self, model: CostModel) {
+        let mut models = self.inner.models.write().unwrap();
+        models.insert(model.deployment.clone(), model);
+        self.inner.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Removes the cost models deleted by this service, without waiting for their notifications
+    pub fn remove(&self, deployments: &[String]) {
+        let mut models = self.inner.models.write().unwrap();
+        for deployment in deployments {
+            if let Ok(deployment) = resolver::stored_deployment(deployment) {
+                models.remove(&deployment);
+            }
+        }
+        self.inner.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Stops listening to cost model notifications. Cost models are kept as they were.
+    pub fn stop(&self) {
+        self.inner.shutdown.notify_one();
+    }
+
+    /// Cost models of the deployments with the global cost model applied, or all cost models
+    pub fn cost_models(&self, deployments: &[String]) -> Vec<CostModel> {
+        resolver::cost_models(&self.inner.models.read().unwrap(), deployments)
+    }
+
+    /// Cost model of the deployment with the global cost model applied
+    pub fn cost_model(&self, deployment: &str) -> Option<CostModel> {
+        resolver::cost_model(&self.inner.models.read().unwrap(), deployment)
+    }
+
+    /// Parsed cost model of the deployment with the global cost model applied, if it has a
+    /// model. Models are only parsed again once they change.
+    pub fn parsed_cost_model(&self, deployment: &str) -> Option<Result<Arc<Model>, String>> {
+        let CostModel {
+            model, variables, ..
+        } = self.cost_model(deployment)?;
+        let model = model?;
+
+        if let Some(parsed) = self.inner.parsed_models.lock().unwrap().get(deployment) {
+            if parsed.model == model && parsed.variables == variables {
+                return Some(Ok(parsed.parsed.clone()));
+            }
+        }
+
+        let parsed = match Model::parse(&model, variables.as_ref()) {
+            Ok(parsed) => Arc::new(parsed),
+            Err(e) => return Some(Err(e)),
+        };
+        self.inner.parsed_models.lock().unwrap().insert(
+            deployment.to_string(),
+            ParsedModel {
+                model,
+                variables,
+                parsed: parsed.clone(),
+            },
+        );
+        Some(Ok(parsed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Executor;
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn follows_cost_model_changes(pool: PgPool) {
+        pool.execute(include_str!(
+            "../../migrations/20230901142040_cost_models.up.sql"
+        ))
+        .await
+        .unwrap();
+        pool.execute(include_str!(
+            "../../migrations/20231018120000_cost_models_notify.up.sql"
+        ))
+        .await
+        .unwrap();
+
+        let deployment_hash = "Qmb5Ysp5oCUXhLA8NmxmYKDAX2nCMnh7Vvb5uffb9n5vss";
+        let deployment_id = "0xbd499f7673ca32ef4a642207a8bebdd0fb03888cf2678b298438e3a1ae5206ea";
+        sqlx::query(r#"INSERT INTO "CostModels" (deployment, model) VALUES ($1, $2)"#)
+            .bind(deployment_id)
+            .bind("default => 0.00025;")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // Loaded at startup
+        let cache = CostModelCache::new(pool.clone(), 60_000).await.unwrap();
+        assert_eq!(
+            cache.cost_model(deployment_hash).unwrap().model.as_deref(),
+            Some("default => 0.00025;")
+        );
+
+        // Changes are applied once notified, or when reloading once listening
+        let wait_for = |expected: &'static str| {
+            let cache = cache.clone();
+            async move {
+                for _ in 0..50 {
+                    let model = cache.cost_model(deployment_hash).and_then(|m| m.model);
+                    if model.as_deref() == Some(expected) {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                panic!("Cost model cache did not apply the change to {}", expected);
+            }
+        };
+        sqlx::query(r#"UPDATE "CostModels" SET model = $1 WHERE deployment = $2"#)
+            .bind("default => 0.0005;")
+            .bind(deployment_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        wait_for("default => 0.0005;").await;
+
+        // Without its own cost model, the deployment falls back to the global one
+        sqlx::query(r#"INSERT INTO "CostModels" (deployment, model) VALUES ('global', $1)"#)
+            .bind("default => 0.00001;")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(r#"DELETE FROM "CostModels" WHERE deployment = $1"#)
+            .bind(deployment_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        wait_for("default => 0.00001;").await;
+
+        cache.stop();
+    }
+
+    #[sqlx::test]
+    async fn reloads_without_notifications(pool: PgPool) {
+        pool.execute(include_str!(
+            "../../migrations/20230901142040_cost_models.up.sql"
+        ))
+        .await
+        .unwrap();
+        assert!(!CostModelCache::trigger_exists(&pool).await.unwrap());
+
+        let deployment_hash = "Qmb5Ysp5oCUXhLA8NmxmYKDAX2nCMnh7Vvb5uffb9n5vss";
+        let deployment_id = "0xbd499f7673ca32ef4a642207a8bebdd0fb03888cf2678b298438e3a1ae5206ea";
+        let cache = CostModelCache::new(pool.clone(), 100).await.unwrap();
+        assert!(cache.cost_model(deployment_hash).is_none());
+
+        // Changes made through this service apply at once
+        cache.set(CostModel {
+            deployment: deployment_id.to_string(),
+            model: Some("default => 0.00025;".to_string()),
+            variables: None,
+        });
+        assert_eq!(
+            cache.cost_model(deployment_hash).unwrap().model.as_deref(),
+            Some("default => 0.00025;")
+        );
+
+        // Models are parsed once, and again when they change
+        let parsed = cache.parsed_cost_model(deployment_hash).unwrap().unwrap();
+        assert!(Arc::ptr_eq(
+            &parsed,
+            &cache.parsed_cost_model(deployment_hash).unwrap().unwrap()
+        ));
+        cache.set(CostModel {
+            deployment: deployment_id.to_string(),
+            model: Some("default => 0.0005;".to_string()),
+            variables: None,
+        });
+        assert!(!Arc::ptr_eq(
+            &parsed,
+            &cache.parsed_cost_model(deployment_hash).unwrap().unwrap()
+        ));
+        cache.set(CostModel {
+            deployment: deployment_id.to_string(),
+            model: Some("default =>".to_string()),
+            variables: None,
+        });
+        assert!(cache.parsed_cost_model(deployment_hash).unwrap().is_err());
+
+        cache.remove(&[deployment_hash.to_string()]);
+        assert!(cache.parsed_cost_model(deployment_hash).is_none());
+        assert!(cache.cost_model(deployment_hash).is_none());
+
+        // Others are picked up by the periodic reload
+        sqlx::query(r#"INSERT INTO "CostModels" (deployment, model) VALUES ($1, $2)"#)
+            .bind(deployment_id)
+            .bind("default => 0.0005;")
+            .execute(&pool)
+            .await
+            .unwrap();
+        for _ in 0..50 {
+            if cache.cost_model(deployment_hash).is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(
+            cache.cost_model(deployment_hash).unwrap().model.as_deref(),
+            Some("default => 0.0005;")
+        );
+
+        cache.stop();
+    }
+}
@@ -3,6 +3,7 @@
 
 use std::collections::HashMap;
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use alloy_primitives::Address;
@@ -12,7 +13,7 @@ use log::{error, info};
 
 use serde::Deserialize;
 
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
 
 use crate::common::types::GraphQLQuery;
 use crate::graph_node::GraphNodeInstance;
@@ -24,6 +25,9 @@ struct EscrowMonitorInner {
     indexer_address: Address,
     interval_ms: u64,
     sender_accounts: Arc<RwLock<HashMap<Address, U256>>>,
+    synced: AtomicBool,
+    resync: Notify,
+    shutdown: Notify,
 }
 
 #[cfg_attr(test, faux::create)]
@@ -49,6 +53,9 @@ impl EscrowMonitor {
             indexer_address,
             interval_ms,
             sender_accounts,
+            synced: AtomicBool::new(false),
+            resync: Notify::new(),
+            shutdown: Notify::new(),
         });
 
         let inner_clone = inner.clone();
@@ -158,6 +165,7 @@ impl EscrowMonitor {
         loop {
             match Self::update_accounts(inner).await {
                 Ok(_) => {
+                    inner.synced.store(true, Ordering::Relaxed);
                     info!("Updated escrow accounts");
                 }
                 Err(e) => {
@@ -165,14 +173,38 @@ impl EscrowMonitor {
                 }
             }
 
-            tokio::time::sleep(tokio::time::Duration::from_millis(inner.interval_ms)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(tokio::time::Duration::from_millis(inner.interval_ms)) => {}
+                _ = inner.resync.notified() => {}
+                _ = inner.shutdown.notified() => {
+                    info!("Escrow monitor stopped");
+                    return Ok(());
+                }
+            }
         }
     }
 
+    /// Stops the monitor loop. Escrow accounts are kept as they were last synced.
+    pub fn stop(&self) {
+        self.inner.shutdown.notify_one();
+    }
+
+    /// Syncs the escrow accounts right away instead of waiting for the next interval. A resync
+    /// requested while syncing runs as soon as the current one completes.
+    pub fn resync(&self) {
+        self.inner.resync.notify_one();
+    }
+
     pub async fn get_accounts(&self) -> tokio::sync::RwLockReadGuard<'_, HashMap<Address, U256>> {
         self.inner.sender_accounts.read().await
     }
 
+    /// Returns true once the escrow accounts have been synced from the escrow subgraph at least
+    /// once.
+    pub fn has_synced(&self) -> bool {
+        self.inner.synced.load(Ordering::Relaxed)
+    }
+
     /// Returns true if the given address has a non-zero balance in the escrow contract.
     ///
     /// Note that this method does not take into account the outstanding TAP balance (Escrow balance - TAP receipts).
@@ -198,7 +230,11 @@ mod tests {
         let escrow_subgraph_deployment = "Qmabcdefghijklmnopqrstuvwxyz1234567890ABCDEFGH";
 
         let mock_server = MockServer::start().await;
-        let graph_node = graph_node::GraphNodeInstance::new(&mock_server.uri());
+        let graph_node = graph_node::GraphNodeInstance::new(
+            &[mock_server.uri()],
+            &crate::config::GraphNodeConfig::default(),
+        )
+        .unwrap();
 
         let mock = Mock::given(method("POST"))
             .and(path(
@@ -216,6 +252,9 @@ mod tests {
             indexer_address,
             interval_ms: 1000,
             sender_accounts: Arc::new(RwLock::new(HashMap::new())),
+            synced: AtomicBool::new(false),
+            resync: Notify::new(),
+            shutdown: Notify::new(),
         };
 
         let accounts = EscrowMonitor::current_accounts(
@@ -1,63 +1,431 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
+use log::warn;
 use reqwest::{header, Client, Url};
+use serde::Serialize;
+use tokio::sync::watch;
 
+use crate::config::{BackendSelection, ConfigError, GraphNodeConfig};
+use crate::metrics;
 use crate::query_processor::{QueryError, UnattestedQueryResult};
 
+/// Deployment and body of a query
+type QueryKey = (String, String);
+/// Result of an in-flight query, shared with the identical queries waiting on it
+type SharedResult = Option<Result<UnattestedQueryResult, String>>;
+type InFlightQueries = Mutex<HashMap<QueryKey, watch::Receiver<SharedResult>>>;
+
+/// A graph node query endpoint, ejected from the selection after repeated transport errors
+#[derive(Debug)]
+struct Backend {
+    endpoint: String,
+    subgraphs_base_url: Url,
+    consecutive_failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    // Moving average of the response latency, 0 until the first response or failure.
+    // Failures count as responses as slow as the request timeout.
+    latency_us: AtomicU64,
+}
+
+impl Backend {
+    fn new(endpoint: &str) -> Result<Self, ConfigError> {
+        Ok(Backend {
+            endpoint: endpoint.to_string(),
+            subgraphs_base_url: Url::parse(endpoint)
+                .and_then(|u| u.join("/subgraphs/id/"))
+                .map_err(|e| {
+                    ConfigError::ValidateInput(format!(
+                        "Invalid graph node endpoint {}: {}",
+                        endpoint, e
+                    ))
+                })?,
+            consecutive_failures: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+            latency_us: AtomicU64::new(0),
+        })
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        self.ejected_until
+            .lock()
+            .unwrap()
+            .map_or(true, |until| now >= until)
+    }
+
+    fn record_latency(&self, latency: Duration) {
+        let latency = latency.as_micros() as u64;
+        let average = match self.latency_us.load(Ordering::Relaxed) {
+            0 => latency,
+            average => (average * 7 + latency) / 8,
+        };
+        self.latency_us.store(average.max(1), Ordering::Relaxed);
+    }
+
+    fn record_success(&self, latency: Duration) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        self.record_latency(latency);
+    }
+
+    fn record_failure(&self, threshold: u32, ejection: Duration, penalty: Duration, now: Instant) {
+        // Failing endpoints must not look fast to the least latency selection
+        self.record_latency(penalty);
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= threshold {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            *self.ejected_until.lock().unwrap() = Some(now + ejection);
+            warn!(
+                "Ejecting graph node endpoint {} for {:?} after {} consecutive errors",
+                self.subgraphs_base_url, ejection, failures
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Backends {
+    backends: Vec<Backend>,
+    selection: BackendSelection,
+    next: AtomicUsize,
+    max_retries: u32,
+    retry_backoff: Duration,
+    ejection_threshold: u32,
+    ejection: Duration,
+    request_timeout: Duration,
+}
+
+impl Backends {
+    /// Endpoints of a graph node query node, `name` describing them in configuration errors
+    fn new(
+        name: &str,
+        endpoints: &[String],
+        config: &GraphNodeConfig,
+    ) -> Result<Self, ConfigError> {
+        if endpoints.is_empty() {
+            return Err(ConfigError::ValidateInput(format!(
+                "No query endpoint for {}",
+                name
+            )));
+        }
+        Ok(Backends {
+            backends: endpoints
+                .iter()
+                .map(|e| Backend::new(e))
+                .collect::<Result<Vec<Backend>, ConfigError>>()?,
+            selection: config.selection,
+            next: AtomicUsize::new(0),
+            max_retries: config.max_retries,
+            retry_backoff: Duration::from_millis(config.retry_backoff_ms),
+            ejection_threshold: config.ejection_threshold,
+            ejection: Duration::from_millis(config.ejection_ms),
+            request_timeout: Duration::from_millis(config.request_timeout_ms),
+        })
+    }
+
+    fn record_failure(&self, backend: &Backend, now: Instant) {
+        backend.record_failure(
+            self.ejection_threshold,
+            self.ejection,
+            self.request_timeout,
+            now,
+        );
+    }
+
+    fn endpoints(&self) -> Vec<String> {
+        self.backends.iter().map(|b| b.endpoint.clone()).collect()
+    }
+
+    fn select(&self, now: Instant) -> &Backend {
+        let available = self
+            .backends
+            .iter()
+            .filter(|backend| backend.is_available(now))
+            .collect::<Vec<&Backend>>();
+        // Rather try an ejected endpoint than fail without trying
+        let candidates = if available.is_empty() {
+            self.backends.iter().collect()
+        } else {
+            available
+        };
+
+        match self.selection {
+            BackendSelection::RoundRobin => {
+                candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+            }
+            BackendSelection::LeastLatency => candidates
+                .into_iter()
+                .min_by_key(|backend| backend.latency_us.load(Ordering::Relaxed))
+                .unwrap(),
+        }
+    }
+}
+
+/// Endpoints queries are sent to, per graph node query node
+#[derive(Debug)]
+struct Routing {
+    default: Backends,
+    nodes: HashMap<String, Backends>,
+    // Deployment (IPFS hash) to node ID assignments
+    configured_assignments: HashMap<String, String>,
+    synced_assignments: RwLock<HashMap<String, String>>,
+}
+
+impl Routing {
+    fn assignment(&self, deployment: &str) -> Option<(String, AssignmentSource)> {
+        if let Some(node) = self.configured_assignments.get(deployment) {
+            return Some((node.clone(), AssignmentSource::Config));
+        }
+        self.synced_assignments
+            .read()
+            .unwrap()
+            .get(deployment)
+            .map(|node| (node.clone(), AssignmentSource::Status))
+    }
+
+    fn backends(&self, deployment: &str) -> &Backends {
+        self.assignment(deployment)
+            .and_then(|(node, _)| self.nodes.get(&node))
+            .unwrap_or(&self.default)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum AssignmentSource {
+    Config,
+    Status,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentRoute {
+    pub deployment: String,
+    pub node: String,
+    pub source: AssignmentSource,
+    /// Endpoints the deployment is queried at, the default endpoints if the node has none
+    pub endpoints: Vec<String>,
+}
+
+/// Current routing of queries to graph node query nodes
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoutingTable {
+    pub default_endpoints: Vec<String>,
+    pub node_endpoints: HashMap<String, Vec<String>>,
+    pub deployments: Vec<DeploymentRoute>,
+}
+
 /// Graph node query wrapper.
 ///
 /// This is Arc internally, so it can be cloned and shared between threads.
 #[derive(Debug, Clone)]
 pub struct GraphNodeInstance {
     client: Client, // it is Arc
-    subgraphs_base_url: Arc<Url>,
+    routing: Arc<Routing>,
+    in_flight: Arc<InFlightQueries>,
+}
+
+/// Removes a query from the in-flight queries once it completes or is cancelled
+struct InFlightQuery<'a> {
+    in_flight: &'a InFlightQueries,
+    key: &'a QueryKey,
+}
+
+impl Drop for InFlightQuery<'_> {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(self.key);
+    }
 }
 
 impl GraphNodeInstance {
-    pub fn new(endpoint: &str) -> GraphNodeInstance {
-        let subgraphs_base_url = Url::parse(endpoint)
-            .and_then(|u| u.join("/subgraphs/id/"))
-            .expect("Could not parse graph node endpoint");
+    /// Creates a graph node client querying `endpoints` by default, and the configured node
+    /// endpoints for deployments assigned to a node. Fails if there is no endpoint to query
+    /// or an endpoint is not a valid URL.
+    pub fn new(
+        endpoints: &[String],
+        config: &GraphNodeConfig,
+    ) -> Result<GraphNodeInstance, ConfigError> {
         let client = reqwest::Client::builder()
             .user_agent("indexer-service")
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .pool_idle_timeout(Duration::from_millis(config.pool_idle_timeout_ms))
+            .tcp_keepalive(Duration::from_millis(config.tcp_keepalive_ms))
+            .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+            .timeout(Duration::from_millis(config.request_timeout_ms))
             .build()
             .expect("Could not build a client to graph node query endpoint");
-        GraphNodeInstance {
+        Ok(GraphNodeInstance {
             client,
-            subgraphs_base_url: Arc::new(subgraphs_base_url),
+            routing: Arc::new(Routing {
+                default: Backends::new("graph node", endpoints, config)?,
+                nodes: config
+                    .node_endpoints
+                    .iter()
+                    .map(|(node, endpoints)| {
+                        let name = format!("graph node query node {}", node);
+                        Ok((node.clone(), Backends::new(&name, endpoints, config)?))
+                    })
+                    .collect::<Result<HashMap<String, Backends>, ConfigError>>()?,
+                configured_assignments: config.deployment_nodes.clone(),
+                synced_assignments: RwLock::new(HashMap::new()),
+            }),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Replaces the deployment to node assignments reported by the indexing status API
+    pub fn update_assignments(&self, assignments: HashMap<String, String>) {
+        *self.routing.synced_assignments.write().unwrap() = assignments;
+    }
+
+    pub fn routing_table(&self) -> RoutingTable {
+        let routing = &self.routing;
+        let mut deployments = routing
+            .synced_assignments
+            .read()
+            .unwrap()
+            .keys()
+            .chain(routing.configured_assignments.keys())
+            .cloned()
+            .collect::<Vec<String>>();
+        deployments.sort();
+        deployments.dedup();
+
+        RoutingTable {
+            default_endpoints: routing.default.endpoints(),
+            node_endpoints: routing
+                .nodes
+                .iter()
+                .map(|(node, backends)| (node.clone(), backends.endpoints()))
+                .collect(),
+            deployments: deployments
+                .into_iter()
+                .filter_map(|deployment| {
+                    let (node, source) = routing.assignment(&deployment)?;
+                    let endpoints = routing.backends(&deployment).endpoints();
+                    Some(DeploymentRoute {
+                        deployment,
+                        node,
+                        source,
+                        endpoints,
+                    })
+                })
+                .collect(),
         }
     }
 
+    /// Queries a subgraph. Concurrent identical queries are coalesced into a single request
+    /// to graph-node, whose result is shared by all of them.
     pub async fn subgraph_query_raw(
         &self,
         subgraph_id: &str,
         data: String,
     ) -> Result<UnattestedQueryResult, QueryError> {
-        let request = self
-            .client
-            .post(self.subgraphs_base_url.join(subgraph_id).map_err(|e| {
+        let key = (subgraph_id.to_string(), data);
+
+        let in_flight = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(receiver) => Err(receiver.clone()),
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    in_flight.insert(key.clone(), receiver);
+                    Ok(sender)
+                }
+            }
+        };
+
+        match in_flight {
+            Ok(sender) => {
+                let _in_flight = InFlightQuery {
+                    in_flight: &self.in_flight,
+                    key: &key,
+                };
+                let result = self.send_subgraph_query(&key.0, key.1.clone()).await;
+                let _ = sender.send(Some(
+                    result.as_ref().map(Clone::clone).map_err(|e| e.to_string()),
+                ));
+                result
+            }
+            Err(mut receiver) => {
+                metrics::COALESCED_QUERIES
+                    .with_label_values(&[subgraph_id])
+                    .inc();
+                if receiver.changed().await.is_ok() {
+                    if let Some(result) = receiver.borrow().clone() {
+                        return result.map_err(|e| QueryError::Other(anyhow!(e)));
+                    }
+                }
+                // The query we waited on was cancelled before completing
+                self.send_subgraph_query(&key.0, key.1).await
+            }
+        }
+    }
+
+    /// Sends a query to a selected endpoint. Connection errors are retried with exponential
+    /// backoff, on the next selected endpoint; queries are read-only so this is safe. Timeouts
+    /// are not retried, as they would add load to a slow graph node and outlast the route
+    /// timeouts.
+    async fn send_subgraph_query(
+        &self,
+        subgraph_id: &str,
+        data: String,
+    ) -> Result<UnattestedQueryResult, QueryError> {
+        let backends = self.routing.backends(subgraph_id);
+        let mut attempt = 0;
+        loop {
+            let backend = backends.select(Instant::now());
+            let url = backend.subgraphs_base_url.join(subgraph_id).map_err(|e| {
                 QueryError::Other(anyhow!(
                     "Could not build subgraph query URL: {}",
                     e.to_string()
                 ))
-            })?)
-            .body(data)
-            .header(header::CONTENT_TYPE, "application/json");
-
-        let response = request.send().await?;
-        let attestable = response
-            .headers()
-            .get("graph-attestable")
-            .map_or(false, |v| v == "true");
-
-        Ok(UnattestedQueryResult {
-            graphql_response: response.text().await?,
-            attestable,
-        })
+            })?;
+
+            let started_at = Instant::now();
+            let request = self
+                .client
+                .post(url)
+                .body(data.clone())
+                .header(header::CONTENT_TYPE, "application/json");
+
+            match request.send().await {
+                Ok(response) => {
+                    backend.record_success(started_at.elapsed());
+                    let attestable = response
+                        .headers()
+                        .get("graph-attestable")
+                        .map_or(false, |v| v == "true");
+
+                    return Ok(UnattestedQueryResult {
+                        graphql_response: response.text().await?,
+                        attestable,
+                    });
+                }
+                Err(e) if e.is_connect() => {
+                    backends.record_failure(backend, Instant::now());
+                    if attempt >= backends.max_retries {
+                        return Err(e.into());
+                    }
+                    let backoff = backends.retry_backoff * 2u32.saturating_pow(attempt);
+                    warn!("Graph node query failed, retrying in {:?}: {}", backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    if e.is_timeout() {
+                        backends.record_failure(backend, Instant::now());
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
     }
 }
 
@@ -96,7 +464,7 @@ mod test {
         let graph_node_endpoint = std::env::var("GRAPH_NODE_ENDPOINT")
             .expect("GRAPH_NODE_ENDPOINT env variable is not set");
 
-        GraphNodeInstance::new(&graph_node_endpoint)
+        GraphNodeInstance::new(&[graph_node_endpoint], &GraphNodeConfig::default()).unwrap()
     }
 
     /// Also tests against the network subgraph, but using the `subgraph_query_raw` method
@@ -135,7 +503,8 @@ mod test {
     async fn test_subgraph_query() {
         let mock_server = mock_graph_node_server().await;
 
-        let graph_node = GraphNodeInstance::new(&mock_server.uri());
+        let graph_node =
+            GraphNodeInstance::new(&[mock_server.uri()], &GraphNodeConfig::default()).unwrap();
 
         let query = r#"
             query {
@@ -158,4 +527,163 @@ mod test {
         // Check that the response is valid JSON
         let _json: serde_json::Value = serde_json::from_str(&response.graphql_response).unwrap();
     }
+
+    #[tokio::test]
+    async fn test_coalesce_identical_queries() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/subgraphs/id/".to_string() + NETWORK_SUBGRAPH_ID))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(r#"{"data": {}}"#, "application/json")
+                    .set_delay(std::time::Duration::from_millis(200)),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let graph_node =
+            GraphNodeInstance::new(&[mock_server.uri()], &GraphNodeConfig::default()).unwrap();
+        let query = json!({ "query": "{ graphNetwork(id: 1) { currentEpoch } }" }).to_string();
+
+        let (first, second) = tokio::join!(
+            graph_node.subgraph_query_raw(NETWORK_SUBGRAPH_ID, query.clone()),
+            graph_node.subgraph_query_raw(NETWORK_SUBGRAPH_ID, query.clone()),
+        );
+        assert_eq!(first.unwrap().graphql_response, r#"{"data": {}}"#);
+        assert_eq!(second.unwrap().graphql_response, r#"{"data": {}}"#);
+        assert!(graph_node.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_backend_selection() {
+        let endpoints = ["http://a:8000".to_string(), "http://b:8000".to_string()];
+        let mut config = GraphNodeConfig::default();
+        let graph_node = GraphNodeInstance::new(&endpoints, &config).unwrap();
+        let backends = &graph_node.routing.default;
+        let now = Instant::now();
+
+        let selected = |backends: &Backends| backends.select(now).subgraphs_base_url.to_string();
+        assert_eq!(selected(backends), "http://a:8000/subgraphs/id/");
+        assert_eq!(selected(backends), "http://b:8000/subgraphs/id/");
+
+        // Ejected endpoints are skipped until the ejection expires
+        backends.backends[0].record_failure(
+            1,
+            Duration::from_secs(10),
+            Duration::from_secs(60),
+            now,
+        );
+        assert_eq!(selected(backends), "http://b:8000/subgraphs/id/");
+        assert_eq!(selected(backends), "http://b:8000/subgraphs/id/");
+        assert!(backends.backends[0].is_available(now + Duration::from_secs(10)));
+
+        config.selection = BackendSelection::LeastLatency;
+        let graph_node = GraphNodeInstance::new(&endpoints, &config).unwrap();
+        let backends = &graph_node.routing.default;
+        backends.backends[0].record_success(Duration::from_millis(50));
+        backends.backends[1].record_success(Duration::from_millis(10));
+        assert_eq!(selected(backends), "http://b:8000/subgraphs/id/");
+
+        // Endpoints that only ever failed are not preferred for lack of a latency
+        let graph_node = GraphNodeInstance::new(&endpoints, &config).unwrap();
+        let backends = &graph_node.routing.default;
+        backends.backends[0].record_success(Duration::from_millis(50));
+        backends.record_failure(&backends.backends[1], now);
+        assert_eq!(selected(backends), "http://a:8000/subgraphs/id/");
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_on_timeout() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(r#"{"data": {}}"#, "application/json")
+                    .set_delay(std::time::Duration::from_millis(500)),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let config = GraphNodeConfig {
+            request_timeout_ms: 50,
+            retry_backoff_ms: 1,
+            ..Default::default()
+        };
+        let graph_node = GraphNodeInstance::new(&[mock_server.uri()], &config).unwrap();
+        let query = json!({ "query": "{ graphNetwork(id: 1) { currentEpoch } }" }).to_string();
+        assert!(graph_node
+            .subgraph_query_raw(NETWORK_SUBGRAPH_ID, query)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_invalid_endpoints() {
+        let config = GraphNodeConfig {
+            node_endpoints: HashMap::from([("node_0".to_string(), vec![])]),
+            ..Default::default()
+        };
+        assert!(GraphNodeInstance::new(&[], &GraphNodeConfig::default()).is_err());
+        assert!(GraphNodeInstance::new(&["http://default:8000".to_string()], &config).is_err());
+        assert!(
+            GraphNodeInstance::new(&["not a url".to_string()], &GraphNodeConfig::default())
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_on_next_endpoint() {
+        let mock_server = mock_graph_node_server().await;
+        let config = GraphNodeConfig {
+            retry_backoff_ms: 1,
+            ..Default::default()
+        };
+        // Nothing listens on port 1, so the first endpoint refuses the connection
+        let graph_node = GraphNodeInstance::new(
+            &["http://127.0.0.1:1".to_string(), mock_server.uri()],
+            &config,
+        )
+        .unwrap();
+
+        let query = json!({ "query": "{ graphNetwork(id: 1) { currentEpoch } }" }).to_string();
+        let response = graph_node
+            .subgraph_query_raw(NETWORK_SUBGRAPH_ID, query)
+            .await
+            .unwrap();
+        let _json: serde_json::Value = serde_json::from_str(&response.graphql_response).unwrap();
+    }
+
+    #[test]
+    fn test_deployment_routing() {
+        let config = GraphNodeConfig {
+            node_endpoints: HashMap::from([
+                ("node_0".to_string(), vec!["http://node-0:8000".to_string()]),
+                ("node_1".to_string(), vec!["http://node-1:8000".to_string()]),
+            ]),
+            deployment_nodes: HashMap::from([("QmA".to_string(), "node_0".to_string())]),
+            ..Default::default()
+        };
+        let graph_node =
+            GraphNodeInstance::new(&["http://default:8000".to_string()], &config).unwrap();
+        graph_node.update_assignments(HashMap::from([
+            ("QmA".to_string(), "node_1".to_string()),
+            ("QmB".to_string(), "node_1".to_string()),
+            ("QmC".to_string(), "node_2".to_string()),
+        ]));
+
+        let endpoints = |deployment: &str| graph_node.routing.backends(deployment).endpoints();
+        // Configured assignments take precedence over the indexing status API
+        assert_eq!(endpoints("QmA"), vec!["http://node-0:8000"]);
+        assert_eq!(endpoints("QmB"), vec!["http://node-1:8000"]);
+        // Nodes without endpoints and unassigned deployments use the default endpoints
+        assert_eq!(endpoints("QmC"), vec!["http://default:8000"]);
+        assert_eq!(endpoints("QmD"), vec!["http://default:8000"]);
+
+        let table = graph_node.routing_table();
+        assert_eq!(table.deployments.len(), 3);
+        assert_eq!(table.deployments[0].source, AssignmentSource::Config);
+        assert_eq!(table.deployments[2].endpoints, vec!["http://default:8000"]);
+    }
 }
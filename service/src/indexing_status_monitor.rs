@@ -0,0 +1,418 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::{info, warn};
+use tokio::sync::{Notify, RwLock};
+
+use crate::{
+    allocation_monitor::AllocationMonitor,
+    common::{
+        indexer_error::IndexerError,
+        indexing_status::{indexing_statuses, DeploymentHealth},
+        types::SubgraphDeploymentID,
+    },
+    graph_node::GraphNodeInstance,
+};
+
+/// Number of failed refreshes in a row after which the last synced health is dropped
+const MAX_FAILED_REFRESHES: u32 = 3;
+
+#[derive(Debug, Default)]
+struct HealthState {
+    /// Whether indexing statuses have been synced at least once
+    synced: bool,
+    /// Refreshes that failed in a row since the last successful one
+    failed_refreshes: u32,
+    // Keyed by deployment IPFS hash
+    by_deployment: HashMap<String, DeploymentHealth>,
+}
+
+#[derive(Debug)]
+struct IndexingStatusMonitorInner {
+    allocation_monitor: AllocationMonitor,
+    graph_node: GraphNodeInstance,
+    graph_node_status_endpoint: String,
+    client: reqwest::Client,
+    interval_ms: u64,
+    max_block_lag: u64,
+    deployment_health: RwLock<HealthState>,
+    shutdown: Notify,
+}
+
+/// Keeps the health of every deployment with an eligible allocation, refreshed from the
+/// index node status endpoint in the background. The graph node each deployment is assigned
+/// to is passed on to the graph node client for routing queries.
+#[derive(Debug, Clone)]
+pub struct IndexingStatusMonitor {
+    _monitor_handle: Arc<tokio::task::JoinHandle<()>>,
+    inner: Arc<IndexingStatusMonitorInner>,
+}
+
+impl IndexingStatusMonitor {
+    pub fn new(
+        allocation_monitor: AllocationMonitor,
+        graph_node: GraphNodeInstance,
+        graph_node_status_endpoint: String,
+        interval_ms: u64,
+        max_block_lag: u64,
+    ) -> Self {
+        let inner = Arc::new(IndexingStatusMonitorInner {
+            allocation_monitor,
+            graph_node,
+            graph_node_status_endpoint,
+            client: reqwest::Client::new(),
+            interval_ms,
+            max_block_lag,
+            deployment_health: RwLock::new(HealthState::default()),
+            shutdown: Notify::new(),
+        });
+
+        let inner_clone = inner.clone();
+
+        IndexingStatusMonitor {
+            _monitor_handle: Arc::new(tokio::spawn(async move {
+                IndexingStatusMonitor::monitor_loop(&inner_clone).await;
+            })),
+            inner,
+        }
+    }
+
+    async fn update_deployment_health(
+        inner: &IndexingStatusMonitorInner,
+    ) -> Result<(), IndexerError> {
+        let mut deployments = inner
+            .allocation_monitor
+            .get_eligible_allocations()
+            .await
+            .values()
+            .map(|allocation| allocation.subgraph_deployment.id.ipfs_hash())
+            .collect::<Vec<String>>();
+        deployments.sort();
+        deployments.dedup();
+
+        // An empty list would make the index node server return the status of every deployment
+        let statuses = if deployments.is_empty() {
+            vec![]
+        } else {
+            indexing_statuses(
+                &inner.client,
+                &inner.graph_node_status_endpoint,
+                &deployments,
+            )
+            .await?
+        };
+
+        let mut deployment_health = HashMap::new();
+        let mut assignments = HashMap::new();
+        for status in statuses.iter() {
+            let status = match status {
+                Ok(status) if deployments.contains(&status.subgraph) => status,
+                Ok(_) => continue,
+                // An unparseable status only leaves that deployment without a known health
+                Err(invalid) => {
+                    warn!(
+                        "Failed to parse the indexing status of deployment {:?}: {}",
+                        invalid.subgraph, invalid.error
+                    );
+                    continue;
+                }
+            };
+            if let Some(node) = &status.node {
+                assignments.insert(status.subgraph.clone(), node.clone());
+            }
+            // A malformed status only leaves that deployment without a known health
+            let health = match status.evaluate(inner.max_block_lag) {
+                Ok(health) => health,
+                Err(e) => {
+                    warn!(
+                        "Failed to evaluate the health of deployment {}: {}",
+                        status.subgraph, e
+                    );
+                    continue;
+                }
+            };
+            if !health.healthy {
+                warn!(
+                    "Deployment {} is unhealthy (health: {:?}, fatal error: {:?})",
+                    health.deployment, health.health, health.fatal_error
+                );
+            }
+            deployment_health.insert(health.deployment.clone(), health);
+        }
+
+        *inner.deployment_health.write().await = HealthState {
+            synced: true,
+            failed_refreshes: 0,
+            by_deployment: deployment_health,
+        };
+        inner.graph_node.update_assignments(assignments);
+        Ok(())
+    }
+
+    /// Counts a failed refresh, dropping the last synced health once it is too old to rely on
+    async fn refresh_failed(inner: &IndexingStatusMonitorInner) {
+        let mut state = inner.deployment_health.write().await;
+        state.failed_refreshes += 1;
+        if state.failed_refreshes == MAX_FAILED_REFRESHES && !state.by_deployment.is_empty() {
+            warn!(
+                "Indexing statuses failed to refresh {} times in a row, deployment health is unknown until the next refresh",
+                MAX_FAILED_REFRESHES
+            );
+            state.by_deployment.clear();
+        }
+    }
+
+    async fn monitor_loop(inner: &Arc<IndexingStatusMonitorInner>) {
+        let mut allocations_receiver = inner.allocation_monitor.subscribe();
+
+        loop {
+            if let Err(e) = Self::update_deployment_health(inner).await {
+                warn!(
+                    "Failed to update the indexing statuses of deployments: {}",
+                    e
+                );
+                Self::refresh_failed(inner).await;
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(tokio::time::Duration::from_millis(inner.interval_ms)) => {}
+                // Pick up the statuses of newly allocated deployments right away
+                _ = allocations_receiver.changed() => {}
+                _ = inner.shutdown.notified() => {
+                    info!("Indexing status monitor stopped");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Stops the monitor loop. Deployment health is kept as it was last synced.
+    pub fn stop(&self) {
+        self.inner.shutdown.notify_one();
+    }
+
+    /// Returns the last synced health of a deployment, if it has been synced. Health is
+    /// unknown for deployments with a missing or malformed status, and for all deployments
+    /// once statuses have failed to refresh `MAX_FAILED_REFRESHES` times in a row.
+    pub async fn deployment_health(
+        &self,
+        deployment: &SubgraphDeploymentID,
+    ) -> Option<DeploymentHealth> {
+        self.inner
+            .deployment_health
+            .read()
+            .await
+            .by_deployment
+            .get(&deployment.ipfs_hash())
+            .cloned()
+    }
+
+    /// Whether indexing statuses have been synced at least once
+    pub async fn has_synced(&self) -> bool {
+        self.inner.deployment_health.read().await.synced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use test_log::test;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::test_vectors;
+
+    use super::*;
+
+    fn status(deployment: &str, health: &str, latest: &str, head: &str) -> serde_json::Value {
+        json!({
+            "subgraph": SubgraphDeploymentID::new(deployment).unwrap().ipfs_hash(),
+            "node": "query_node_0",
+            "health": health,
+            "fatalError": null,
+            "chains": [{
+                "network": "mainnet",
+                "latestBlock": { "number": latest, "hash": "0x00" },
+                "chainHeadBlock": { "number": head, "hash": "0x00" },
+            }],
+        })
+    }
+
+    async fn mock_statuses(mock_server: &MockServer, statuses: Vec<serde_json::Value>) {
+        Mock::given(method("POST"))
+            .and(path("/status"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": { "indexingStatuses": statuses }
+            })))
+            .mount(mock_server)
+            .await;
+    }
+
+    /// Monitor state querying the mock server for the statuses of the test vector allocations
+    unsafe fn monitor_inner(mock_server: &MockServer) -> IndexingStatusMonitorInner {
+        let mut mock_allocation_monitor = AllocationMonitor::faux();
+
+        faux::when!(mock_allocation_monitor.get_eligible_allocations).then_unchecked(|_| {
+            // See `attestation_signers::tests` for why the lock is read from another thread
+            let t = std::thread::spawn(|| {
+                let eligible_allocations = Box::leak(Box::new(Arc::new(RwLock::new(
+                    test_vectors::expected_eligible_allocations(),
+                ))));
+                eligible_allocations.blocking_read()
+            });
+            t.join().unwrap()
+        });
+
+        IndexingStatusMonitorInner {
+            allocation_monitor: mock_allocation_monitor,
+            graph_node: GraphNodeInstance::new(
+                &[mock_server.uri()],
+                &crate::config::GraphNodeConfig::default(),
+            )
+            .unwrap(),
+            graph_node_status_endpoint: format!("{}/status", mock_server.uri()),
+            client: reqwest::Client::new(),
+            interval_ms: 1000,
+            max_block_lag: 50,
+            deployment_health: RwLock::new(HealthState::default()),
+            shutdown: Notify::new(),
+        }
+    }
+
+    fn ipfs_hash(deployment: &str) -> String {
+        SubgraphDeploymentID::new(deployment).unwrap().ipfs_hash()
+    }
+
+    #[test(tokio::test)]
+    async fn test_update_deployment_health() {
+        let mock_server = MockServer::start().await;
+        mock_statuses(
+            &mock_server,
+            vec![
+                status(
+                    "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a",
+                    "healthy",
+                    "100",
+                    "101",
+                ),
+                status(
+                    "0xcda7fa0405d6fd10721ed13d18823d24b535060d8ff661f862b26c23334f13bf",
+                    "failed",
+                    "100",
+                    "101",
+                ),
+                status(
+                    "0xc064c354bc21dd958b1d41b67b8ef161b75d2246b425f68ed4c74964ae705cbd",
+                    "healthy",
+                    "100",
+                    "200",
+                ),
+            ],
+        )
+        .await;
+
+        let inner = unsafe { monitor_inner(&mock_server) };
+        IndexingStatusMonitor::update_deployment_health(&inner)
+            .await
+            .unwrap();
+
+        let state = inner.deployment_health.read().await;
+        assert!(state.synced);
+        assert_eq!(state.by_deployment.len(), 3);
+        let healthy = |deployment: &str| state.by_deployment[&ipfs_hash(deployment)].healthy;
+        assert!(healthy(
+            "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a"
+        ));
+        assert!(!healthy(
+            "0xcda7fa0405d6fd10721ed13d18823d24b535060d8ff661f862b26c23334f13bf"
+        ));
+        // Lagging more than 50 blocks behind chain head
+        assert!(!healthy(
+            "0xc064c354bc21dd958b1d41b67b8ef161b75d2246b425f68ed4c74964ae705cbd"
+        ));
+
+        // Node assignments are passed on for routing queries
+        let routing_table = inner.graph_node.routing_table();
+        assert_eq!(routing_table.deployments.len(), 3);
+        assert!(routing_table
+            .deployments
+            .iter()
+            .all(|route| route.node == "query_node_0"));
+    }
+
+    #[test(tokio::test)]
+    async fn malformed_statuses_leave_health_unknown() {
+        let mock_server = MockServer::start().await;
+        mock_statuses(
+            &mock_server,
+            vec![
+                status(
+                    "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a",
+                    "healthy",
+                    "100",
+                    "101",
+                ),
+                status(
+                    "0xc064c354bc21dd958b1d41b67b8ef161b75d2246b425f68ed4c74964ae705cbd",
+                    "healthy",
+                    "latest",
+                    "200",
+                ),
+                // A health this service does not know of
+                status(
+                    "0xcda7fa0405d6fd10721ed13d18823d24b535060d8ff661f862b26c23334f13bf",
+                    "paused",
+                    "100",
+                    "101",
+                ),
+            ],
+        )
+        .await;
+
+        let inner = unsafe { monitor_inner(&mock_server) };
+        IndexingStatusMonitor::update_deployment_health(&inner)
+            .await
+            .unwrap();
+
+        // The malformed statuses do not prevent updating the other deployments
+        let state = inner.deployment_health.read().await;
+        assert!(state.synced);
+        assert_eq!(state.by_deployment.len(), 1);
+        assert!(state.by_deployment.contains_key(&ipfs_hash(
+            "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a"
+        )));
+    }
+
+    #[test(tokio::test)]
+    async fn health_expires_after_failed_refreshes() {
+        let mock_server = MockServer::start().await;
+        mock_statuses(
+            &mock_server,
+            vec![status(
+                "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a",
+                "healthy",
+                "100",
+                "101",
+            )],
+        )
+        .await;
+
+        let inner = unsafe { monitor_inner(&mock_server) };
+        IndexingStatusMonitor::update_deployment_health(&inner)
+            .await
+            .unwrap();
+
+        for _ in 1..MAX_FAILED_REFRESHES {
+            IndexingStatusMonitor::refresh_failed(&inner).await;
+        }
+        assert_eq!(inner.deployment_health.read().await.by_deployment.len(), 1);
+
+        IndexingStatusMonitor::refresh_failed(&inner).await;
+        let state = inner.deployment_health.read().await;
+        assert!(state.synced);
+        assert!(state.by_deployment.is_empty());
+    }
+}
@@ -3,15 +3,15 @@
 
 use alloy_primitives::Address;
 use alloy_sol_types::eip712_domain;
-use async_graphql::{EmptyMutation, EmptySubscription, Schema};
+use async_graphql::EmptySubscription;
 use axum::Server;
 use dotenvy::dotenv;
 
 use ethereum_types::U256;
 
-use std::{net::SocketAddr, str::FromStr};
+use std::{net::SocketAddr, str::FromStr, time::Duration};
 
-use tracing::info;
+use tracing::{info, warn};
 
 use util::{package_version, shutdown_signal};
 
@@ -19,7 +19,10 @@ use crate::{
     common::network_subgraph::NetworkSubgraph,
     common::{
         database,
-        indexer_management_client::{IndexerManagementClient, QueryRoot},
+        indexer_management_client::{
+            schema::CostSchema, IndexerManagementClient, MutationRoot, QueryRoot,
+        },
+        types::SubgraphDeploymentID,
     },
     config::Cli,
     metrics::handle_serve_metrics,
@@ -28,15 +31,19 @@ use crate::{
     util::public_key,
 };
 
-use server::ServerOptions;
+use server::{auth::AuthToken, ServerOptions};
 
 mod allocation_monitor;
+mod api_keys;
 mod attestation_signers;
 mod common;
 mod config;
+mod cost_model_cache;
 mod escrow_monitor;
 mod graph_node;
+mod indexing_status_monitor;
 mod metrics;
+mod query_cache;
 mod query_processor;
 mod server;
 mod tap_manager;
@@ -66,9 +73,21 @@ async fn main() -> Result<(), std::io::Error> {
     let release = package_version().expect("Failed to resolve for release version");
 
     // Initialize graph-node client
-    let graph_node = graph_node::GraphNodeInstance::new(
-        &config.indexer_infrastructure.graph_node_query_endpoint,
-    );
+    config
+        .graph_node
+        .validate_timeouts(&config.request_limits)
+        .expect("Validate graph node timeouts");
+    let graph_node_query_endpoints = if config.graph_node.query_endpoints.is_empty() {
+        vec![config
+            .indexer_infrastructure
+            .graph_node_query_endpoint
+            .clone()]
+    } else {
+        config.graph_node.query_endpoints.clone()
+    };
+    let graph_node =
+        graph_node::GraphNodeInstance::new(&graph_node_query_endpoints, &config.graph_node)
+            .expect("Initialize graph node client");
 
     // Make an instance of network subgraph at either
     // graph_node_query_endpoint/subgraphs/id/network_subgraph_deployment
@@ -108,6 +127,7 @@ async fn main() -> Result<(), std::io::Error> {
     // agent. Hence we leave syncing and migrating entirely to the agent and
     // assume the models are up to date in the service.
     let database = database::connect(&config.postgres).await;
+    let database_pool = database.clone();
 
     let escrow_monitor = escrow_monitor::EscrowMonitor::new(
         graph_node.clone(),
@@ -121,7 +141,7 @@ async fn main() -> Result<(), std::io::Error> {
     let tap_manager = tap_manager::TapManager::new(
         database.clone(),
         allocation_monitor.clone(),
-        escrow_monitor,
+        escrow_monitor.clone(),
         // TODO: arguments for eip712_domain should be a config
         eip712_domain! {
             name: "TapManager",
@@ -132,43 +152,180 @@ async fn main() -> Result<(), std::io::Error> {
 
     // Proper initiation of server, query processor
     // server health check, graph-node instance connection check
-    let query_processor =
-        QueryProcessor::new(graph_node.clone(), attestation_signers.clone(), tap_manager);
+    let indexing_status_monitor = indexing_status_monitor::IndexingStatusMonitor::new(
+        allocation_monitor.clone(),
+        graph_node.clone(),
+        config
+            .indexer_infrastructure
+            .graph_node_status_endpoint
+            .clone(),
+        config
+            .indexer_infrastructure
+            .indexing_status_syncing_interval,
+        config.indexer_infrastructure.paid_query_max_block_lag,
+    );
+
+    let cost_model_cache = cost_model_cache::CostModelCache::new(
+        database.clone(),
+        config.indexer_infrastructure.cost_models_reload_interval,
+    )
+    .await
+    .expect("Initialize cost model cache");
+
+    let query_processor = QueryProcessor::new(
+        graph_node.clone(),
+        attestation_signers.clone(),
+        tap_manager,
+        indexing_status_monitor.clone(),
+        config.indexer_infrastructure.unhealthy_deployment_queries,
+        config.indexer_infrastructure.attestation_request_form,
+        config.query_cache.as_ref().map(|query_cache| {
+            query_cache::QueryCache::new(
+                query_cache,
+                Duration::from_millis(
+                    config
+                        .indexer_infrastructure
+                        .indexing_status_syncing_interval,
+                ),
+            )
+        }),
+        cost_model_cache.clone(),
+    );
+
+    // Notifies the metrics server once the query server has drained
+    let (metrics_shutdown_sender, mut metrics_shutdown_receiver) = tokio::sync::watch::channel(());
 
     // Start indexer service basic metrics
-    tokio::spawn(handle_serve_metrics(
+    let metrics_server = tokio::spawn(handle_serve_metrics(
         String::from("0.0.0.0"),
         config.indexer_infrastructure.metrics_port,
+        async move {
+            let _ = metrics_shutdown_receiver.changed().await;
+        },
     ));
 
+    let api_keys = api_keys::ApiKeys::new(
+        database.clone(),
+        config.indexer_infrastructure.api_keys_reload_interval,
+    );
+    if let Err(e) = api_keys.reload().await {
+        warn!(
+            "Failed to load API keys, only the free query auth token is accepted: {}",
+            e
+        );
+    }
     let indexer_management_client = IndexerManagementClient::new(database).await;
     let service_options = ServerOptions::new(
         Some(config.indexer_infrastructure.port),
         release,
         query_processor,
-        config.indexer_infrastructure.free_query_auth_token,
+        config
+            .indexer_infrastructure
+            .free_query_auth_token
+            .map(|token| AuthToken::new(&token).expect("Parse free query auth token")),
+        api_keys.clone(),
+        config.indexer_infrastructure.free_query_require_allocation,
+        config
+            .indexer_infrastructure
+            .free_query_allowed_deployments
+            .iter()
+            .map(|deployment| {
+                SubgraphDeploymentID::new(deployment).expect("Parse free query allowed deployment")
+            })
+            .collect(),
+        config.query_limits.clone(),
         config.indexer_infrastructure.graph_node_status_endpoint,
+        config.indexer_infrastructure.status_allowed_root_fields,
+        config.indexer_infrastructure.deployment_max_block_lag,
         indexer_management_client,
+        cost_model_cache.clone(),
         public_key(&config.ethereum.mnemonic).expect("Failed to initiate with operator wallet"),
+        config
+            .indexer_infrastructure
+            .operator_auth_token
+            .map(|token| AuthToken::new(&token).expect("Parse operator auth token")),
         network_subgraph,
-        config.network_subgraph.network_subgraph_auth_token,
+        config
+            .network_subgraph
+            .network_subgraph_auth_token
+            .map(|token| AuthToken::new(&token).expect("Parse network subgraph auth token")),
         config.network_subgraph.serve_network_subgraph,
+        allocation_monitor.clone(),
+        escrow_monitor.clone(),
+        attestation_signers.clone(),
+        config
+            .subscriptions
+            .as_ref()
+            .map(server::subscriptions::SubscriptionProxy::new),
     );
+    let query_processor = service_options.query_processor.clone();
 
     // defineCostModelModels
-    let schema = Schema::build(QueryRoot, EmptyMutation, EmptySubscription).finish();
+    let schema = CostSchema::build(QueryRoot, MutationRoot, EmptySubscription).finish();
 
     info!("Initialized server options");
-    let app = create_server(service_options, schema).await;
+    let app = create_server(
+        service_options,
+        schema,
+        &config.rate_limits,
+        &config.request_limits,
+    )
+    .await;
 
     let addr = SocketAddr::from_str(&format!("0.0.0.0:{}", config.indexer_infrastructure.port))
         .expect("Start server port");
     info!("Initialized server app at {}", addr);
-    Server::bind(&addr)
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
+
+    // Once a shutdown signal is received, the server stops accepting new connections and waits
+    // for in-flight requests to complete, for at most the configured drain timeout
+    let (drain_sender, drain_receiver) = tokio::sync::oneshot::channel();
+    let server = Server::bind(&addr)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            let _ = drain_sender.send(());
+        });
+    let drain_timeout = Duration::from_millis(config.indexer_infrastructure.shutdown_drain_timeout);
+
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = async move {
+            if drain_receiver.await.is_ok() {
+                tokio::time::sleep(drain_timeout).await;
+            } else {
+                std::future::pending::<()>().await;
+            }
+        } => {
+            warn!(
+                "Drain timeout of {:?} elapsed with {} paid queries still in flight",
+                drain_timeout,
+                query_processor.in_flight_paid_queries()
+            );
+        }
+    }
+
+    // Stop background work now that no more queries are served
+    allocation_monitor.stop();
+    escrow_monitor.stop();
+    attestation_signers.stop();
+    indexing_status_monitor.stop();
+    cost_model_cache.stop();
+    api_keys.stop();
+    let _ = metrics_shutdown_sender.send(());
+    if tokio::time::timeout(drain_timeout, metrics_server)
         .await
-        .unwrap();
+        .is_err()
+    {
+        warn!("Metrics server did not stop within the drain timeout");
+    }
+
+    info!(
+        "Closing database pool ({} connections, {} idle)",
+        database_pool.size(),
+        database_pool.num_idle()
+    );
+    database_pool.close().await;
+    info!("Shutdown complete");
 
     Ok(())
 }
@@ -7,7 +7,9 @@ use axum::routing::get;
 use axum::Router;
 use once_cell::sync::Lazy;
 use prometheus::{core::Collector, Registry};
-use prometheus::{linear_buckets, HistogramOpts, HistogramVec, IntCounterVec, Opts};
+use prometheus::{
+    linear_buckets, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+};
 use std::{net::SocketAddr, str::FromStr};
 use tracing::{debug, info};
 
@@ -80,6 +82,150 @@ pub static QUERIES_WITHOUT_RECEIPT: Lazy<IntCounterVec> = Lazy::new(|| {
     m
 });
 
+pub static RATE_LIMITED_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let m = IntCounterVec::new(
+        Opts::new(
+            "rateLimitedRequests",
+            "Requests rejected because they exceeded the route's rate limit",
+        )
+        .namespace("indexer")
+        .subsystem("service"),
+        &["route"],
+    )
+    .expect("Failed to create rateLimitedRequests counters");
+    prometheus::register(Box::new(m.clone()))
+        .expect("Failed to register rateLimitedRequests counter");
+    m
+});
+
+pub static REQUEST_TIMEOUTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let m = IntCounterVec::new(
+        Opts::new(
+            "requestTimeouts",
+            "Requests aborted because they exceeded the route's timeout",
+        )
+        .namespace("indexer")
+        .subsystem("service"),
+        &["route"],
+    )
+    .expect("Failed to create requestTimeouts counters");
+    prometheus::register(Box::new(m.clone())).expect("Failed to register requestTimeouts counter");
+    m
+});
+
+pub static QUERY_CACHE_HITS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let m = IntCounterVec::new(
+        Opts::new("queryCacheHits", "Queries served from the query cache")
+            .namespace("indexer")
+            .subsystem("service"),
+        &["deployment"],
+    )
+    .expect("Failed to create queryCacheHits counters");
+    prometheus::register(Box::new(m.clone())).expect("Failed to register queryCacheHits counter");
+    m
+});
+
+pub static QUERY_CACHE_MISSES: Lazy<IntCounterVec> = Lazy::new(|| {
+    let m = IntCounterVec::new(
+        Opts::new(
+            "queryCacheMisses",
+            "Queries that could not be served from the query cache",
+        )
+        .namespace("indexer")
+        .subsystem("service"),
+        &["deployment"],
+    )
+    .expect("Failed to create queryCacheMisses counters");
+    prometheus::register(Box::new(m.clone())).expect("Failed to register queryCacheMisses counter");
+    m
+});
+
+pub static COALESCED_QUERIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    let m = IntCounterVec::new(
+        Opts::new(
+            "coalescedQueries",
+            "Queries that shared the graph-node response of an identical in-flight query",
+        )
+        .namespace("indexer")
+        .subsystem("service"),
+        &["deployment"],
+    )
+    .expect("Failed to create coalescedQueries counters");
+    prometheus::register(Box::new(m.clone())).expect("Failed to register coalescedQueries counter");
+    m
+});
+
+pub static ACTIVE_SUBSCRIPTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    let m = IntGaugeVec::new(
+        Opts::new(
+            "activeSubscriptions",
+            "GraphQL subscriptions currently proxied to graph node",
+        )
+        .namespace("indexer")
+        .subsystem("service"),
+        &["deployment"],
+    )
+    .expect("Failed to create activeSubscriptions gauges");
+    prometheus::register(Box::new(m.clone()))
+        .expect("Failed to register activeSubscriptions gauge");
+    m
+});
+
+pub static SUBSCRIPTION_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    let m = IntGauge::with_opts(
+        Opts::new(
+            "subscriptionConnections",
+            "Open WebSocket connections proxying subscriptions to graph node",
+        )
+        .namespace("indexer")
+        .subsystem("service"),
+    )
+    .expect("Failed to create subscriptionConnections gauge");
+    prometheus::register(Box::new(m.clone()))
+        .expect("Failed to register subscriptionConnections gauge");
+    m
+});
+
+pub static API_KEY_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let m = IntCounterVec::new(
+        Opts::new("apiKeyRequests", "Requests made with a named API key")
+            .namespace("indexer")
+            .subsystem("service"),
+        &["key", "route", "outcome"],
+    )
+    .expect("Failed to create apiKeyRequests counters");
+    prometheus::register(Box::new(m.clone())).expect("Failed to register apiKeyRequests counter");
+    m
+});
+
+pub static FAILED_AUTH: Lazy<IntCounterVec> = Lazy::new(|| {
+    let m = IntCounterVec::new(
+        Opts::new("failedAuth", "Requests with missing or invalid auth tokens")
+            .namespace("indexer")
+            .subsystem("service"),
+        &["route"],
+    )
+    .expect("Failed to create failedAuth counters");
+    prometheus::register(Box::new(m.clone())).expect("Failed to register failedAuth counter");
+    m
+});
+
+pub static DENIED_DEPLOYMENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let m = IntCounterVec::new(
+        Opts::new(
+            "deniedDeployments",
+            "Allocated deployments that became denied",
+        )
+        .namespace("indexer")
+        .subsystem("service"),
+        &["deployment"],
+    )
+    .expect("Failed to create deniedDeployments counters");
+    prometheus::register(Box::new(m.clone()))
+        .expect("Failed to register deniedDeployments counter");
+    m
+});
+
 #[allow(dead_code)]
 pub static QUERY_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
     let m = HistogramVec::new(
@@ -132,6 +278,16 @@ pub fn start_metrics() {
             Box::new(FAILED_QUERIES.clone()),
             Box::new(QUERIES_WITH_INVALID_RECEIPT_HEADER.clone()),
             Box::new(QUERIES_WITHOUT_RECEIPT.clone()),
+            Box::new(RATE_LIMITED_REQUESTS.clone()),
+            Box::new(REQUEST_TIMEOUTS.clone()),
+            Box::new(DENIED_DEPLOYMENTS.clone()),
+            Box::new(QUERY_CACHE_HITS.clone()),
+            Box::new(QUERY_CACHE_MISSES.clone()),
+            Box::new(COALESCED_QUERIES.clone()),
+            Box::new(ACTIVE_SUBSCRIPTIONS.clone()),
+            Box::new(SUBSCRIPTION_CONNECTIONS.clone()),
+            Box::new(API_KEY_REQUESTS.clone()),
+            Box::new(FAILED_AUTH.clone()),
             Box::new(QUERY_DURATION.clone()),
             Box::new(INDEXER_ERROR.clone()),
         ],
@@ -148,7 +304,11 @@ pub async fn get_metrics() -> (StatusCode, String) {
 }
 
 #[allow(dead_code)]
-pub async fn handle_serve_metrics(host: String, port: u16) {
+pub async fn handle_serve_metrics(
+    host: String,
+    port: u16,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
     // Set up the exporter to collect metrics
     let _exporter = global_metrics_exporter();
 
@@ -163,6 +323,7 @@ pub async fn handle_serve_metrics(host: String, port: u16) {
 
     server
         .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown)
         .await
         .expect("Error starting example API server");
 }
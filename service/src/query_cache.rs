@@ -0,0 +1,279 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use serde_json::Value;
+use sha3::{Digest, Keccak256};
+
+use crate::{common::types::SubgraphDeploymentID, config::QueryCacheConfig};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    deployment: SubgraphDeploymentID,
+    query_hash: [u8; 32],
+    variables_hash: [u8; 32],
+    // Documents with several operations run the one named by the request
+    operation_name: Option<String>,
+}
+
+impl CacheKey {
+    /// Builds the key of a `{ query, variables, operationName }` request body. Returns None if
+    /// the body cannot be parsed, in which case the response is not cached.
+    pub fn new(deployment: &SubgraphDeploymentID, body: &str) -> Option<CacheKey> {
+        let body: Value = serde_json::from_str(body).ok()?;
+        let query = body.get("query")?.as_str()?;
+        // Object keys are sorted when serialized, so equal variables hash the same
+        let variables =
+            serde_json::to_string(body.get("variables").unwrap_or(&Value::Null)).ok()?;
+        let operation_name = match body.get("operationName") {
+            None | Some(Value::Null) => None,
+            Some(name) => Some(name.as_str()?.to_string()),
+        };
+
+        Some(CacheKey {
+            deployment: deployment.clone(),
+            query_hash: keccak256(query.as_bytes()),
+            variables_hash: keccak256(variables.as_bytes()),
+            operation_name,
+        })
+    }
+}
+
+fn keccak256(data: &[u8]) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&Keccak256::digest(data));
+    hash
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    graphql_response: String,
+    // Latest block of every chain of the deployment when the response was cached
+    latest_blocks: Vec<u64>,
+    inserted_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    entries: HashMap<CacheKey, CacheEntry>,
+    // Keys by last use, oldest first
+    recency: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl CacheInner {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+        }
+    }
+}
+
+/// LRU cache of attestable graph-node responses. Entries expire after a TTL and are
+/// dropped as soon as the deployment's latest block is seen to advance.
+///
+/// Latest blocks are only known as of the last indexing status refresh, so the TTL is
+/// capped at the refresh interval: a response is never served for longer than it takes
+/// to notice that the deployment has moved on.
+#[derive(Debug)]
+pub struct QueryCache {
+    max_entries: usize,
+    ttl: Duration,
+    inner: Mutex<CacheInner>,
+}
+
+impl QueryCache {
+    pub fn new(config: &QueryCacheConfig, status_refresh_interval: Duration) -> Self {
+        let mut ttl = Duration::from_millis(config.ttl_ms);
+        if ttl > status_refresh_interval {
+            warn!(
+                "Query cache TTL ({:?}) exceeds the indexing status refresh interval, capping it to {:?}",
+                ttl, status_refresh_interval
+            );
+            ttl = status_refresh_interval;
+        }
+
+        QueryCache {
+            max_entries: config.max_entries,
+            ttl,
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    pub fn get(&self, key: &CacheKey, latest_blocks: &[u64], now: Instant) -> Option<String> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let entry = inner.entries.get(key)?;
+        if entry.latest_blocks != latest_blocks || now.duration_since(entry.inserted_at) > self.ttl
+        {
+            inner.remove(key);
+            return None;
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        let entry = inner.entries.get_mut(key)?;
+        let previous_use = std::mem::replace(&mut entry.last_used, tick);
+        let graphql_response = entry.graphql_response.clone();
+        inner.recency.remove(&previous_use);
+        inner.recency.insert(tick, key.clone());
+
+        Some(graphql_response)
+    }
+
+    pub fn insert(
+        &self,
+        key: CacheKey,
+        graphql_response: String,
+        latest_blocks: Vec<u64>,
+        now: Instant,
+    ) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.remove(&key);
+        while inner.entries.len() >= self.max_entries {
+            let oldest = match inner.recency.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(key) = inner.recency.remove(&oldest) {
+                inner.entries.remove(&key);
+            }
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.recency.insert(tick, key.clone());
+        inner.entries.insert(
+            key,
+            CacheEntry {
+                graphql_response,
+                latest_blocks,
+                inserted_at: now,
+                last_used: tick,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment() -> SubgraphDeploymentID {
+        SubgraphDeploymentID::new("QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ").unwrap()
+    }
+
+    fn cache(max_entries: usize) -> QueryCache {
+        QueryCache::new(
+            &QueryCacheConfig {
+                max_entries,
+                ttl_ms: 1000,
+            },
+            Duration::from_secs(30),
+        )
+    }
+
+    #[test]
+    fn keys_ignore_variable_order() {
+        let a = CacheKey::new(
+            &deployment(),
+            r#"{"query": "{ a }", "variables": {"x": 1, "y": 2}}"#,
+        );
+        let b = CacheKey::new(
+            &deployment(),
+            r#"{"variables": {"y": 2, "x": 1}, "query": "{ a }"}"#,
+        );
+        assert!(a.is_some());
+        assert_eq!(a, b);
+        assert_ne!(a, CacheKey::new(&deployment(), r#"{"query": "{ b }"}"#));
+        assert_eq!(CacheKey::new(&deployment(), "{ a }"), None);
+    }
+
+    #[test]
+    fn keys_include_operation_name() {
+        let body = |operation_name: &str| {
+            format!(
+                r#"{{"query": "query A {{ a }} query B {{ b }}", "operationName": {}}}"#,
+                operation_name
+            )
+        };
+        let a = CacheKey::new(&deployment(), &body(r#""A""#));
+        let b = CacheKey::new(&deployment(), &body(r#""B""#));
+        assert!(a.is_some() && b.is_some());
+        assert_ne!(a, b);
+        assert_eq!(a, CacheKey::new(&deployment(), &body(r#""A""#)));
+        assert_eq!(
+            CacheKey::new(&deployment(), &body("null")),
+            CacheKey::new(&deployment(), r#"{"query": "query A { a } query B { b }"}"#)
+        );
+        assert_eq!(CacheKey::new(&deployment(), &body("1")), None);
+    }
+
+    #[test]
+    fn invalidates_on_new_blocks_and_expiry() {
+        let cache = cache(10);
+        let key = CacheKey::new(&deployment(), r#"{"query": "{ a }"}"#).unwrap();
+        let now = Instant::now();
+
+        cache.insert(key.clone(), "response".to_string(), vec![100], now);
+        assert_eq!(cache.get(&key, &[100], now), Some("response".to_string()));
+        assert_eq!(cache.get(&key, &[101], now), None);
+        // The entry was dropped along with the stale block
+        assert_eq!(cache.get(&key, &[100], now), None);
+
+        cache.insert(key.clone(), "response".to_string(), vec![100], now);
+        assert_eq!(
+            cache.get(&key, &[100], now + Duration::from_millis(1001)),
+            None
+        );
+    }
+
+    #[test]
+    fn ttl_is_capped_at_status_refresh_interval() {
+        let cache = QueryCache::new(
+            &QueryCacheConfig {
+                max_entries: 10,
+                ttl_ms: 60_000,
+            },
+            Duration::from_millis(500),
+        );
+        let key = CacheKey::new(&deployment(), r#"{"query": "{ a }"}"#).unwrap();
+        let now = Instant::now();
+
+        cache.insert(key.clone(), "response".to_string(), vec![100], now);
+        assert!(cache
+            .get(&key, &[100], now + Duration::from_millis(400))
+            .is_some());
+        assert_eq!(
+            cache.get(&key, &[100], now + Duration::from_millis(501)),
+            None
+        );
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = cache(2);
+        let now = Instant::now();
+        let key = |query: &str| {
+            CacheKey::new(&deployment(), &format!(r#"{{"query": "{}"}}"#, query)).unwrap()
+        };
+
+        cache.insert(key("a"), "a".to_string(), vec![1], now);
+        cache.insert(key("b"), "b".to_string(), vec![1], now);
+        assert!(cache.get(&key("a"), &[1], now).is_some());
+        cache.insert(key("c"), "c".to_string(), vec![1], now);
+
+        assert!(cache.get(&key("a"), &[1], now).is_some());
+        assert!(cache.get(&key("b"), &[1], now).is_none());
+        assert!(cache.get(&key("c"), &[1], now).is_some());
+    }
+}
@@ -1,6 +1,12 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use alloy_primitives::Address;
 use ethers_core::types::{Signature, U256};
 use log::error;
 use native::attestation::AttestationSigner;
@@ -8,9 +14,17 @@ use serde::{Deserialize, Serialize};
 use tap_core::tap_manager::SignedReceipt;
 
 use crate::attestation_signers::AttestationSigners;
-use crate::common::types::SubgraphDeploymentID;
-use crate::graph_node::GraphNodeInstance;
-use crate::tap_manager::TapManager;
+use crate::common::cost_model_language::Model;
+use crate::common::indexing_status::SubgraphHealth;
+use crate::common::query_validation::canonical_request;
+use crate::common::types::{GraphQLQuery, SubgraphDeploymentID};
+use crate::config::{AttestationRequestForm, UnhealthyDeploymentQueries};
+use crate::cost_model_cache::CostModelCache;
+use crate::graph_node::{GraphNodeInstance, RoutingTable};
+use crate::indexing_status_monitor::IndexingStatusMonitor;
+use crate::metrics;
+use crate::query_cache::{CacheKey, QueryCache};
+use crate::tap_manager::{OutstandingReceipts, TapManager};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QueryResult {
@@ -48,12 +62,45 @@ pub struct PaidQuery {
     pub receipt: String,
 }
 
+/// Free queries on a deployment sent together in a single request
+#[derive(Debug)]
+pub struct FreeBatch {
+    pub subgraph_deployment_id: SubgraphDeploymentID,
+    pub queries: Vec<String>,
+}
+
+/// Paid queries on a deployment sent together in a single request. The receipt is either
+/// a single receipt covering the whole batch or a JSON array with one receipt per query.
+pub struct PaidBatch {
+    pub subgraph_deployment_id: SubgraphDeploymentID,
+    pub queries: Vec<String>,
+    pub receipt: String,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum QueryError {
     #[error(transparent)]
     Transport(#[from] reqwest::Error),
     #[error("The subgraph is in a failed state")]
     IndexingError,
+    #[error("The subgraph deployment is failed or lagging behind: {0}")]
+    UnhealthyDeployment(String),
+    #[error("The subgraph deployment {0} has been denied")]
+    DeniedDeployment(String),
+    #[error("Receipt's allocation ID ({0}) is for deployment {1}, not {2}")]
+    WrongAllocationDeployment(String, String, String),
+    #[error("Receipt's allocation ID ({0}) is not eligible for this indexer")]
+    IneligibleAllocation(String),
+    #[error("Receipt's sender ({0}) is not eligible for this indexer")]
+    IneligibleSender(String),
+    #[error("Invalid receipt: {0}")]
+    InvalidReceipt(String),
+    #[error("The query cannot be priced by the cost model: {0}")]
+    UnpricedQuery(String),
+    #[error("Receipt value of {0} wei does not cover the query cost of {1} wei")]
+    InsufficientReceiptValue(u128, u128),
+    #[error("Receipt with nonce {0} is repeated in the batch")]
+    DuplicateReceipt(u64),
     #[error("Bad or invalid entity data found in the subgraph: {}", .0.to_string())]
     BadData(anyhow::Error),
     #[error("Unknown error: {0}")]
@@ -65,6 +112,28 @@ pub struct QueryProcessor {
     graph_node: GraphNodeInstance,
     attestation_signers: AttestationSigners,
     tap_manager: TapManager,
+    indexing_status_monitor: IndexingStatusMonitor,
+    unhealthy_deployment_queries: UnhealthyDeploymentQueries,
+    attestation_request_form: AttestationRequestForm,
+    query_cache: Option<Arc<QueryCache>>,
+    cost_model_cache: CostModelCache,
+    in_flight_paid_queries: Arc<AtomicUsize>,
+}
+
+/// Keeps a paid query counted as in flight until it is dropped
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl InFlightGuard {
+    fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(counter.clone())
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl QueryProcessor {
@@ -72,21 +141,54 @@ impl QueryProcessor {
         graph_node: GraphNodeInstance,
         attestation_signers: AttestationSigners,
         tap_manager: TapManager,
+        indexing_status_monitor: IndexingStatusMonitor,
+        unhealthy_deployment_queries: UnhealthyDeploymentQueries,
+        attestation_request_form: AttestationRequestForm,
+        query_cache: Option<QueryCache>,
+        cost_model_cache: CostModelCache,
     ) -> QueryProcessor {
         QueryProcessor {
             graph_node,
             attestation_signers,
             tap_manager,
+            indexing_status_monitor,
+            unhealthy_deployment_queries,
+            attestation_request_form,
+            query_cache: query_cache.map(Arc::new),
+            cost_model_cache,
+            in_flight_paid_queries: Arc::new(AtomicUsize::new(0)),
         }
     }
 
+    /// Recovers the sender of a `scalar-receipt` header value, if it is a valid receipt from
+    /// an eligible sender
+    pub async fn eligible_receipt_sender(&self, receipt: &str) -> Option<Address> {
+        self.tap_manager.eligible_receipt_sender(receipt).await
+    }
+
+    /// Number and total value of the receipts of each sender not yet aggregated into a RAV
+    pub async fn outstanding_receipts(
+        &self,
+    ) -> Result<HashMap<Address, OutstandingReceipts>, anyhow::Error> {
+        self.tap_manager.outstanding_receipts().await
+    }
+
+    /// Current routing of queries to graph node query nodes
+    pub fn routing_table(&self) -> RoutingTable {
+        self.graph_node.routing_table()
+    }
+
+    /// Number of paid queries currently being processed, including storing their receipts
+    pub fn in_flight_paid_queries(&self) -> usize {
+        self.in_flight_paid_queries.load(Ordering::SeqCst)
+    }
+
     pub async fn execute_free_query(
         &self,
         query: FreeQuery,
     ) -> Result<Response<UnattestedQueryResult>, QueryError> {
         let response = self
-            .graph_node
-            .subgraph_query_raw(&query.subgraph_deployment_id.ipfs_hash(), query.query)
+            .subgraph_query(&query.subgraph_deployment_id, query.query)
             .await?;
 
         Ok(Response {
@@ -99,21 +201,29 @@ impl QueryProcessor {
         &self,
         query: PaidQuery,
     ) -> Result<Response<QueryResult>, QueryError> {
+        let _in_flight = InFlightGuard::new(&self.in_flight_paid_queries);
+
         let PaidQuery {
             subgraph_deployment_id,
             query,
             receipt,
         } = query;
 
+        let healthy = self.deployment_healthy(&subgraph_deployment_id).await?;
+        let attested_request = self.attested_request(&query)?;
+
         // TODO: Emit IndexerErrorCode::IE031 on error
         let parsed_receipt: SignedReceipt = serde_json::from_str(&receipt)
             .map_err(|e| QueryError::Other(anyhow::Error::from(e)))?;
 
         let allocation_id = parsed_receipt.message.allocation_id;
 
-        self.tap_manager
-            .verify_and_store_receipt(parsed_receipt)
-            .await?;
+        self.verify_and_store_receipts(
+            &subgraph_deployment_id,
+            std::slice::from_ref(&query),
+            vec![parsed_receipt],
+        )
+        .await?;
 
         let signers = self.attestation_signers.read().await;
         let signer = signers.get(&allocation_id).ok_or_else(|| {
@@ -123,14 +233,10 @@ impl QueryProcessor {
             ))
         })?;
 
-        let response = self
-            .graph_node
-            .subgraph_query_raw(&subgraph_deployment_id.ipfs_hash(), query.clone())
-            .await?;
+        let response = self.subgraph_query(&subgraph_deployment_id, query).await?;
 
-        let attestation_signature = response
-            .attestable
-            .then(|| Self::create_attestation(signer, query, &response));
+        let attestation_signature = (healthy && response.attestable)
+            .then(|| Self::create_attestation(signer, attested_request, &response));
 
         Ok(Response {
             result: QueryResult {
@@ -141,6 +247,289 @@ impl QueryProcessor {
         })
     }
 
+    pub async fn execute_free_batch(
+        &self,
+        batch: FreeBatch,
+    ) -> Result<Response<Vec<UnattestedQueryResult>>, QueryError> {
+        let responses = self
+            .subgraph_queries(&batch.subgraph_deployment_id, batch.queries)
+            .await?;
+
+        Ok(Response {
+            result: responses,
+            status: 200,
+        })
+    }
+
+    pub async fn execute_paid_batch(
+        &self,
+        batch: PaidBatch,
+    ) -> Result<Response<Vec<QueryResult>>, QueryError> {
+        let _in_flight = InFlightGuard::new(&self.in_flight_paid_queries);
+
+        let PaidBatch {
+            subgraph_deployment_id,
+            queries,
+            receipt,
+        } = batch;
+
+        let healthy = self.deployment_healthy(&subgraph_deployment_id).await?;
+        let attested_requests = queries
+            .iter()
+            .map(|query| self.attested_request(query))
+            .collect::<Result<Vec<String>, QueryError>>()?;
+
+        // TODO: Emit IndexerErrorCode::IE031 on error
+        let parsed_receipts: Vec<SignedReceipt> = if receipt.trim_start().starts_with('[') {
+            serde_json::from_str(&receipt)
+        } else {
+            serde_json::from_str(&receipt).map(|receipt| vec![receipt])
+        }
+        .map_err(|e| QueryError::Other(anyhow::Error::from(e)))?;
+        if parsed_receipts.len() != 1 && parsed_receipts.len() != queries.len() {
+            return Err(QueryError::Other(anyhow::anyhow!(
+                "Expected a single receipt or one receipt per query for a batch of {} queries, got {}",
+                queries.len(),
+                parsed_receipts.len()
+            )));
+        }
+
+        let allocation_ids = parsed_receipts
+            .iter()
+            .map(|parsed_receipt| parsed_receipt.message.allocation_id)
+            .collect::<Vec<_>>();
+        self.verify_and_store_receipts(&subgraph_deployment_id, &queries, parsed_receipts)
+            .await?;
+
+        let signers = self.attestation_signers.read().await;
+        let batch_signers = allocation_ids
+            .iter()
+            .map(|allocation_id| {
+                signers.get(allocation_id).ok_or_else(|| {
+                    QueryError::Other(anyhow::anyhow!(
+                        "No signer found for allocation id {}",
+                        allocation_id
+                    ))
+                })
+            })
+            .collect::<Result<Vec<&AttestationSigner>, QueryError>>()?;
+
+        let responses = self
+            .subgraph_queries(&subgraph_deployment_id, queries)
+            .await?;
+
+        let results = responses
+            .into_iter()
+            .zip(attested_requests)
+            .enumerate()
+            .map(|(i, (response, attested_request))| {
+                // A single receipt covers the whole batch, so its allocation attests every query
+                let signer = batch_signers[if batch_signers.len() == 1 { 0 } else { i }];
+                let attestation = (healthy && response.attestable)
+                    .then(|| Self::create_attestation(signer, attested_request, &response));
+                QueryResult {
+                    graphql_response: response.graphql_response,
+                    attestation,
+                }
+            })
+            .collect();
+
+        Ok(Response {
+            result: results,
+            status: 200,
+        })
+    }
+
+    /// Checks that the receipts are distinct, valid and pay for the queries, then stores them.
+    /// No receipt is stored unless all of them pass. A single receipt pays for all the
+    /// queries, otherwise there is one receipt per query.
+    async fn verify_and_store_receipts(
+        &self,
+        subgraph_deployment_id: &SubgraphDeploymentID,
+        queries: &[String],
+        receipts: Vec<SignedReceipt>,
+    ) -> Result<(), QueryError> {
+        check_distinct_receipts(&receipts)?;
+        for receipt in &receipts {
+            self.tap_manager
+                .verify_receipt(subgraph_deployment_id, receipt)
+                .await?;
+        }
+        let costs = queries
+            .iter()
+            .map(|query| self.query_cost(subgraph_deployment_id, query))
+            .collect::<Result<Vec<u128>, QueryError>>()?;
+        check_receipt_values(&receipts, &costs)?;
+        self.tap_manager.store_receipts(receipts).await
+    }
+
+    /// Whether paid query responses on the deployment can be attested. Before the first sync of
+    /// indexing statuses, deployments are served and attested so that queries are not refused
+    /// right after starting. Afterwards, responses on deployments without a known health are
+    /// served without attestation.
+    async fn deployment_healthy(
+        &self,
+        subgraph_deployment_id: &SubgraphDeploymentID,
+    ) -> Result<bool, QueryError> {
+        match self
+            .indexing_status_monitor
+            .deployment_health(subgraph_deployment_id)
+            .await
+        {
+            // Deployments without a known status once statuses are synced may be failed or
+            // lagging, so their responses are served without attestation
+            None if self.indexing_status_monitor.has_synced().await => Ok(false),
+            Some(health) if !health.healthy => {
+                let reason = if health.health == SubgraphHealth::failed {
+                    format!(
+                        "failed with {}",
+                        health.fatal_error.as_deref().unwrap_or("an unknown error")
+                    )
+                } else {
+                    format!(
+                        "more than {} blocks behind chain head",
+                        health.max_block_lag
+                    )
+                };
+                if self.unhealthy_deployment_queries == UnhealthyDeploymentQueries::Reject {
+                    return Err(QueryError::UnhealthyDeployment(reason));
+                }
+                Ok(false)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// The request that the attestation of a paid query is made over
+    fn attested_request(&self, query: &str) -> Result<String, QueryError> {
+        match self.attestation_request_form {
+            AttestationRequestForm::Raw => Ok(query.to_string()),
+            AttestationRequestForm::Canonical => {
+                canonical_request(query).map_err(|e| QueryError::Other(anyhow::anyhow!(e)))
+            }
+        }
+    }
+
+    /// Cost in wei of a `{ query, variables }` request according to the cost model of the
+    /// deployment. Queries on deployments without a cost model are free.
+    fn query_cost(
+        &self,
+        subgraph_deployment_id: &SubgraphDeploymentID,
+        query: &str,
+    ) -> Result<u128, QueryError> {
+        match self
+            .cost_model_cache
+            .parsed_cost_model(&subgraph_deployment_id.ipfs_hash())
+        {
+            Some(Ok(model)) => request_cost(&model, query),
+            Some(Err(e)) => Err(QueryError::Other(anyhow::anyhow!(
+                "Invalid cost model: {}",
+                e
+            ))),
+            None => Ok(0),
+        }
+    }
+
+    /// Runs the queries of a batch concurrently, returning their responses in order
+    async fn subgraph_queries(
+        &self,
+        subgraph_deployment_id: &SubgraphDeploymentID,
+        queries: Vec<String>,
+    ) -> Result<Vec<UnattestedQueryResult>, QueryError> {
+        let handles = queries
+            .into_iter()
+            .map(|query| {
+                let processor = self.clone();
+                let subgraph_deployment_id = subgraph_deployment_id.clone();
+                tokio::spawn(async move {
+                    processor
+                        .subgraph_query(&subgraph_deployment_id, query)
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut responses = Vec::with_capacity(handles.len());
+        for handle in handles {
+            responses.push(
+                handle
+                    .await
+                    .map_err(|e| QueryError::Other(anyhow::Error::from(e)))??,
+            );
+        }
+        Ok(responses)
+    }
+
+    /// Queries graph-node, serving attestable responses from the query cache when it is enabled
+    async fn subgraph_query(
+        &self,
+        subgraph_deployment_id: &SubgraphDeploymentID,
+        query: String,
+    ) -> Result<UnattestedQueryResult, QueryError> {
+        let cache = self.query_cache.as_ref().and_then(|cache| {
+            CacheKey::new(subgraph_deployment_id, &query).map(|key| (cache, key))
+        });
+
+        let mut latest_blocks = None;
+        if let Some((cache, key)) = &cache {
+            latest_blocks = self.latest_blocks(subgraph_deployment_id).await;
+            let deployment_label = subgraph_deployment_id.ipfs_hash();
+            if let Some(graphql_response) = latest_blocks
+                .as_ref()
+                .and_then(|latest_blocks| cache.get(key, latest_blocks, Instant::now()))
+            {
+                metrics::QUERY_CACHE_HITS
+                    .with_label_values(&[&deployment_label])
+                    .inc();
+                return Ok(UnattestedQueryResult {
+                    graphql_response,
+                    attestable: true,
+                });
+            }
+            metrics::QUERY_CACHE_MISSES
+                .with_label_values(&[&deployment_label])
+                .inc();
+        }
+
+        let response = self
+            .graph_node
+            .subgraph_query_raw(&subgraph_deployment_id.ipfs_hash(), query)
+            .await?;
+
+        // Responses can only be invalidated if the deployment's latest blocks are known
+        if let (Some((cache, key)), Some(latest_blocks)) = (cache, latest_blocks) {
+            if response.attestable && !has_errors(&response.graphql_response) {
+                cache.insert(
+                    key,
+                    response.graphql_response.clone(),
+                    latest_blocks,
+                    Instant::now(),
+                );
+            }
+        }
+
+        Ok(response)
+    }
+
+    /// Latest block of every chain of a deployment, as last synced by the indexing status monitor
+    async fn latest_blocks(
+        &self,
+        subgraph_deployment_id: &SubgraphDeploymentID,
+    ) -> Option<Vec<u64>> {
+        let health = self
+            .indexing_status_monitor
+            .deployment_health(subgraph_deployment_id)
+            .await?;
+        if health.chains.is_empty() {
+            return None;
+        }
+        health
+            .chains
+            .iter()
+            .map(|chain| chain.latest_block)
+            .collect()
+    }
+
     fn create_attestation(
         signer: &AttestationSigner,
         query: String,
@@ -155,12 +544,64 @@ impl QueryProcessor {
     }
 }
 
+fn has_errors(graphql_response: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(graphql_response)
+        .map_or(true, |response| response.get("errors").is_some())
+}
+
+/// Cost in wei of a `{ query, variables }` request according to a cost model
+fn request_cost(model: &Model, query: &str) -> Result<u128, QueryError> {
+    let request: GraphQLQuery =
+        serde_json::from_str(query).map_err(|e| QueryError::Other(e.into()))?;
+    model.cost(&request).map_err(QueryError::UnpricedQuery)
+}
+
+/// Rejects receipts sent more than once, by nonce or signature, so that a single receipt
+/// does not pay for several queries of a batch
+fn check_distinct_receipts(receipts: &[SignedReceipt]) -> Result<(), QueryError> {
+    let mut nonces = HashSet::new();
+    let mut signatures = HashSet::new();
+    for receipt in receipts {
+        if !nonces.insert(receipt.message.nonce) || !signatures.insert(receipt.signature) {
+            return Err(QueryError::DuplicateReceipt(receipt.message.nonce));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a single receipt covers the total cost of the queries, or that each receipt
+/// covers the cost of its own query
+fn check_receipt_values(receipts: &[SignedReceipt], costs: &[u128]) -> Result<(), QueryError> {
+    let check = |receipt: &SignedReceipt, cost: u128| {
+        if receipt.message.value < cost {
+            return Err(QueryError::InsufficientReceiptValue(
+                receipt.message.value,
+                cost,
+            ));
+        }
+        Ok(())
+    };
+
+    if let [receipt] = receipts {
+        let total = costs
+            .iter()
+            .fold(0u128, |total, cost| total.saturating_add(*cost));
+        check(receipt, total)
+    } else {
+        receipts
+            .iter()
+            .zip(costs)
+            .try_for_each(|(receipt, cost)| check(receipt, *cost))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
     use alloy_primitives::Address;
     use hex_literal::hex;
+    use sha3::{Digest, Keccak256};
 
     use crate::{
         common::allocation::{allocation_signer, Allocation, AllocationStatus, SubgraphDeployment},
@@ -230,7 +671,29 @@ mod tests {
     }
 
     #[test]
-    fn paid_query_attestation() {
+    fn uncacheable_responses() {
+        assert!(!has_errors(r#"{"data": {"a": 1}}"#));
+        assert!(has_errors(
+            r#"{"data": null, "errors": [{"message": "Store error"}]}"#
+        ));
+        assert!(has_errors("Internal server error"));
+    }
+
+    #[test]
+    fn in_flight_guard() {
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        let first = InFlightGuard::new(&counter);
+        let second = InFlightGuard::new(&counter);
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+
+        drop(first);
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        drop(second);
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+    }
+
+    fn attestation_signer() -> AttestationSigner {
         let subgraph_deployment_id = SubgraphDeploymentID::new(
             "0xc064c354bc21dd958b1d41b67b8ef161b75d2246b425f68ed4c74964ae705cbd",
         )
@@ -262,13 +725,18 @@ mod tests {
 
         let allocation_key = allocation_signer(INDEXER_OPERATOR_MNEMONIC, allocation).unwrap();
 
-        let attestation_signer = create_attestation_signer(
+        create_attestation_signer(
             U256::from(1),
             Address::from_str("0xdeadbeefcafebabedeadbeefcafebabedeadbeef").unwrap(),
             allocation_key,
             subgraph_deployment_id.bytes32(),
         )
-        .unwrap();
+        .unwrap()
+    }
+
+    #[test]
+    fn paid_query_attestation() {
+        let attestation_signer = attestation_signer();
 
         let attestation = QueryProcessor::create_attestation(
             &attestation_signer,
@@ -288,4 +756,121 @@ mod tests {
 
         assert_eq!(attestation, expected_signature);
     }
+
+    #[test]
+    fn canonical_query_attestation() {
+        let attestation_signer = attestation_signer();
+        let response = UnattestedQueryResult {
+            graphql_response: r#"{"data":{"tokens":[]}}"#.to_string(),
+            attestable: true,
+        };
+        let attest = |request: &str| {
+            QueryProcessor::create_attestation(&attestation_signer, request.to_string(), &response)
+        };
+
+        let compact = r#"{"query": "{ tokens(first: 10) { id } }", "variables": {"b": 1, "a": 2}}"#;
+        let formatted = r#"{"query": "query {\n  tokens(first: 10) {\n    id\n  }\n}", "variables": {"a": 2, "b": 1}}"#;
+
+        // Both requests have the same canonical form, and so the same request CID
+        let expected_canonical = r#"{"query":"query {\n  tokens(first: 10) {\n    id\n  }\n}\n","variables":{"a":2,"b":1}}"#;
+        assert_eq!(canonical_request(compact).unwrap(), expected_canonical);
+        assert_eq!(canonical_request(formatted).unwrap(), expected_canonical);
+        assert_eq!(
+            Keccak256::digest(expected_canonical.as_bytes()).as_slice(),
+            hex!("858933bb9917a6fdc497319ba416bf487e4173418741c041c35a29bfb950c7c9")
+        );
+
+        // Pinned from this implementation, which produces the `paid_query_attestation` values
+        // above, so that changes to the canonical form or its signing are noticed
+        let expected_canonical_signature = Signature {
+            v: 28,
+            r: hex!("bfda6c303c2f7f9c2aaf8baddec283969afa258f0f21de52444dec3addcf29ee").into(),
+            s: hex!("2cf1abf8c6e0032516e3fba5babd8effb7abefe79c390702b9d19eb35284d9c2").into(),
+        };
+        assert_eq!(
+            attest(&canonical_request(compact).unwrap()),
+            expected_canonical_signature
+        );
+        assert_eq!(
+            attest(&canonical_request(formatted).unwrap()),
+            expected_canonical_signature
+        );
+
+        // Raw attestations differ as soon as the request bytes do
+        assert_eq!(
+            attest(compact),
+            Signature {
+                v: 27,
+                r: hex!("7980b66d19a5409cdcfd3896c632cfff511b5221aaac3dff338ed6f47c683de2").into(),
+                s: hex!("0ccaafd3ea28a10c55da90c837922c6ef27a0f2278f66b4085a0c6f1c008f749").into(),
+            }
+        );
+        assert_eq!(
+            attest(formatted),
+            Signature {
+                v: 28,
+                r: hex!("12984120d60d1c5fd7200f702bad8e13484c28453358f37d8b48247477957f2c").into(),
+                s: hex!("24d1a468557c448d8e58db9f6959d59a9579d18f87008ed7ce9c148f62cf76fb").into(),
+            }
+        );
+    }
+
+    #[test]
+    fn prices_requests_with_the_cost_model() {
+        let model = Model::parse("query { a } => 0.5;", None).unwrap();
+        assert_eq!(
+            request_cost(&model, r#"{"query": "{ a }"}"#).unwrap(),
+            500_000_000_000_000_000
+        );
+        match request_cost(&model, r#"{"query": "{ b }"}"#) {
+            Err(QueryError::UnpricedQuery(_)) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn receipts_must_pay_for_their_queries() {
+        let allocation_id =
+            Address::from_str("0xdeadbeefcafebabedeadbeefcafebabedeadbeef").unwrap();
+        let receipt = |nonce: u64, value: u128| {
+            crate::tap_manager::test::create_signed_receipt(allocation_id, nonce, nonce, value)
+        };
+
+        // A single receipt pays for the whole batch
+        let single = vec![receipt(1, 30).await];
+        assert!(check_receipt_values(&single, &[10, 20]).is_ok());
+        match check_receipt_values(&single, &[10, 21]) {
+            Err(QueryError::InsufficientReceiptValue(30, 31)) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        // Otherwise each receipt pays for its own query
+        let per_query = vec![receipt(1, 10).await, receipt(2, 20).await];
+        assert!(check_receipt_values(&per_query, &[10, 20]).is_ok());
+        match check_receipt_values(&per_query, &[20, 10]) {
+            Err(QueryError::InsufficientReceiptValue(10, 20)) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_receipts_must_be_distinct() {
+        let allocation_id =
+            Address::from_str("0xdeadbeefcafebabedeadbeefcafebabedeadbeef").unwrap();
+        let receipt = |nonce: u64, value: u128| {
+            crate::tap_manager::test::create_signed_receipt(allocation_id, nonce, nonce, value)
+        };
+
+        let first = receipt(1, 10).await;
+        assert!(check_distinct_receipts(&[first.clone(), receipt(2, 10).await]).is_ok());
+        match check_distinct_receipts(&[first.clone(), first.clone()]) {
+            Err(QueryError::DuplicateReceipt(1)) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        // Receipts reusing a nonce are duplicates even if they differ otherwise
+        match check_distinct_receipts(&[first, receipt(1, 20).await]) {
+            Err(QueryError::DuplicateReceipt(1)) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
 }
@@ -0,0 +1,112 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::net::SocketAddr;
+
+use axum::{extract::ConnectInfo, http::Extensions};
+use sha3::{Digest, Keccak256};
+use tracing::warn;
+
+use crate::metrics;
+
+/// Prefix of pre-hashed tokens in the config, followed by the hex encoded keccak256 hash
+/// of the token, e.g. as printed by `cast keccak <token>`
+const KECCAK256_PREFIX: &str = "keccak256:";
+
+/// An auth token, held only as its keccak256 hash and compared in constant time
+#[derive(Clone)]
+pub struct AuthToken {
+    hash: [u8; 32],
+}
+
+impl AuthToken {
+    /// Parses a token from the config, either in plaintext or pre-hashed as `keccak256:<hash>`
+    pub fn new(config_value: &str) -> Result<AuthToken, String> {
+        let hash = match config_value.strip_prefix(KECCAK256_PREFIX) {
+            Some(hash) => {
+                let hash = hex::decode(hash.trim_start_matches("0x"))
+                    .map_err(|e| format!("Invalid token hash: {}", e))?;
+                hash.try_into()
+                    .map_err(|_| "Invalid token hash: expected 32 bytes".to_string())?
+            }
+            None => keccak256(config_value),
+        };
+        Ok(AuthToken { hash })
+    }
+
+    pub fn matches(&self, token: &str) -> bool {
+        constant_time_eq(&keccak256(token), &self.hash)
+    }
+
+    /// Prefix of the token hash, identifying the token in records without revealing it
+    pub fn fingerprint(&self) -> String {
+        hex::encode(&self.hash[..4])
+    }
+}
+
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthToken(..)")
+    }
+}
+
+fn keccak256(token: &str) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&Keccak256::digest(token.as_bytes()));
+    hash
+}
+
+/// Compares every byte regardless of where the first difference is
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    let difference = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    std::hint::black_box(difference) == 0
+}
+
+/// Address of the client that sent a request, if the server tracks connection info
+pub fn client_address(extensions: &Extensions) -> Option<SocketAddr> {
+    extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| *address)
+}
+
+/// Counts and logs a request with missing or invalid credentials
+pub fn record_failed_auth(route: &'static str, client: Option<SocketAddr>) {
+    metrics::FAILED_AUTH.with_label_values(&[route]).inc();
+    warn!(
+        "Failed authentication on /{} from {}",
+        route,
+        client.map_or_else(
+            || "unknown address".to_string(),
+            |client| client.ip().to_string()
+        )
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plaintext_and_hashed_tokens() {
+        let plaintext = AuthToken::new("hello").unwrap();
+        let hashed = AuthToken::new(
+            "keccak256:0x1c8aff950685c2ed4bc3174f3472287b56d9517b9c948127319a09a7a36deac8",
+        )
+        .unwrap();
+
+        for token in [plaintext, hashed] {
+            assert!(token.matches("hello"));
+            assert!(!token.matches("hello "));
+            assert!(!token.matches(""));
+        }
+
+        assert!(AuthToken::new("keccak256:0x1234").is_err());
+        assert!(AuthToken::new("keccak256:not-hex").is_err());
+        assert_eq!(
+            format!("{:?}", AuthToken::new("hello").unwrap()),
+            "AuthToken(..)"
+        );
+        assert_eq!(AuthToken::new("hello").unwrap().fingerprint(), "1c8aff95");
+    }
+}
@@ -1,46 +1,82 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use async_graphql::{EmptyMutation, EmptySubscription, Schema};
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
 use axum::{
     error_handling::HandleErrorLayer,
-    handler::Handler,
     http::{Method, StatusCode},
     routing::get,
 };
 use axum::{routing::post, Extension, Router};
 
-use std::time::Duration;
-use tower::{BoxError, ServiceBuilder};
+use tower::{
+    layer::util::{Identity, Stack},
+    BoxError, ServiceBuilder,
+};
 use tower_http::{
-    add_extension::AddExtensionLayer,
     cors::CorsLayer,
     trace::{self, TraceLayer},
 };
 use tracing::Level;
 
 use crate::{
-    common::indexer_management_client::{IndexerManagementClient, QueryRoot},
+    allocation_monitor::AllocationMonitor,
+    api_keys::ApiKeys,
+    attestation_signers::AttestationSigners,
+    common::indexer_management_client::{schema::CostSchema, IndexerManagementClient},
     common::network_subgraph::NetworkSubgraph,
+    common::types::SubgraphDeploymentID,
+    config::{QueryLimits, RateLimit, RateLimits, RequestLimit, RequestLimits},
+    cost_model_cache::CostModelCache,
+    escrow_monitor::EscrowMonitor,
     query_processor::QueryProcessor,
-    server::routes::{network_ratelimiter, slow_ratelimiter},
+    server::{
+        auth::AuthToken, rate_limit::RateLimitLayer, request_limits::RequestLimitsLayer,
+        subscriptions::SubscriptionProxy,
+    },
     util::PackageVersion,
 };
 
+pub mod auth;
+pub mod rate_limit;
+pub mod request_limits;
 pub mod routes;
+pub mod subscriptions;
+
+/// Timeout of the requests made to the index node status endpoint while handling a request
+const STATUS_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Clone)]
 pub struct ServerOptions {
     pub port: Option<u32>,
     pub release: PackageVersion,
     pub query_processor: QueryProcessor,
-    pub free_query_auth_token: Option<String>,
+    pub free_query_auth_token: Option<AuthToken>,
+    pub api_keys: ApiKeys,
+    pub free_query_require_allocation: bool,
+    pub free_query_allowed_deployments: Vec<SubgraphDeploymentID>,
+    pub query_limits: QueryLimits,
     pub graph_node_status_endpoint: String,
+    /// Client for requests to the status endpoint, shared so that connections are reused
+    pub status_client: reqwest::Client,
+    pub status_allowed_root_fields: Vec<String>,
+    pub deployment_max_block_lag: u64,
     pub indexer_management_client: IndexerManagementClient,
+    pub cost_model_cache: CostModelCache,
     pub operator_public_key: String,
+    pub operator_auth_token: Option<AuthToken>,
     pub network_subgraph: NetworkSubgraph,
-    pub network_subgraph_auth_token: Option<String>,
+    pub network_subgraph_auth_token: Option<AuthToken>,
     pub serve_network_subgraph: bool,
+    pub allocation_monitor: AllocationMonitor,
+    pub escrow_monitor: EscrowMonitor,
+    pub attestation_signers: AttestationSigners,
+    pub subscription_proxy: Option<SubscriptionProxy>,
+    /// Deployments whose queries have been turned off by the operator until re-enabled
+    pub disabled_deployments: Arc<RwLock<HashSet<SubgraphDeploymentID>>>,
 }
 
 impl ServerOptions {
@@ -49,91 +85,204 @@ impl ServerOptions {
         port: Option<u32>,
         release: PackageVersion,
         query_processor: QueryProcessor,
-        free_query_auth_token: Option<String>,
+        free_query_auth_token: Option<AuthToken>,
+        api_keys: ApiKeys,
+        free_query_require_allocation: bool,
+        free_query_allowed_deployments: Vec<SubgraphDeploymentID>,
+        query_limits: QueryLimits,
         graph_node_status_endpoint: String,
+        status_allowed_root_fields: Vec<String>,
+        deployment_max_block_lag: u64,
         indexer_management_client: IndexerManagementClient,
+        cost_model_cache: CostModelCache,
         operator_public_key: String,
+        operator_auth_token: Option<AuthToken>,
         network_subgraph: NetworkSubgraph,
-        network_subgraph_auth_token: Option<String>,
+        network_subgraph_auth_token: Option<AuthToken>,
         serve_network_subgraph: bool,
+        allocation_monitor: AllocationMonitor,
+        escrow_monitor: EscrowMonitor,
+        attestation_signers: AttestationSigners,
+        subscription_proxy: Option<SubscriptionProxy>,
     ) -> Self {
-        let free_query_auth_token = free_query_auth_token.map(|token| format!("Bearer {}", token));
-
         ServerOptions {
             port,
             release,
             query_processor,
             free_query_auth_token,
+            api_keys,
+            free_query_require_allocation,
+            free_query_allowed_deployments,
+            query_limits,
             graph_node_status_endpoint,
+            status_client: reqwest::Client::builder()
+                .timeout(STATUS_REQUEST_TIMEOUT)
+                .build()
+                .expect("Could not build a HTTP client"),
+            status_allowed_root_fields,
+            deployment_max_block_lag,
             indexer_management_client,
+            cost_model_cache,
             operator_public_key,
+            operator_auth_token,
             network_subgraph,
             network_subgraph_auth_token,
             serve_network_subgraph,
+            allocation_monitor,
+            escrow_monitor,
+            attestation_signers,
+            subscription_proxy,
+            disabled_deployments: Arc::new(RwLock::new(HashSet::new())),
         }
     }
+
+    /// Whether queries on the deployment are served, i.e. it has not been disabled by the operator
+    pub fn is_serving(&self, deployment: &SubgraphDeploymentID) -> bool {
+        !self
+            .disabled_deployments
+            .read()
+            .unwrap()
+            .contains(deployment)
+    }
+}
+
+/// Request limits and rate limit of a route. The rate limit is checked first, so that
+/// rejected requests are never buffered.
+fn route_limits(
+    route: &'static str,
+    request_limit: &RequestLimit,
+    rate_limit: Option<&RateLimit>,
+) -> ServiceBuilder<Stack<RequestLimitsLayer, Stack<RateLimitLayer, Identity>>> {
+    shared_route_limits(
+        route,
+        request_limit,
+        &RateLimitLayer::new(route, rate_limit),
+    )
+}
+
+/// Request limits of a route sharing its rate limit with other routes, so that requests are
+/// counted together whichever of the routes they are sent to
+fn shared_route_limits(
+    route: &'static str,
+    request_limit: &RequestLimit,
+    rate_limit: &RateLimitLayer,
+) -> ServiceBuilder<Stack<RequestLimitsLayer, Stack<RateLimitLayer, Identity>>> {
+    ServiceBuilder::new()
+        .layer(rate_limit.clone())
+        .layer(RequestLimitsLayer::new(route, request_limit))
 }
 
 pub async fn create_server(
     options: ServerOptions,
-    schema: Schema<QueryRoot, EmptyMutation, EmptySubscription>,
+    schema: CostSchema,
+    rate_limits: &RateLimits,
+    request_limits: &RequestLimits,
 ) -> Router {
+    let basic_limits = || route_limits("default", &request_limits.default, None);
+    let deployment_health_rate_limit =
+        RateLimitLayer::new("deployment_health", rate_limits.deployment_health.as_ref());
+    let subgraphs_rate_limit = RateLimitLayer::new("subgraphs", rate_limits.subgraphs.as_ref());
+
     Router::new()
-        .route("/", get(routes::basic::index))
-        .route("/health", get(routes::basic::health))
-        .route("/version", get(routes::basic::version))
+        .route("/", get(routes::basic::index).layer(basic_limits()))
+        .route("/health", get(routes::basic::health).layer(basic_limits()))
+        .route(
+            "/health/live",
+            get(routes::basic::health).layer(basic_limits()),
+        )
+        .route(
+            "/health/ready",
+            get(routes::basic::readiness).layer(basic_limits()),
+        )
+        .route(
+            "/version",
+            get(routes::basic::version).layer(basic_limits()),
+        )
         .route(
             "/status",
-            post(routes::status::status_queries)
-                .layer(AddExtensionLayer::new(network_ratelimiter())),
+            post(routes::status::status_queries).layer(route_limits(
+                "status",
+                &request_limits.status,
+                rate_limits.status.as_ref(),
+            )),
+        )
+        .route(
+            "/subgraphs/health",
+            get(routes::deployment::deployments_health).layer(shared_route_limits(
+                "deployment_health",
+                &request_limits.deployment_health,
+                &deployment_health_rate_limit,
+            )),
         )
         .route(
             "/subgraphs/health/:deployment",
-            get(routes::deployment::deployment_health
-                .layer(AddExtensionLayer::new(slow_ratelimiter()))),
+            get(routes::deployment::deployment_health).layer(shared_route_limits(
+                "deployment_health",
+                &request_limits.deployment_health,
+                &deployment_health_rate_limit,
+            )),
         )
         .route(
             "/cost",
             post(routes::cost::graphql_handler)
                 .get(routes::cost::graphql_handler)
-                .layer(AddExtensionLayer::new(slow_ratelimiter())),
+                .layer(route_limits(
+                    "cost",
+                    &request_limits.cost,
+                    rate_limits.cost.as_ref(),
+                )),
         )
         .nest(
             "/operator",
-            routes::basic::create_operator_server(options.clone())
-                .layer(AddExtensionLayer::new(slow_ratelimiter())),
+            routes::basic::create_operator_server(options.clone()).layer(route_limits(
+                "operator",
+                &request_limits.operator,
+                rate_limits.operator.as_ref(),
+            )),
         )
         .route(
             "/network",
-            post(routes::network::network_queries)
-                .layer(AddExtensionLayer::new(network_ratelimiter())),
+            post(routes::network::network_queries).layer(route_limits(
+                "network",
+                &request_limits.network,
+                rate_limits.network.as_ref(),
+            )),
         )
         .route(
             "/subgraphs/id/:id",
-            post(routes::subgraphs::subgraph_queries),
+            post(routes::subgraphs::subgraph_queries)
+                .get(routes::subgraphs::subgraph_subscriptions)
+                .layer(shared_route_limits(
+                    "subgraphs",
+                    &request_limits.subgraphs,
+                    &subgraphs_rate_limit,
+                )),
+        )
+        .route(
+            "/subgraphs/name/*name",
+            post(routes::subgraphs::subgraph_name_queries).layer(shared_route_limits(
+                "subgraphs",
+                &request_limits.subgraphs,
+                &subgraphs_rate_limit,
+            )),
         )
         .layer(Extension(schema))
         .layer(Extension(options.clone()))
         .layer(CorsLayer::new().allow_methods([Method::GET, Method::POST]))
         .layer(
-            // Handle error for timeout, ratelimit, or a general internal server error
+            // Handle a general internal server error, timeouts and rate limits are handled per route
             ServiceBuilder::new()
                 .layer(HandleErrorLayer::new(|error: BoxError| async move {
-                    if error.is::<tower::timeout::error::Elapsed>() {
-                        Ok(StatusCode::REQUEST_TIMEOUT)
-                    } else {
-                        Err((
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            format!("Unhandled internal error: {}", error),
-                        ))
-                    }
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Unhandled internal error: {}", error),
+                    )
                 }))
                 .layer(
                     TraceLayer::new_for_http()
                         .make_span_with(trace::DefaultMakeSpan::new().level(Level::DEBUG))
                         .on_response(trace::DefaultOnResponse::new().level(Level::DEBUG)),
                 )
-                .timeout(Duration::from_secs(10))
                 .into_inner(),
         )
 }
@@ -0,0 +1,415 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, Request, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use tower::{Layer, Service};
+
+use crate::{
+    api_keys::ApiKeys,
+    config::{RateLimit, RateLimitKey},
+    metrics,
+    server::{auth::AuthToken, ServerOptions},
+};
+
+#[derive(Debug)]
+struct Window {
+    started_at: Instant,
+    count: u64,
+}
+
+#[derive(Debug)]
+struct Windows {
+    by_key: HashMap<String, Window>,
+    pruned_at: Instant,
+}
+
+/// Fixed window rate limiter counting requests separately per key
+#[derive(Debug)]
+pub struct KeyedRateLimiter {
+    route: &'static str,
+    limit: u64,
+    period: Duration,
+    keys: Vec<RateLimitKey>,
+    windows: Mutex<Windows>,
+}
+
+impl KeyedRateLimiter {
+    pub fn new(route: &'static str, config: &RateLimit) -> Self {
+        KeyedRateLimiter {
+            route,
+            limit: config.limit,
+            period: Duration::from_millis(config.period_ms),
+            keys: config.keys.clone(),
+            windows: Mutex::new(Windows {
+                by_key: HashMap::new(),
+                pruned_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Counts a request for `key`. Returns the time until the key's window resets if
+    /// the request exceeds the limit.
+    pub fn check(&self, key: String, now: Instant) -> Result<(), Duration> {
+        let mut windows = self.windows.lock().unwrap();
+
+        // Expired windows are pruned once per period, so that pruning stays cheap however
+        // many keys are tracked
+        if now.saturating_duration_since(windows.pruned_at) >= self.period {
+            let period = self.period;
+            windows
+                .by_key
+                .retain(|_, w| now.saturating_duration_since(w.started_at) < period);
+            windows.pruned_at = now;
+        }
+
+        let window = windows.by_key.entry(key).or_insert(Window {
+            started_at: now,
+            count: 0,
+        });
+        let elapsed = now.saturating_duration_since(window.started_at);
+        if elapsed >= self.period {
+            window.started_at = now;
+            window.count = 0;
+        }
+
+        if window.count >= self.limit {
+            return Err(self.period - now.saturating_duration_since(window.started_at));
+        }
+        window.count += 1;
+        Ok(())
+    }
+
+    /// Builds the key a request is counted under from the configured key components.
+    /// Components missing from the request fall back to the client IP, so that requests
+    /// without them are not all counted together.
+    async fn request_key(&self, req: &Request<Body>) -> String {
+        let client_ip = || {
+            req.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| format!("ip:{}", addr.ip()))
+                .unwrap_or_default()
+        };
+
+        let mut components = Vec::with_capacity(self.keys.len());
+        for key in &self.keys {
+            let component = match key {
+                RateLimitKey::ClientIp => None,
+                // Made up tokens would each get their own window, so only known tokens are
+                // counted by token
+                RateLimitKey::AuthToken => {
+                    match (
+                        req.headers()
+                            .get(header::AUTHORIZATION)
+                            .and_then(|t| t.to_str().ok()),
+                        req.extensions().get::<ServerOptions>(),
+                    ) {
+                        (Some(token), Some(server))
+                            if known_auth_token(
+                                server.free_query_auth_token.as_ref(),
+                                &server.api_keys,
+                                token,
+                            ) =>
+                        {
+                            Some(format!("token:{}", token))
+                        }
+                        _ => None,
+                    }
+                }
+                // Anyone can sign a receipt with a new key, so only eligible senders are
+                // counted by sender
+                RateLimitKey::ReceiptSender => {
+                    match (
+                        req.headers()
+                            .get("scalar-receipt")
+                            .and_then(|r| r.to_str().ok()),
+                        req.extensions().get::<ServerOptions>(),
+                    ) {
+                        (Some(receipt), Some(server)) => server
+                            .query_processor
+                            .eligible_receipt_sender(receipt)
+                            .await
+                            .map(|sender| format!("sender:{}", sender)),
+                        _ => None,
+                    }
+                }
+                RateLimitKey::Deployment => deployment_from_path(req.uri().path())
+                    .map(|deployment| format!("deployment:{}", deployment)),
+            };
+            components.push(component.unwrap_or_else(client_ip));
+        }
+        components.join("|")
+    }
+}
+
+/// Whether the `Authorization` header value is the free query auth token or an API key
+fn known_auth_token(
+    free_query_auth_token: Option<&AuthToken>,
+    api_keys: &ApiKeys,
+    auth_token: &str,
+) -> bool {
+    let free = match (auth_token.strip_prefix("Bearer "), free_query_auth_token) {
+        (Some(auth_token), Some(expected)) => expected.matches(auth_token),
+        _ => false,
+    };
+    free || api_keys.contains(auth_token)
+}
+
+/// Extracts the deployment from the paths of deployment specific routes
+fn deployment_from_path(path: &str) -> Option<&str> {
+    ["/subgraphs/id/", "/subgraphs/health/"]
+        .iter()
+        .find_map(|prefix| path.strip_prefix(prefix))
+        .map(|rest| rest.trim_end_matches('/'))
+}
+
+fn too_many_requests_response(retry_after: Duration) -> Response {
+    // Round up so clients never retry before the window has reset
+    let retry_after_secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after_secs.max(1).to_string())],
+        Json("Too many requests, please retry later".to_string()),
+    )
+        .into_response()
+}
+
+/// Layer rejecting requests above a `KeyedRateLimiter`'s limit with 429.
+/// Requests pass through unchecked if the route has no rate limit configured, or a
+/// limit of 0.
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    limiter: Option<Arc<KeyedRateLimiter>>,
+}
+
+impl RateLimitLayer {
+    pub fn new(route: &'static str, config: Option<&RateLimit>) -> Self {
+        RateLimitLayer {
+            limiter: config
+                .filter(|config| config.limit > 0)
+                .map(|config| Arc::new(KeyedRateLimiter::new(route, config))),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    limiter: Option<Arc<KeyedRateLimiter>>,
+}
+
+impl<S> Service<Request<Body>> for RateLimitService<S>
+where
+    S: Service<Request<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Response: IntoResponse,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let limiter = self.limiter.clone();
+        // The service driven to readiness is the one to call, leave a fresh clone in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            if let Some(limiter) = limiter {
+                let key = limiter.request_key(&req).await;
+                if let Err(retry_after) = limiter.check(key, Instant::now()) {
+                    metrics::RATE_LIMITED_REQUESTS
+                        .with_label_values(&[limiter.route])
+                        .inc();
+                    return Ok(too_many_requests_response(retry_after));
+                }
+            }
+
+            inner.call(req).await.map(IntoResponse::into_response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(limit: u64) -> KeyedRateLimiter {
+        limiter_with_keys(limit, vec![RateLimitKey::ClientIp])
+    }
+
+    fn limiter_with_keys(limit: u64, keys: Vec<RateLimitKey>) -> KeyedRateLimiter {
+        KeyedRateLimiter::new(
+            "test",
+            &RateLimit {
+                limit,
+                period_ms: 1000,
+                keys,
+            },
+        )
+    }
+
+    fn request(path: &str, ip: [u8; 4], token: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().uri(path);
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, token);
+        }
+        let mut req = builder.body(Body::empty()).unwrap();
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((ip, 7600))));
+        req
+    }
+
+    #[test]
+    fn limits_each_key_separately() {
+        let limiter = limiter(2);
+        let now = Instant::now();
+
+        assert!(limiter.check("a".to_string(), now).is_ok());
+        assert!(limiter.check("a".to_string(), now).is_ok());
+        assert!(limiter.check("a".to_string(), now).is_err());
+        assert!(limiter.check("b".to_string(), now).is_ok());
+    }
+
+    #[test]
+    fn resets_after_period() {
+        let limiter = limiter(1);
+        let now = Instant::now();
+
+        assert!(limiter.check("a".to_string(), now).is_ok());
+        let retry_after = limiter
+            .check("a".to_string(), now + Duration::from_millis(400))
+            .unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(600));
+        assert!(limiter
+            .check("a".to_string(), now + Duration::from_millis(1000))
+            .is_ok());
+    }
+
+    #[test]
+    fn prunes_expired_windows_once_per_period() {
+        let limiter = limiter(1);
+        let now = Instant::now();
+
+        assert!(limiter.check("a".to_string(), now).is_ok());
+        assert!(limiter
+            .check("b".to_string(), now + Duration::from_millis(600))
+            .is_ok());
+        assert!(limiter
+            .check("c".to_string(), now + Duration::from_millis(1200))
+            .is_ok());
+        let windows = limiter.windows.lock().unwrap();
+        let mut keys = windows.by_key.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["b".to_string(), "c".to_string()]);
+    }
+
+    #[test]
+    fn zero_limit_disables_rate_limit() {
+        let config = RateLimit {
+            limit: 0,
+            period_ms: 1000,
+            keys: vec![RateLimitKey::ClientIp],
+        };
+        assert!(RateLimitLayer::new("test", Some(&config)).limiter.is_none());
+        assert!(RateLimitLayer::new("test", None).limiter.is_none());
+    }
+
+    #[tokio::test]
+    async fn missing_key_components_fall_back_to_client_ip() {
+        let limiter = limiter_with_keys(1, vec![RateLimitKey::AuthToken, RateLimitKey::Deployment]);
+
+        // Tokens that cannot be checked against the known ones are not trusted as keys
+        assert_eq!(
+            limiter
+                .request_key(&request(
+                    "/subgraphs/id/QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ",
+                    [1, 2, 3, 4],
+                    Some("Bearer token")
+                ))
+                .await,
+            "ip:1.2.3.4|deployment:QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ"
+        );
+        assert_eq!(
+            limiter
+                .request_key(&request("/status", [1, 2, 3, 4], None))
+                .await,
+            "ip:1.2.3.4|ip:1.2.3.4"
+        );
+        assert_ne!(
+            limiter
+                .request_key(&request("/status", [1, 2, 3, 4], None))
+                .await,
+            limiter
+                .request_key(&request("/status", [5, 6, 7, 8], None))
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn only_known_tokens_are_keys() {
+        let free_query_auth_token = AuthToken::new("free").unwrap();
+        let api_keys = ApiKeys::new(
+            sqlx::PgPool::connect_lazy("postgres://localhost/unused").unwrap(),
+            60_000,
+        );
+
+        assert!(known_auth_token(
+            Some(&free_query_auth_token),
+            &api_keys,
+            "Bearer free"
+        ));
+        assert!(!known_auth_token(
+            Some(&free_query_auth_token),
+            &api_keys,
+            "free"
+        ));
+        assert!(!known_auth_token(
+            Some(&free_query_auth_token),
+            &api_keys,
+            "Bearer made-up"
+        ));
+        assert!(!known_auth_token(None, &api_keys, "Bearer free"));
+        api_keys.stop();
+    }
+
+    #[test]
+    fn deployment_path() {
+        assert_eq!(
+            deployment_from_path("/subgraphs/id/QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ"),
+            Some("QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ")
+        );
+        assert_eq!(deployment_from_path("/status"), None);
+    }
+}
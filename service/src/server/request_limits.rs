@@ -0,0 +1,188 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::body::HttpBody;
+use tower::{Layer, Service};
+use tracing::warn;
+
+use crate::{
+    common::indexer_error::{IndexerError, IndexerErrorCause, IndexerErrorCode},
+    config::RequestLimit,
+    metrics,
+};
+
+/// Layer enforcing a route's request timeout and maximum request body size.
+///
+/// Requests with a body over the limit are rejected with 413, requests taking longer
+/// than the timeout are aborted with 408.
+#[derive(Debug, Clone)]
+pub struct RequestLimitsLayer {
+    route: &'static str,
+    timeout: Duration,
+    max_body_size: u64,
+}
+
+impl RequestLimitsLayer {
+    pub fn new(route: &'static str, config: &RequestLimit) -> Self {
+        RequestLimitsLayer {
+            route,
+            timeout: Duration::from_millis(config.timeout_ms),
+            max_body_size: config.max_body_size,
+        }
+    }
+}
+
+impl<S> Layer<S> for RequestLimitsLayer {
+    type Service = RequestLimitsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestLimitsService {
+            inner,
+            limits: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestLimitsService<S> {
+    inner: S,
+    limits: RequestLimitsLayer,
+}
+
+impl<S> Service<Request<Body>> for RequestLimitsService<S>
+where
+    S: Service<Request<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Response: IntoResponse,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        // Take the service that was driven to readiness, leaving a clone in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limits = self.limits.clone();
+
+        Box::pin(async move {
+            let request = async {
+                let req = match limit_body(req, limits.max_body_size).await {
+                    Ok(req) => req,
+                    Err(response) => return Ok(response),
+                };
+                inner.call(req).await.map(IntoResponse::into_response)
+            };
+
+            match tokio::time::timeout(limits.timeout, request).await {
+                Ok(response) => response,
+                Err(_) => {
+                    let error = IndexerError::new(
+                        IndexerErrorCode::IE077,
+                        Some(IndexerErrorCause::new(format!(
+                            "{} request exceeded timeout of {:?}",
+                            limits.route, limits.timeout
+                        ))),
+                    );
+                    warn!("{}", error);
+                    metrics::REQUEST_TIMEOUTS
+                        .with_label_values(&[limits.route])
+                        .inc();
+                    metrics::INDEXER_ERROR
+                        .with_label_values(&[&error.code().to_string()])
+                        .inc();
+                    Ok((StatusCode::REQUEST_TIMEOUT, Json(error.to_string())).into_response())
+                }
+            }
+        })
+    }
+}
+
+/// Buffers the request body, rejecting it as soon as it grows over `max_body_size`
+async fn limit_body(req: Request<Body>, max_body_size: u64) -> Result<Request<Body>, Response> {
+    let declared_size = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| l.parse::<u64>().ok());
+    if declared_size.map_or(false, |size| size > max_body_size) {
+        return Err(payload_too_large_response(max_body_size));
+    }
+
+    let (parts, mut body) = req.into_parts();
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(
+                    IndexerError::new(IndexerErrorCode::IE075, Some(IndexerErrorCause::new(e)))
+                        .to_string(),
+                ),
+            )
+                .into_response()
+        })?;
+        if (buffer.len() + chunk.len()) as u64 > max_body_size {
+            return Err(payload_too_large_response(max_body_size));
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+
+    Ok(Request::from_parts(parts, Body::from(buffer)))
+}
+
+fn payload_too_large_response(max_body_size: u64) -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Json(format!(
+            "Request body exceeds the maximum size of {} bytes",
+            max_body_size
+        )),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_oversized_bodies() {
+        let req = Request::new(Body::from(vec![0u8; 11]));
+        let response = limit_body(req, 10).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let req = Request::builder()
+            .header(header::CONTENT_LENGTH, "1000")
+            .body(Body::empty())
+            .unwrap();
+        let response = limit_body(req, 10).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn keeps_bodies_within_limit() {
+        let req = Request::new(Body::from("{ query }"));
+        let req = limit_body(req, 10).await.unwrap();
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(body, "{ query }");
+    }
+}
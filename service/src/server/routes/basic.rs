@@ -1,24 +1,186 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use axum::{extract::Extension, routing::get, Router};
-use axum::{http::StatusCode, response::IntoResponse, Json};
+use std::collections::HashSet;
+use std::time::Duration;
+
+use alloy_primitives::Address;
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum::{
+    extract::{Extension, Path},
+    middleware::{self, Next},
+    routing::{delete, get, post, put},
+    Router,
+};
+use reqwest::header;
 use serde::Serialize;
 use serde_json::json;
 
-use crate::server::ServerOptions;
+use crate::{
+    api_keys::ApiKey,
+    common::types::SubgraphDeploymentID,
+    graph_node::RoutingTable,
+    server::{
+        auth::{client_address, record_failed_auth, AuthToken},
+        routes::{bad_request_response, internal_server_error_response},
+        ServerOptions,
+    },
+};
 
 #[derive(Serialize)]
 struct Health {
     healthy: bool,
 }
 
-/// Endpoint for server health
+/// Result of a single readiness check
+#[derive(Debug, Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn ok() -> Self {
+        Check {
+            ok: true,
+            error: None,
+        }
+    }
+
+    fn failed(error: impl ToString) -> Self {
+        Check {
+            ok: false,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Readiness {
+    ready: bool,
+    postgres: Check,
+    graph_node: Check,
+    allocation_monitor: Check,
+    escrow_monitor: Check,
+    attestation_signers: Check,
+}
+
+/// Endpoint for server health (liveness): the process is up and serving HTTP
 pub async fn health() -> impl IntoResponse {
     let health = Health { healthy: true };
     (StatusCode::OK, Json(health))
 }
 
+/// Endpoint for server readiness: all dependencies needed to serve paid queries are available
+pub async fn readiness(Extension(server): Extension<ServerOptions>) -> impl IntoResponse {
+    let (postgres, graph_node) = tokio::join!(
+        check_postgres(&server),
+        check_graph_node(&server.graph_node_status_endpoint)
+    );
+
+    let readiness = Readiness::new(
+        postgres,
+        graph_node,
+        server.allocation_monitor.has_synced(),
+        server.escrow_monitor.has_synced(),
+        !server.attestation_signers.read().await.is_empty(),
+    );
+    readiness_response(readiness)
+}
+
+impl Readiness {
+    fn new(
+        postgres: Check,
+        graph_node: Check,
+        allocations_synced: bool,
+        escrow_accounts_synced: bool,
+        attestation_signer_loaded: bool,
+    ) -> Self {
+        let allocation_monitor = if allocations_synced {
+            Check::ok()
+        } else {
+            Check::failed("Allocations have not been synced yet")
+        };
+
+        let escrow_monitor = if escrow_accounts_synced {
+            Check::ok()
+        } else {
+            Check::failed("Escrow accounts have not been synced yet")
+        };
+
+        let attestation_signers = if attestation_signer_loaded {
+            Check::ok()
+        } else {
+            Check::failed("No attestation signer loaded")
+        };
+
+        let ready = [
+            &postgres,
+            &graph_node,
+            &allocation_monitor,
+            &escrow_monitor,
+            &attestation_signers,
+        ]
+        .iter()
+        .all(|check| check.ok);
+
+        Readiness {
+            ready,
+            postgres,
+            graph_node,
+            allocation_monitor,
+            escrow_monitor,
+            attestation_signers,
+        }
+    }
+}
+
+/// 200 if every check passed, 503 otherwise, with the outcome of each check
+fn readiness_response(readiness: Readiness) -> Response {
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness)).into_response()
+}
+
+async fn check_postgres(server: &ServerOptions) -> Check {
+    match sqlx::query("SELECT 1")
+        .execute(server.indexer_management_client.database())
+        .await
+    {
+        Ok(_) => Check::ok(),
+        Err(e) => Check::failed(e),
+    }
+}
+
+async fn check_graph_node(graph_node_status_endpoint: &str) -> Check {
+    let response = reqwest::Client::new()
+        .post(graph_node_status_endpoint)
+        .header(header::CONTENT_TYPE, "application/json")
+        .json(&json!({ "query": "{ version { version } }" }))
+        .timeout(Duration::from_secs(3))
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => Check::ok(),
+        Ok(response) => Check::failed(format!(
+            "Graph node status endpoint responded with {}",
+            response.status()
+        )),
+        Err(e) => Check::failed(e),
+    }
+}
+
 /// Index endpoint for status checks
 pub async fn index() -> impl IntoResponse {
     let responder = "Ready to roll!".to_string();
@@ -37,7 +199,375 @@ async fn operator_info(Extension(options): Extension<ServerOptions>) -> Json<ser
     Json(json!({ "publicKey": public_key }))
 }
 
-// Create a function to build the operator server router
-pub fn create_operator_server(_options: ServerOptions) -> Router {
-    Router::new().route("/info", get(operator_info))
+/// Allocated deployments that have been denied, for which paid queries are refused
+async fn denied_deployments(Extension(options): Extension<ServerOptions>) -> Json<Vec<String>> {
+    Json(
+        options
+            .allocation_monitor
+            .denied_deployments()
+            .await
+            .iter()
+            .map(|deployment| deployment.ipfs_hash())
+            .collect(),
+    )
+}
+
+/// Graph node query nodes each deployment is routed to
+async fn routing_table(Extension(options): Extension<ServerOptions>) -> Json<RoutingTable> {
+    Json(options.query_processor.routing_table())
+}
+
+/// Named API keys for free queries, without the keys themselves
+async fn list_api_keys(Extension(options): Extension<ServerOptions>) -> Json<Vec<ApiKey>> {
+    Json(options.api_keys.list())
+}
+
+/// Create a named API key. The generated key is only ever returned in this response.
+async fn create_api_key(
+    Extension(options): Extension<ServerOptions>,
+    Json(mut api_key): Json<ApiKey>,
+) -> Response {
+    if let Some(deployments) = &mut api_key.deployments {
+        // Accept both deployment representations, keys are checked against IPFS hashes
+        match deployments
+            .iter()
+            .map(|d| SubgraphDeploymentID::new(d).map(|d| d.ipfs_hash()))
+            .collect::<Result<Vec<String>, _>>()
+        {
+            Ok(ipfs_hashes) => *deployments = ipfs_hashes,
+            Err(e) => return bad_request_response(&format!("Invalid deployment: {}", e)),
+        }
+    }
+
+    let name = api_key.name.clone();
+    match options.api_keys.create(api_key).await {
+        Ok(key) => (
+            StatusCode::CREATED,
+            Json(json!({ "name": name, "key": key })),
+        )
+            .into_response(),
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23505") => (
+            StatusCode::CONFLICT,
+            Json(format!("API key {} already exists", name)),
+        )
+            .into_response(),
+        Err(e) => internal_server_error_response(&e.to_string()),
+    }
+}
+
+async fn delete_api_key(
+    Extension(options): Extension<ServerOptions>,
+    Path(name): Path<String>,
+) -> Response {
+    match options.api_keys.delete(&name).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            Json(format!("API key {} does not exist", name)),
+        )
+            .into_response(),
+        Err(e) => internal_server_error_response(&e.to_string()),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EligibleAllocation {
+    id: Address,
+    deployment: String,
+    allocated_tokens: String,
+    created_at_epoch: u64,
+    closed_at_epoch: Option<u64>,
+    denied: bool,
+    has_attestation_signer: bool,
+}
+
+/// Allocations paid queries are accepted for, and whether their attestation signer is loaded
+async fn eligible_allocations(
+    Extension(options): Extension<ServerOptions>,
+) -> Json<Vec<EligibleAllocation>> {
+    let signers = options.attestation_signers.read().await;
+    let mut allocations = options
+        .allocation_monitor
+        .get_eligible_allocations()
+        .await
+        .values()
+        .map(|allocation| EligibleAllocation {
+            id: allocation.id,
+            deployment: allocation.subgraph_deployment.id.ipfs_hash(),
+            allocated_tokens: allocation.allocated_tokens.to_string(),
+            created_at_epoch: allocation.created_at_epoch,
+            closed_at_epoch: allocation.closed_at_epoch,
+            denied: allocation.subgraph_deployment.is_denied(),
+            has_attestation_signer: signers.contains_key(&allocation.id),
+        })
+        .collect::<Vec<EligibleAllocation>>();
+    allocations.sort_by(|a, b| a.deployment.cmp(&b.deployment).then(a.id.cmp(&b.id)));
+    Json(allocations)
+}
+
+#[derive(Serialize)]
+struct LoadedSigner {
+    allocation: Address,
+    deployment: Option<String>,
+}
+
+/// Allocations with a loaded attestation signer. Signers of allocations that are no longer
+/// eligible are kept, without a deployment.
+async fn attestation_signers(
+    Extension(options): Extension<ServerOptions>,
+) -> Json<Vec<LoadedSigner>> {
+    let allocations = options.allocation_monitor.get_eligible_allocations().await;
+    let mut signers = options
+        .attestation_signers
+        .read()
+        .await
+        .keys()
+        .map(|allocation| LoadedSigner {
+            allocation: *allocation,
+            deployment: allocations
+                .get(allocation)
+                .map(|allocation| allocation.subgraph_deployment.id.ipfs_hash()),
+        })
+        .collect::<Vec<LoadedSigner>>();
+    signers.sort_by_key(|signer| signer.allocation);
+    Json(signers)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SenderAccount {
+    sender: Address,
+    escrow_balance: String,
+    outstanding_receipts: u64,
+    outstanding_value: String,
+}
+
+/// Escrow balance of each sender and the receipts of theirs not yet aggregated into a RAV
+async fn sender_accounts(Extension(options): Extension<ServerOptions>) -> Response {
+    let outstanding = match options.query_processor.outstanding_receipts().await {
+        Ok(outstanding) => outstanding,
+        Err(e) => return internal_server_error_response(&e.to_string()),
+    };
+    let balances = options.escrow_monitor.get_accounts().await;
+
+    let senders = balances
+        .keys()
+        .chain(outstanding.keys())
+        .collect::<HashSet<&Address>>();
+    let mut accounts = senders
+        .into_iter()
+        .map(|sender| {
+            let receipts = outstanding.get(sender).copied().unwrap_or_default();
+            SenderAccount {
+                sender: *sender,
+                escrow_balance: balances
+                    .get(sender)
+                    .map_or_else(|| "0".to_string(), |balance| balance.to_string()),
+                outstanding_receipts: receipts.count,
+                outstanding_value: receipts.value.to_string(),
+            }
+        })
+        .collect::<Vec<SenderAccount>>();
+    accounts.sort_by_key(|account| account.sender);
+    Json(accounts).into_response()
+}
+
+/// Sync the eligible allocations and escrow accounts now instead of at their next interval
+async fn resync(Extension(options): Extension<ServerOptions>) -> impl IntoResponse {
+    options.allocation_monitor.resync();
+    options.escrow_monitor.resync();
+    StatusCode::ACCEPTED
+}
+
+/// Deployments whose queries have been turned off with `PUT /disabled-deployments/:deployment`
+async fn disabled_deployments(Extension(options): Extension<ServerOptions>) -> Json<Vec<String>> {
+    let mut deployments = options
+        .disabled_deployments
+        .read()
+        .unwrap()
+        .iter()
+        .map(|deployment| deployment.ipfs_hash())
+        .collect::<Vec<String>>();
+    deployments.sort();
+    Json(deployments)
+}
+
+/// Stop serving queries on a deployment, until it is enabled again or the service restarts
+async fn disable_deployment(
+    Extension(options): Extension<ServerOptions>,
+    Path(deployment): Path<String>,
+) -> Response {
+    match SubgraphDeploymentID::new(&deployment) {
+        Ok(deployment) => {
+            options
+                .disabled_deployments
+                .write()
+                .unwrap()
+                .insert(deployment);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => bad_request_response(&format!("Invalid deployment: {}", e)),
+    }
+}
+
+async fn enable_deployment(
+    Extension(options): Extension<ServerOptions>,
+    Path(deployment): Path<String>,
+) -> Response {
+    match SubgraphDeploymentID::new(&deployment) {
+        Ok(deployment) => {
+            options
+                .disabled_deployments
+                .write()
+                .unwrap()
+                .remove(&deployment);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => bad_request_response(&format!("Invalid deployment: {}", e)),
+    }
+}
+
+/// Rejects requests without the operator auth token as a bearer token
+async fn require_operator_token(
+    req: Request<Body>,
+    next: Next<Body>,
+    token: AuthToken,
+) -> Response {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|t| t.to_str().ok())
+        .and_then(|t| t.strip_prefix("Bearer "))
+        .map_or(false, |t| token.matches(t));
+    if !authorized {
+        record_failed_auth("operator", client_address(req.extensions()));
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json("Operator auth token required".to_string()),
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+/// Operator routes. Only `/info` is public, the management routes are served when an
+/// operator auth token is configured and require it.
+pub fn create_operator_server(options: ServerOptions) -> Router {
+    let router = Router::new().route("/info", get(operator_info));
+    let token = match options.operator_auth_token {
+        Some(token) => token,
+        None => return router,
+    };
+
+    let management = Router::new()
+        .route("/allocations", get(eligible_allocations))
+        .route("/attestation-signers", get(attestation_signers))
+        .route("/senders", get(sender_accounts))
+        .route("/resync", post(resync))
+        .route("/denied-deployments", get(denied_deployments))
+        .route("/disabled-deployments", get(disabled_deployments))
+        .route(
+            "/disabled-deployments/:deployment",
+            put(disable_deployment).delete(enable_deployment),
+        )
+        .route("/routing", get(routing_table))
+        .route("/api-keys", get(list_api_keys).post(create_api_key))
+        .route("/api-keys/:name", delete(delete_api_key))
+        .layer(middleware::from_fn(move |req, next| {
+            require_operator_token(req, next, token.clone())
+        }));
+    router.merge(management)
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    async fn json_body(response: Response) -> serde_json::Value {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn ready_once_every_check_passes() {
+        let response =
+            readiness_response(Readiness::new(Check::ok(), Check::ok(), true, true, true));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            json_body(response).await,
+            json!({
+                "ready": true,
+                "postgres": { "ok": true },
+                "graphNode": { "ok": true },
+                "allocationMonitor": { "ok": true },
+                "escrowMonitor": { "ok": true },
+                "attestationSigners": { "ok": true },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn not_ready_before_monitors_sync() {
+        let response =
+            readiness_response(Readiness::new(Check::ok(), Check::ok(), false, false, true));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            json_body(response).await,
+            json!({
+                "ready": false,
+                "postgres": { "ok": true },
+                "graphNode": { "ok": true },
+                "allocationMonitor": {
+                    "ok": false,
+                    "error": "Allocations have not been synced yet",
+                },
+                "escrowMonitor": {
+                    "ok": false,
+                    "error": "Escrow accounts have not been synced yet",
+                },
+                "attestationSigners": { "ok": true },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn not_ready_without_attestation_signer() {
+        let response =
+            readiness_response(Readiness::new(Check::ok(), Check::ok(), true, true, false));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = json_body(response).await;
+        assert_eq!(body["ready"], false);
+        assert_eq!(
+            body["attestationSigners"],
+            json!({ "ok": false, "error": "No attestation signer loaded" })
+        );
+        assert_eq!(body["allocationMonitor"], json!({ "ok": true }));
+    }
+
+    #[tokio::test]
+    async fn graph_node_check() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&mock_server)
+            .await;
+
+        let check = check_graph_node(&mock_server.uri()).await;
+        assert!(!check.ok);
+        assert!(check.error.unwrap().contains("500"));
+
+        let response = readiness_response(Readiness::new(
+            Check::ok(),
+            check_graph_node(&mock_server.uri()).await,
+            true,
+            true,
+            true,
+        ));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json_body(response).await["graphNode"]["ok"], false);
+    }
 }
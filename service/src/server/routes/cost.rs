@@ -1,18 +1,57 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::net::SocketAddr;
+
 use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
-use axum::extract::Extension;
+use axum::{
+    extract::{ConnectInfo, Extension},
+    http::{self, HeaderMap},
+};
 
-use crate::{common::indexer_management_client::schema::CostSchema, server::ServerOptions};
+use crate::{
+    common::indexer_management_client::{schema::CostSchema, CostModelEditor},
+    server::{auth::record_failed_auth, ServerOptions},
+};
 
 pub(crate) async fn graphql_handler(
-    req: GraphQLRequest,
     Extension(schema): Extension<CostSchema>,
     Extension(server_options): Extension<ServerOptions>,
+    headers: HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    req: GraphQLRequest,
 ) -> GraphQLResponse {
-    schema
-        .execute(req.into_inner().data(server_options))
-        .await
-        .into()
+    let client = connect_info.map(|ConnectInfo(address)| address);
+    let mut req = req.into_inner();
+    if let Some(editor) = cost_model_editor(&server_options, &headers, client) {
+        req = req.data(editor);
+    }
+
+    schema.execute(req.data(server_options)).await.into()
+}
+
+/// Requests with the operator auth token can change cost models. Changes are recorded as made
+/// by the fingerprint of the token, so that rotated tokens can be told apart.
+fn cost_model_editor(
+    server: &ServerOptions,
+    headers: &HeaderMap,
+    client: Option<SocketAddr>,
+) -> Option<CostModelEditor> {
+    let auth_token = headers
+        .get(http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()
+        .and_then(|t| t.strip_prefix("Bearer "));
+    match (auth_token, &server.operator_auth_token) {
+        (Some(auth_token), Some(expected)) if expected.matches(auth_token) => {
+            Some(CostModelEditor {
+                name: format!("operator:{}", expected.fingerprint()),
+                client_address: client.map(|client| client.ip().to_string()),
+            })
+        }
+        _ => {
+            record_failed_auth("cost", client);
+            None
+        }
+    }
 }
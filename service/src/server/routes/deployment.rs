@@ -1,150 +1,366 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
-
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 
 use crate::{
-    common::indexer_error::{IndexerError, IndexerErrorCause},
-    server::{routes::internal_server_error_response, ServerOptions},
+    common::{
+        indexing_status::{
+            indexing_statuses, status_deployment, DeploymentHealth, IndexingStatus,
+            InvalidIndexingStatus,
+        },
+        types::SubgraphDeploymentID,
+    },
+    server::{
+        routes::{bad_request_response, internal_server_error_response},
+        ServerOptions,
+    },
 };
 
-/// Parse an incoming query request and route queries with authenticated
-/// free query token to graph node
-/// Later add receipt manager functions for paid queries
+/// Maximum number of deployments in a single batch health request
+const MAX_BATCH_DEPLOYMENTS: usize = 100;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthParams {
+    max_block_lag: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchHealthParams {
+    /// Comma separated list of deployments
+    deployments: Option<String>,
+    max_block_lag: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchHealth {
+    healthy: bool,
+    max_block_lag: u64,
+    deployments: Vec<DeploymentHealth>,
+    /// Requested deployments the index node server has no indexing status for
+    not_indexed: Vec<String>,
+    /// Requested deployments whose indexing status could not be evaluated
+    errors: Vec<DeploymentHealthError>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeploymentHealthError {
+    deployment: String,
+    error: String,
+}
+
+/// Report the health of a deployment across all the chains it indexes.
+/// Responds with 503 if the deployment has failed or lags behind any chain head,
+/// and with 500 if its indexing status could not be determined.
 pub async fn deployment_health(
     Extension(server): Extension<ServerOptions>,
-    deployment: axum::extract::Path<String>,
+    Path(deployment): Path<String>,
+    Query(params): Query<HealthParams>,
 ) -> impl IntoResponse {
-    // Create the GraphQL query
-    let query = status_query(deployment.to_string());
-
-    // Send the GraphQL request
-    let response = reqwest::Client::new()
-        .post(server.graph_node_status_endpoint)
-        .header("Content-Type", "application/json")
-        .json(&query)
-        .send()
-        .await;
-
-    match response {
-        Ok(response) => {
-            if response.status().is_success() {
-                // Deserialize the JSON response
-                //TODO: match with error
-                let data: serde_json::Value = if let Ok(data) = response.json().await {
-                    data
-                } else {
-                    return internal_server_error_response("Invalid json response");
-                };
-
-                // Process the response and return the appropriate HTTP status
-                let status = if let Some(status) =
-                    data["data"]["indexingStatuses"].get(0).and_then(|s| {
-                        let parse = serde_json::from_value::<IndexingStatus>(s.clone());
-                        parse.ok()
-                    }) {
-                    status
-                } else {
-                    return internal_server_error_response("Missing indexing status");
-                };
-
-                // Build health response based on the returned status
-                if status.health == SubgraphHealth::failed {
-                    return internal_server_error_response("Subgraph deployment has failed");
-                }
-
-                if let Ok((latest, head)) = block_numbers(status) {
-                    if latest > head - 5 {
-                        (StatusCode::OK, Json("Subgraph deployment is up to date")).into_response()
-                    } else {
-                        internal_server_error_response("Subgraph deployment is lagging behind")
-                    }
-                } else {
-                    internal_server_error_response(
-                        "Invalid indexing status (missing block numbers)",
-                    )
-                }
-            } else {
-                internal_server_error_response("Unknown error")
-            }
+    let deployment = match SubgraphDeploymentID::new(&deployment) {
+        Ok(deployment) => deployment.ipfs_hash(),
+        Err(e) => return bad_request_response(&format!("Invalid deployment: {}", e)),
+    };
+    let max_block_lag = params
+        .max_block_lag
+        .unwrap_or(server.deployment_max_block_lag);
+
+    let statuses = match indexing_statuses(
+        &server.status_client,
+        &server.graph_node_status_endpoint,
+        &[deployment.clone()],
+    )
+    .await
+    {
+        Ok(statuses) => statuses,
+        Err(e) => return internal_server_error_response(&e.to_string()),
+    };
+
+    let status = match statuses
+        .into_iter()
+        .find(|status| status_deployment(status) == Some(deployment.as_str()))
+    {
+        Some(Ok(status)) => status,
+        Some(Err(invalid)) => return internal_server_error_response(&invalid.error),
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(format!("Deployment {} is not indexed", deployment)),
+            )
+                .into_response()
         }
+    };
+
+    match status.evaluate(max_block_lag) {
+        Ok(health) if health.healthy => (StatusCode::OK, Json(health)).into_response(),
+        Ok(health) => (StatusCode::SERVICE_UNAVAILABLE, Json(health)).into_response(),
         Err(e) => internal_server_error_response(&e.to_string()),
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct IndexingStatus {
-    health: SubgraphHealth,
-    chains: Vec<ChainStatus>,
-}
+/// Report the health of several deployments with a single indexing statuses query.
+/// Defaults to all deployments with eligible allocations, and accepts at most
+/// `MAX_BATCH_DEPLOYMENTS` requested deployments. Responds with 503 if any of the
+/// deployments is unhealthy, not indexed or has an invalid indexing status.
+pub async fn deployments_health(
+    Extension(server): Extension<ServerOptions>,
+    Query(params): Query<BatchHealthParams>,
+) -> impl IntoResponse {
+    let mut deployments = match params.deployments {
+        Some(deployments) => match requested_deployments(&deployments) {
+            Ok(deployments) => deployments,
+            Err(e) => return bad_request_response(&e),
+        },
+        None => server
+            .allocation_monitor
+            .get_eligible_allocations()
+            .await
+            .values()
+            .map(|allocation| allocation.subgraph_deployment.id.ipfs_hash())
+            .collect(),
+    };
+    deployments.sort();
+    deployments.dedup();
+    let max_block_lag = params
+        .max_block_lag
+        .unwrap_or(server.deployment_max_block_lag);
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-#[allow(non_camel_case_types)] // Need exact field names to match with GQL response
-enum SubgraphHealth {
-    healthy,
-    unhealthy,
-    failed,
-}
+    // An empty list would make the index node server return the status of every deployment
+    let statuses = if deployments.is_empty() {
+        vec![]
+    } else {
+        match indexing_statuses(
+            &server.status_client,
+            &server.graph_node_status_endpoint,
+            &deployments,
+        )
+        .await
+        {
+            Ok(statuses) => statuses,
+            Err(e) => return internal_server_error_response(&e.to_string()),
+        }
+    };
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct ChainStatus {
-    network: String,
-    latest_block: Block,
-    chain_head_block: Block,
+    let batch = batch_health(deployments, &statuses, max_block_lag);
+    let status_code = if batch.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status_code, Json(batch)).into_response()
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Block {
-    number: String,
-    hash: String,
+/// Parses a comma separated list of deployments, rejecting lists longer than
+/// `MAX_BATCH_DEPLOYMENTS` so that one request cannot query the index node server for
+/// arbitrarily many deployments.
+fn requested_deployments(deployments: &str) -> Result<Vec<String>, String> {
+    let mut deployments = deployments
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| SubgraphDeploymentID::new(d).map(|d| d.ipfs_hash()))
+        .collect::<Result<Vec<String>, _>>()
+        .map_err(|e| format!("Invalid deployment: {}", e))?;
+    deployments.sort();
+    deployments.dedup();
+    if deployments.len() > MAX_BATCH_DEPLOYMENTS {
+        return Err(format!(
+            "Too many deployments: {} requested, at most {} allowed",
+            deployments.len(),
+            MAX_BATCH_DEPLOYMENTS
+        ));
+    }
+    Ok(deployments)
 }
 
-fn status_query(deployment: String) -> serde_json::Value {
-    json!({
-        "query": r#"query indexingStatus($subgraphs: [String!]!) {
-            indexingStatuses(subgraphs: $subgraphs) {
-                subgraph
-                health
-                chains {
-                    network
-                    ... on EthereumIndexingStatus {
-                        latestBlock { number hash }
-                        chainHeadBlock { number hash }
-                    }
-                }
-            }
-        }"#,
-        "variables": {
-            "subgraphs": [deployment],
-        },
-    })
+/// Evaluates the health of each requested deployment from the indexing statuses. A
+/// deployment whose status cannot be evaluated is reported on its own, without failing
+/// the other deployments.
+fn batch_health(
+    deployments: Vec<String>,
+    statuses: &[Result<IndexingStatus, InvalidIndexingStatus>],
+    max_block_lag: u64,
+) -> BatchHealth {
+    let mut health = vec![];
+    let mut errors = vec![];
+    for status in statuses {
+        let deployment = match status_deployment(status) {
+            Some(deployment) if deployments.iter().any(|d| d == deployment) => deployment,
+            _ => continue,
+        };
+        match status
+            .as_ref()
+            .map_err(|invalid| invalid.error.clone())
+            .and_then(|status| status.evaluate(max_block_lag).map_err(|e| e.to_string()))
+        {
+            Ok(deployment_health) => health.push(deployment_health),
+            Err(error) => errors.push(DeploymentHealthError {
+                deployment: deployment.to_string(),
+                error,
+            }),
+        }
+    }
+    let not_indexed = deployments
+        .into_iter()
+        .filter(|d| {
+            !statuses
+                .iter()
+                .any(|status| status_deployment(status) == Some(d.as_str()))
+        })
+        .collect::<Vec<String>>();
+
+    BatchHealth {
+        healthy: not_indexed.is_empty() && errors.is_empty() && health.iter().all(|h| h.healthy),
+        max_block_lag,
+        deployments: health,
+        not_indexed,
+        errors,
+    }
 }
 
-fn block_numbers(status: IndexingStatus) -> Result<(u64, u64), IndexerError> {
-    let latest_block_number = status
-        .chains
-        .get(0)
-        .map(|chain| chain.latest_block.number.clone())
-        .map(|number| number.parse::<u64>());
-
-    let head_block_number = status
-        .chains
-        .get(0)
-        .map(|chain| chain.chain_head_block.number.clone())
-        .map(|number| number.parse::<u64>());
-
-    if let (Some(Ok(latest)), Some(Ok(head))) = (latest_block_number, head_block_number) {
-        Ok((latest, head))
-    } else {
-        Err(IndexerError::new(
-            crate::common::indexer_error::IndexerErrorCode::IE018,
-            Some(IndexerErrorCause::new(
-                "Ill formatted block numbers from indexing status",
-            )),
-        ))
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const HEALTHY: &str = "QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ";
+    const LAGGING: &str = "QmVhiE4nax9i86UBnBmQCYDzvjWuwHShYh7aspGPQhU5Sj";
+    const FAILED: &str = "QmacQnSgia4iDPWHpeY6aWxesRFdb8o5DKZUx96zZqEWrB";
+    const UNKNOWN: &str = "QmWVtsWk8Pqn3zY3czDjyoVreshRLmoz9jko3mQ4uvxQDj";
+    const INVALID: &str = "Qmb5Ysp5oCUXhLA8NmxmYKDAX2nCMnh7Vvb5uffb9n5vss";
+    const PAUSED: &str = "QmTBxvMF6YnbT1eYeRx9XQpH4WvxTV53vdptCCZFiZSprg";
+
+    fn status(deployment: &str, health: &str, latest: &str, head: &str) -> IndexingStatus {
+        serde_json::from_value(json!({
+            "subgraph": deployment,
+            "node": "default",
+            "health": health,
+            "fatalError": null,
+            "chains": [{
+                "network": "mainnet",
+                "latestBlock": { "number": latest, "hash": "0x00" },
+                "chainHeadBlock": { "number": head, "hash": "0x00" },
+            }],
+        }))
+        .unwrap()
+    }
+
+    fn statuses() -> Vec<Result<IndexingStatus, InvalidIndexingStatus>> {
+        vec![
+            Ok(status(HEALTHY, "healthy", "100", "101")),
+            Ok(status(LAGGING, "healthy", "100", "200")),
+            Ok(status(FAILED, "failed", "100", "100")),
+            Ok(status(INVALID, "healthy", "latest", "100")),
+            // As returned for a status with a health this service does not know of
+            Err(InvalidIndexingStatus {
+                subgraph: Some(PAUSED.to_string()),
+                error: "Invalid indexing status: unknown variant `paused`".to_string(),
+            }),
+        ]
+    }
+
+    fn healthy(batch: &BatchHealth, deployment: &str) -> bool {
+        batch
+            .deployments
+            .iter()
+            .find(|health| health.deployment == deployment)
+            .unwrap()
+            .healthy
+    }
+
+    #[test]
+    fn healthy_deployments() {
+        let batch = batch_health(vec![HEALTHY.to_string()], &statuses(), 5);
+        assert!(batch.healthy);
+        assert!(healthy(&batch, HEALTHY));
+        assert!(batch.not_indexed.is_empty());
+        assert!(batch.errors.is_empty());
+    }
+
+    #[test]
+    fn lagging_and_failed_deployments() {
+        let batch = batch_health(
+            vec![HEALTHY.to_string(), LAGGING.to_string(), FAILED.to_string()],
+            &statuses(),
+            5,
+        );
+        assert!(!batch.healthy);
+        assert!(healthy(&batch, HEALTHY));
+        assert!(!healthy(&batch, LAGGING));
+        assert!(!healthy(&batch, FAILED));
+
+        // Lag is relative to the requested maximum
+        let batch = batch_health(vec![LAGGING.to_string()], &statuses(), 100);
+        assert!(batch.healthy);
+    }
+
+    #[test]
+    fn unknown_deployments() {
+        let batch = batch_health(
+            vec![HEALTHY.to_string(), UNKNOWN.to_string()],
+            &statuses(),
+            5,
+        );
+        assert!(!batch.healthy);
+        assert!(healthy(&batch, HEALTHY));
+        assert_eq!(batch.not_indexed, vec![UNKNOWN.to_string()]);
+    }
+
+    #[test]
+    fn invalid_statuses_are_reported_per_deployment() {
+        let batch = batch_health(
+            vec![HEALTHY.to_string(), INVALID.to_string()],
+            &statuses(),
+            5,
+        );
+        assert!(!batch.healthy);
+        assert!(healthy(&batch, HEALTHY));
+        assert_eq!(batch.errors.len(), 1);
+        assert_eq!(batch.errors[0].deployment, INVALID);
+        assert!(batch.not_indexed.is_empty());
+    }
+
+    #[test]
+    fn unparseable_statuses_are_reported_per_deployment() {
+        let batch = batch_health(
+            vec![HEALTHY.to_string(), PAUSED.to_string()],
+            &statuses(),
+            5,
+        );
+        assert!(!batch.healthy);
+        assert!(healthy(&batch, HEALTHY));
+        assert_eq!(batch.errors.len(), 1);
+        assert_eq!(batch.errors[0].deployment, PAUSED);
+        assert!(batch.not_indexed.is_empty());
+    }
+
+    #[test]
+    fn requested_deployments_are_capped() {
+        assert_eq!(
+            requested_deployments(&format!("{}, {},{}", HEALTHY, LAGGING, HEALTHY)).unwrap(),
+            vec![LAGGING.to_string(), HEALTHY.to_string()]
+        );
+        assert!(requested_deployments("not-a-deployment").is_err());
+
+        let duplicates = vec![HEALTHY; MAX_BATCH_DEPLOYMENTS + 1].join(",");
+        assert_eq!(requested_deployments(&duplicates).unwrap().len(), 1);
+        let too_many = (0..=MAX_BATCH_DEPLOYMENTS)
+            .map(|i| format!("0x{:064x}", i))
+            .collect::<Vec<String>>()
+            .join(",");
+        assert!(requested_deployments(&too_many)
+            .unwrap_err()
+            .starts_with("Too many deployments"));
     }
 }
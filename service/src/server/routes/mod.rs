@@ -1,14 +1,21 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::common::indexer_error::{IndexerError, IndexerErrorCause};
+use crate::{
+    api_keys::{ApiKeyError, ApiRoute},
+    common::{
+        indexer_error::{IndexerError, IndexerErrorCause, IndexerErrorCode},
+        types::SubgraphDeploymentID,
+    },
+    server::ServerOptions,
+};
 use axum::{
-    http::StatusCode,
+    body::{boxed, Full},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
 use hyper::http::HeaderName;
-use tower::limit::RateLimitLayer;
 
 pub mod basic;
 pub mod cost;
@@ -46,6 +53,16 @@ pub fn bad_request_response(error_body: &str) -> Response {
         .into_response()
 }
 
+/// Create response for a request missing valid authentication
+pub fn unauthorized_response(error_body: &str) -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        axum::response::AppendHeaders([(HeaderName::from_static("graph-attestable"), "false")]),
+        Json(error_body.to_string()),
+    )
+        .into_response()
+}
+
 /// Create response for an internal server error
 pub fn internal_server_error_response(error_body: &str) -> Response {
     (
@@ -55,12 +72,142 @@ pub fn internal_server_error_response(error_body: &str) -> Response {
         .into_response()
 }
 
-/// Limit status requests to 9000/30min (5/s)
-pub fn slow_ratelimiter() -> RateLimitLayer {
-    RateLimitLayer::new(9000, std::time::Duration::from_millis(30 * 60 * 1000))
+/// Forward a graph-node response, preserving its status code, content type and body. The
+/// body is buffered in full before it is forwarded, and no other header is forwarded.
+pub async fn passthrough_response(
+    response: reqwest::Response,
+    error_code: IndexerErrorCode,
+) -> Response {
+    let status = response.status();
+    let content_type = response.headers().get(header::CONTENT_TYPE).cloned();
+
+    match response.bytes().await {
+        Ok(body) => {
+            let mut builder = Response::builder().status(status);
+            if let Some(content_type) = content_type {
+                builder = builder.header(header::CONTENT_TYPE, content_type);
+            }
+            builder
+                .body(boxed(Full::from(body)))
+                .expect("Build passthrough response")
+        }
+        Err(e) => upstream_error_response(e, error_code),
+    }
+}
+
+/// Create response for a failure to reach graph-node: 504 on timeouts, 502 otherwise
+pub fn upstream_error_response(error: reqwest::Error, error_code: IndexerErrorCode) -> Response {
+    let status = if error.is_timeout() {
+        StatusCode::GATEWAY_TIMEOUT
+    } else {
+        StatusCode::BAD_GATEWAY
+    };
+    let error = IndexerError::new(error_code, Some(IndexerErrorCause::new(error)));
+    (status, Json(error.to_string())).into_response()
+}
+
+/// Checks the request's API key, if it carries one, against the key's scopes for the route.
+/// Returns whether the request is authorized by an API key, or the response rejecting it.
+pub fn authorize_api_key(
+    server: &ServerOptions,
+    headers: &HeaderMap,
+    route: ApiRoute,
+    deployment: Option<&SubgraphDeploymentID>,
+) -> Result<bool, Response> {
+    let auth_token = match headers
+        .get(header::AUTHORIZATION)
+        .and_then(|t| t.to_str().ok())
+    {
+        Some(auth_token) => auth_token,
+        None => return Ok(false),
+    };
+
+    match server.api_keys.authorize(auth_token, route, deployment) {
+        None => Ok(false),
+        Some(Ok(_)) => Ok(true),
+        Some(Err(ApiKeyError::RateLimited(name, retry_after))) => Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(
+                header::RETRY_AFTER,
+                retry_after.as_secs().max(1).to_string(),
+            )],
+            Json(format!("API key {} exceeded its rate limit", name)),
+        )
+            .into_response()),
+        Some(Err(e)) => Err((StatusCode::FORBIDDEN, Json(e.to_string())).into_response()),
+    }
 }
 
-/// Limit network requests to 90000/30min (50/s)
-pub fn network_ratelimiter() -> RateLimitLayer {
-    RateLimitLayer::new(90000, std::time::Duration::from_millis(30 * 60 * 1000))
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    async fn body(response: Response) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn passes_through_status_content_type_and_body() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(418)
+                    .insert_header("x-graph-node", "query_node_0")
+                    .set_body_raw(r#"{"errors":[]}"#, "application/graphql-response+json"),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let upstream = reqwest::Client::new()
+            .post(mock_server.uri())
+            .send()
+            .await
+            .unwrap();
+        let response = passthrough_response(upstream, IndexerErrorCode::IE018).await;
+
+        assert_eq!(response.status(), StatusCode::IM_A_TEAPOT);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/graphql-response+json"
+        );
+        assert!(response.headers().get("x-graph-node").is_none());
+        assert_eq!(body(response).await, r#"{"errors":[]}"#);
+    }
+
+    #[tokio::test]
+    async fn upstream_errors() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+            .mount(&mock_server)
+            .await;
+
+        let timeout = reqwest::Client::builder()
+            .timeout(Duration::from_millis(50))
+            .build()
+            .unwrap()
+            .post(mock_server.uri())
+            .send()
+            .await
+            .unwrap_err();
+        let response = upstream_error_response(timeout, IndexerErrorCode::IE018);
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(body(response).await.contains("IE018"));
+
+        // Nothing listens on the discard port
+        let unreachable = reqwest::Client::new()
+            .post("http://127.0.0.1:9")
+            .send()
+            .await
+            .unwrap_err();
+        let response = upstream_error_response(unreachable, IndexerErrorCode::IE024);
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert!(body(response).await.contains("IE024"));
+    }
 }
@@ -3,14 +3,23 @@
 
 use axum::{
     extract::Extension,
-    http::{self, Request, StatusCode},
+    http::{self, Request},
     response::IntoResponse,
-    Json,
 };
 
-use crate::server::ServerOptions;
+use crate::{
+    api_keys::ApiRoute,
+    common::indexer_error::IndexerErrorCode,
+    server::{
+        auth::{client_address, record_failed_auth},
+        ServerOptions,
+    },
+};
 
-use super::{bad_request_response, response_body_to_query_string};
+use super::{
+    authorize_api_key, bad_request_response, passthrough_response, response_body_to_query_string,
+    upstream_error_response,
+};
 
 pub async fn network_queries(
     Extension(server): Extension<ServerOptions>,
@@ -22,12 +31,25 @@ pub async fn network_queries(
         .get(http::header::AUTHORIZATION)
         .and_then(|t| t.to_str().ok());
 
-    // Serve only if enabled by indexer and request auth token matches
-    if !(server.serve_network_subgraph
-        && auth_token.is_some()
-        && server.network_subgraph_auth_token.is_some()
-        && auth_token.unwrap() == server.network_subgraph_auth_token.as_deref().unwrap())
-    {
+    let token_matches = match (auth_token, &server.network_subgraph_auth_token) {
+        (Some(auth_token), Some(expected)) => expected.matches(auth_token),
+        _ => false,
+    };
+    let api_key = if token_matches || !server.serve_network_subgraph {
+        false
+    } else {
+        match authorize_api_key(&server, req.headers(), ApiRoute::Network, None) {
+            Ok(api_key) => api_key,
+            Err(response) => return response,
+        }
+    };
+
+    // Serve only if enabled by indexer and request auth token or API key matches
+    if !server.serve_network_subgraph {
+        return bad_request_response("Not enabled or authorized query");
+    }
+    if !(token_matches || api_key) {
+        record_failed_auth("network", client_address(req.extensions()));
         return bad_request_response("Not enabled or authorized query");
     }
 
@@ -38,14 +60,12 @@ pub async fn network_queries(
         Err(e) => return bad_request_response(&e.to_string()),
     };
 
-    let request = server
+    match server
         .network_subgraph
-        .execute_network_free_query(query_string)
+        .network_query_response(query_string)
         .await
-        .expect("Failed to execute free network subgraph query");
-
-    match request.status {
-        200 => (StatusCode::OK, Json(request.result)).into_response(),
-        _ => bad_request_response("Bad response from Graph node"),
+    {
+        Ok(response) => passthrough_response(response, IndexerErrorCode::IE078).await,
+        Err(e) => upstream_error_response(e, IndexerErrorCode::IE078),
     }
 }
@@ -1,39 +1,184 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use axum::{
-    http::{Request, StatusCode},
-    response::IntoResponse,
-    Extension, Json,
-};
+use std::collections::HashSet;
 
+use axum::{http::Request, response::IntoResponse, Extension};
+use graphql_parser::query::{Definition, OperationDefinition, Selection, SelectionSet};
 use reqwest::{header, Client};
 
-use crate::server::ServerOptions;
+use crate::{
+    api_keys::ApiRoute,
+    common::{indexer_error::IndexerErrorCode, types::GraphQLQuery},
+    server::ServerOptions,
+};
 
-use super::bad_request_response;
+use super::{
+    authorize_api_key, bad_request_response, passthrough_response, response_body_to_query_string,
+    upstream_error_response,
+};
+
+/// Maximum depth of nested fragments that are resolved when looking for root fields
+const MAX_FRAGMENT_DEPTH: usize = 8;
 
 // Custom middleware function to process the request before reaching the main handler
 pub async fn status_queries(
     Extension(server): Extension<ServerOptions>,
     req: Request<axum::body::Body>,
 ) -> impl IntoResponse {
-    let req_body = req.into_body();
-    // TODO: Extract the incoming GraphQL operation and filter root fields
-    // Pass the modified operation to the actual endpoint
+    // The status endpoint is public, but API keys are still held to their scopes
+    if let Err(response) = authorize_api_key(&server, req.headers(), ApiRoute::Status, None) {
+        return response;
+    }
+
+    let query_string = match response_body_to_query_string(req.into_body()).await {
+        Ok(q) => q,
+        Err(e) => return bad_request_response(&e.to_string()),
+    };
+
+    // Only pass the operation to the index node server if all of its root fields are allowed
+    let query: GraphQLQuery = match serde_json::from_str(&query_string) {
+        Ok(q) => q,
+        Err(e) => return bad_request_response(&format!("Invalid GraphQL request: {}", e)),
+    };
+    if let Err(e) = check_root_fields(&query.query, &server.status_allowed_root_fields) {
+        return bad_request_response(&e);
+    }
 
     let request = Client::new()
         .post(&server.graph_node_status_endpoint)
-        .body(req_body)
+        .body(query_string)
         .header(header::CONTENT_TYPE, "application/json");
 
-    let response: reqwest::Response = match request.send().await {
-        Ok(r) => r,
-        Err(e) => return bad_request_response(&e.to_string()),
-    };
+    match request.send().await {
+        Ok(response) => passthrough_response(response, IndexerErrorCode::IE018).await,
+        Err(e) => upstream_error_response(e, IndexerErrorCode::IE024),
+    }
+}
+
+/// Checks that the query only selects allowed root fields, rejecting mutations and subscriptions
+fn check_root_fields(query: &str, allowed_root_fields: &[String]) -> Result<(), String> {
+    let document = graphql_parser::parse_query::<&str>(query)
+        .map_err(|e| format!("Invalid GraphQL query: {}", e))?;
+
+    let fragments = document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Fragment(fragment) => Some((fragment.name, &fragment.selection_set)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let mut root_fields = HashSet::new();
+    for definition in &document.definitions {
+        let selection_set = match definition {
+            Definition::Operation(OperationDefinition::SelectionSet(selection_set)) => {
+                selection_set
+            }
+            Definition::Operation(OperationDefinition::Query(query)) => &query.selection_set,
+            Definition::Operation(_) => {
+                return Err("Only queries are allowed on /status".to_string());
+            }
+            Definition::Fragment(_) => continue,
+        };
+        collect_root_fields(selection_set, &fragments, 0, &mut root_fields)?;
+    }
+
+    match root_fields
+        .into_iter()
+        .find(|field| !allowed_root_fields.iter().any(|allowed| allowed == field))
+    {
+        Some(field) => Err(format!(
+            "Root field `{}` is not allowed on /status, allowed root fields: {}",
+            field,
+            allowed_root_fields.join(", ")
+        )),
+        None => Ok(()),
+    }
+}
+
+fn collect_root_fields<'q>(
+    selection_set: &SelectionSet<'q, &'q str>,
+    fragments: &[(&'q str, &SelectionSet<'q, &'q str>)],
+    depth: usize,
+    root_fields: &mut HashSet<&'q str>,
+) -> Result<(), String> {
+    if depth > MAX_FRAGMENT_DEPTH {
+        return Err("Fragments are nested too deeply".to_string());
+    }
+
+    for selection in &selection_set.items {
+        match selection {
+            Selection::Field(field) => {
+                root_fields.insert(field.name);
+            }
+            Selection::InlineFragment(fragment) => {
+                collect_root_fields(&fragment.selection_set, fragments, depth + 1, root_fields)?
+            }
+            Selection::FragmentSpread(spread) => {
+                let (_, selection_set) = fragments
+                    .iter()
+                    .find(|(name, _)| *name == spread.fragment_name)
+                    .ok_or_else(|| format!("Unknown fragment `{}`", spread.fragment_name))?;
+                collect_root_fields(selection_set, fragments, depth + 1, root_fields)?
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed() -> Vec<String> {
+        vec![
+            "indexingStatuses".to_string(),
+            "publicProofsOfIndexing".to_string(),
+        ]
+    }
+
+    #[test]
+    fn allows_allowed_root_fields() {
+        assert!(check_root_fields("{ indexingStatuses { subgraph health } }", &allowed()).is_ok());
+        assert!(check_root_fields(
+            r#"query statuses($subgraphs: [String!]!) {
+                indexingStatuses(subgraphs: $subgraphs) { subgraph }
+                pois: publicProofsOfIndexing(requests: []) { proofOfIndexing }
+            }"#,
+            &allowed()
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn rejects_other_root_fields() {
+        let err = check_root_fields(
+            "{ indexingStatuses { subgraph } indexingStatusForCurrentVersion(subgraphName: \"a\") { subgraph } }",
+            &allowed(),
+        )
+        .unwrap_err();
+        assert!(err.contains("indexingStatusForCurrentVersion"));
+    }
+
+    #[test]
+    fn resolves_fragments_at_root() {
+        assert!(check_root_fields(
+            "query { ...Root } fragment Root on Query { indexingStatuses { subgraph } }",
+            &allowed()
+        )
+        .is_ok());
+        assert!(check_root_fields(
+            "query { ... on Query { proofOfIndexing(subgraph: \"a\", blockNumber: 1, blockHash: \"0x\") } }",
+            &allowed()
+        )
+        .is_err());
+    }
 
-    match response.text().await {
-        Ok(r) => (StatusCode::OK, Json(r)).into_response(),
-        _ => bad_request_response("Response from Graph node cannot be parsed as a string"),
+    #[test]
+    fn rejects_mutations_and_invalid_queries() {
+        assert!(check_root_fields("mutation { indexingStatuses }", &allowed()).is_err());
+        assert!(check_root_fields("{ indexingStatuses", &allowed()).is_err());
     }
 }
@@ -1,21 +1,35 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::net::SocketAddr;
+
 use axum::{
-    extract::Extension,
-    http::{self, Request, StatusCode},
-    response::IntoResponse,
+    extract::{ws::WebSocketUpgrade, ConnectInfo, Extension, Path},
+    http::{self, HeaderMap, HeaderValue, Request, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
+use hyper::http::HeaderName;
 
 use tracing::trace;
 
 use crate::{
-    common::types::SubgraphDeploymentID,
+    api_keys::ApiRoute,
+    common::{
+        indexer_error::{IndexerError, IndexerErrorCause, IndexerErrorCode},
+        indexing_status::current_deployment,
+        query_validation::{validate_batch, validate_request},
+        types::{SubgraphDeploymentID, SubgraphName},
+    },
     metrics,
-    query_processor::FreeQuery,
+    query_processor::{FreeBatch, FreeQuery, PaidBatch, QueryError},
     server::{
-        routes::{bad_request_response, response_body_to_query_string},
+        auth::{client_address, record_failed_auth},
+        routes::{
+            authorize_api_key, bad_request_response, internal_server_error_response,
+            response_body_to_query_string, unauthorized_response,
+        },
+        subscriptions::GRAPHQL_WS_PROTOCOL,
         ServerOptions,
     },
 };
@@ -25,7 +39,7 @@ use crate::{
 /// Later add receipt manager functions for paid queries
 pub async fn subgraph_queries(
     Extension(server): Extension<ServerOptions>,
-    id: axum::extract::Path<String>,
+    id: Path<String>,
     req: Request<axum::body::Body>,
 ) -> impl IntoResponse {
     let (parts, body) = req.into_parts();
@@ -35,6 +49,9 @@ pub async fn subgraph_queries(
         Ok(id) => id,
         Err(e) => return bad_request_response(&e.to_string()),
     };
+    if !server.is_serving(&subgraph_deployment_id) {
+        return disabled_deployment_response(&subgraph_deployment_id);
+    }
     let deployment_label = subgraph_deployment_id.ipfs_hash();
 
     let query_duration_timer = metrics::QUERY_DURATION
@@ -63,15 +80,17 @@ pub async fn subgraph_queries(
         receipt
     );
 
-    // Extract free query auth token
-    let auth_token = parts
-        .headers
-        .get(http::header::AUTHORIZATION)
-        .and_then(|t| t.to_str().ok());
     // determine if the query is paid or authenticated to be free
-    let free = auth_token.is_some()
-        && server.free_query_auth_token.is_some()
-        && auth_token.unwrap() == server.free_query_auth_token.as_deref().unwrap();
+    let free = match authorize_free_query(&server, &parts.headers, &subgraph_deployment_id) {
+        Ok(free) => free,
+        Err(response) => {
+            query_duration_timer.observe_duration();
+            return response;
+        }
+    };
+    if !free && parts.headers.contains_key(http::header::AUTHORIZATION) {
+        record_failed_auth("subgraphs", client_address(&parts.extensions));
+    }
 
     let query_string = match response_body_to_query_string(body).await {
         Ok(q) => q,
@@ -80,8 +99,58 @@ pub async fn subgraph_queries(
             return bad_request_response(&e.to_string());
         }
     };
+    // Reject malformed or overly expensive queries before any receipt is processed. A JSON
+    // array is a batch of queries, answered with an array of results.
+    let batch = if query_string.trim_start().starts_with('[') {
+        match validate_batch(&query_string, &server.query_limits) {
+            Ok(queries) => Some(queries),
+            Err(e) => {
+                query_duration_timer.observe_duration();
+                return bad_request_response(&e);
+            }
+        }
+    } else {
+        if let Err(e) = validate_request(&query_string, &server.query_limits) {
+            query_duration_timer.observe_duration();
+            return bad_request_response(&e);
+        }
+        None
+    };
 
     if free {
+        if server.free_query_require_allocation
+            && !server
+                .free_query_allowed_deployments
+                .contains(&subgraph_deployment_id)
+            && !server
+                .allocation_monitor
+                .is_deployment_allocated(&subgraph_deployment_id)
+                .await
+        {
+            query_duration_timer.observe_duration();
+            return (
+                StatusCode::FORBIDDEN,
+                Json(format!(
+                    "Deployment {} is not allocated to or allowed for free queries",
+                    deployment_label
+                )),
+            )
+                .into_response();
+        }
+
+        if let Some(queries) = batch {
+            let free_batch = FreeBatch {
+                subgraph_deployment_id,
+                queries,
+            };
+            let res = server.query_processor.execute_free_batch(free_batch).await;
+            query_duration_timer.observe_duration();
+            return match res {
+                Ok(res) => (StatusCode::OK, Json(res.result)).into_response(),
+                Err(e) => internal_server_error_response(&e.to_string()),
+            };
+        }
+
         let free_query = FreeQuery {
             subgraph_deployment_id,
             query: query_string,
@@ -98,6 +167,29 @@ pub async fn subgraph_queries(
             200 => (StatusCode::OK, Json(res.result)).into_response(),
             _ => bad_request_response("Bad response from Graph node"),
         }
+    } else if let (Some(receipt), Some(queries)) = (receipt, batch) {
+        let paid_batch = PaidBatch {
+            subgraph_deployment_id,
+            queries,
+            receipt: receipt.to_string(),
+        };
+
+        let res = server.query_processor.execute_paid_batch(paid_batch).await;
+        query_duration_timer.observe_duration();
+        match res {
+            Ok(res) => {
+                metrics::SUCCESSFUL_QUERIES
+                    .with_label_values(&[&deployment_label])
+                    .inc();
+                (StatusCode::OK, Json(res.result)).into_response()
+            }
+            Err(e) => {
+                metrics::FAILED_QUERIES
+                    .with_label_values(&[&deployment_label])
+                    .inc();
+                paid_query_error_response(e)
+            }
+        }
     } else if receipt.is_some() {
         let paid_query = crate::query_processor::PaidQuery {
             subgraph_deployment_id,
@@ -105,12 +197,16 @@ pub async fn subgraph_queries(
             receipt: receipt.unwrap().to_string(),
         };
 
-        // TODO: Emit IndexerErrorCode::IE032 on error
-        let res = server
-            .query_processor
-            .execute_paid_query(paid_query)
-            .await
-            .expect("Failed to execute paid query");
+        let res = match server.query_processor.execute_paid_query(paid_query).await {
+            Ok(res) => res,
+            Err(e) => {
+                query_duration_timer.observe_duration();
+                metrics::FAILED_QUERIES
+                    .with_label_values(&[&deployment_label])
+                    .inc();
+                return paid_query_error_response(e);
+            }
+        };
 
         query_duration_timer.observe_duration();
         match res.status {
@@ -137,3 +233,183 @@ pub async fn subgraph_queries(
         bad_request_response(error_body)
     }
 }
+
+/// Resolve a subgraph name to its current deployment and run the query against it on the
+/// free query path. The resolved deployment is echoed in the `graph-subgraph-deployment`
+/// response header.
+pub async fn subgraph_name_queries(
+    Extension(server): Extension<ServerOptions>,
+    Path(name): Path<String>,
+    req: Request<axum::body::Body>,
+) -> Response {
+    // Scopes of API keys are checked once the deployment is resolved
+    let known_api_key = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|t| t.to_str().ok())
+        .map_or(false, |t| server.api_keys.contains(t));
+    if !has_free_query_token(&server, req.headers()) && !known_api_key {
+        record_failed_auth("subgraphs", client_address(req.extensions()));
+        return unauthorized_response("Queries by subgraph name require a free query auth token");
+    }
+
+    let subgraph_name = SubgraphName::new(name.trim_start_matches('/'));
+    let deployment = match current_deployment(
+        &server.status_client,
+        &server.graph_node_status_endpoint,
+        &subgraph_name,
+    )
+    .await
+    {
+        Ok(Some(deployment)) => deployment,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(format!(
+                    "Subgraph {} has no current deployment",
+                    subgraph_name.to_string()
+                )),
+            )
+                .into_response()
+        }
+        Err(e) => return internal_server_error_response(&e.to_string()),
+    };
+
+    let mut response = subgraph_queries(Extension(server), Path(deployment.clone()), req)
+        .await
+        .into_response();
+    if let Ok(deployment) = HeaderValue::from_str(&deployment) {
+        response.headers_mut().insert(
+            HeaderName::from_static("graph-subgraph-deployment"),
+            deployment,
+        );
+    }
+    response
+}
+
+/// Upgrade to a WebSocket proxying `graphql-ws` subscriptions to graph node. Requires the
+/// free query auth token and a deployment that is allocated or allowed for free queries.
+pub async fn subgraph_subscriptions(
+    Extension(server): Extension<ServerOptions>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let proxy = match &server.subscription_proxy {
+        Some(proxy) => proxy.clone(),
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json("Subscriptions are not enabled".to_string()),
+            )
+                .into_response()
+        }
+    };
+    let subgraph_deployment_id = match SubgraphDeploymentID::new(&id) {
+        Ok(id) => id,
+        Err(e) => return bad_request_response(&e.to_string()),
+    };
+    if !server.is_serving(&subgraph_deployment_id) {
+        return disabled_deployment_response(&subgraph_deployment_id);
+    }
+    match authorize_free_query(&server, &headers, &subgraph_deployment_id) {
+        Ok(true) => {}
+        Ok(false) => {
+            record_failed_auth(
+                "subgraphs",
+                connect_info.map(|ConnectInfo(address)| address),
+            );
+            return unauthorized_response("Subscriptions require a free query auth token");
+        }
+        Err(response) => return response,
+    }
+    if !server
+        .free_query_allowed_deployments
+        .contains(&subgraph_deployment_id)
+        && !server
+            .allocation_monitor
+            .is_deployment_allocated(&subgraph_deployment_id)
+            .await
+    {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(format!(
+                "Deployment {} is not allocated to or allowed for subscriptions",
+                subgraph_deployment_id.ipfs_hash()
+            )),
+        )
+            .into_response();
+    }
+
+    let slot = match proxy.acquire() {
+        Some(slot) => slot,
+        None => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json("Too many subscription connections".to_string()),
+            )
+                .into_response()
+        }
+    };
+    ws.protocols([GRAPHQL_WS_PROTOCOL])
+        .on_upgrade(
+            move |socket| async move { proxy.proxy(socket, subgraph_deployment_id, slot).await },
+        )
+}
+
+/// Whether the request is authorized as a free query on the deployment, by the free query auth
+/// token or by an API key. Requests with an API key out of its scopes are rejected.
+fn authorize_free_query(
+    server: &ServerOptions,
+    headers: &HeaderMap,
+    deployment: &SubgraphDeploymentID,
+) -> Result<bool, Response> {
+    if has_free_query_token(server, headers) {
+        return Ok(true);
+    }
+    authorize_api_key(server, headers, ApiRoute::Subgraphs, Some(deployment))
+}
+
+/// Whether the request carries the free query auth token
+fn has_free_query_token(server: &ServerOptions, headers: &HeaderMap) -> bool {
+    let auth_token = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|t| t.to_str().ok())
+        .and_then(|t| t.strip_prefix("Bearer "));
+    match (auth_token, &server.free_query_auth_token) {
+        (Some(auth_token), Some(expected)) => expected.matches(auth_token),
+        _ => false,
+    }
+}
+
+fn disabled_deployment_response(deployment: &SubgraphDeploymentID) -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(format!(
+            "Queries on deployment {} have been disabled by the operator",
+            deployment.ipfs_hash()
+        )),
+    )
+        .into_response()
+}
+
+fn paid_query_error_response(error: QueryError) -> Response {
+    let status = match error {
+        QueryError::UnhealthyDeployment(_) => StatusCode::SERVICE_UNAVAILABLE,
+        QueryError::DeniedDeployment(_) => StatusCode::FORBIDDEN,
+        QueryError::WrongAllocationDeployment(..) => StatusCode::BAD_REQUEST,
+        QueryError::IneligibleAllocation(_) => StatusCode::BAD_REQUEST,
+        QueryError::InvalidReceipt(_) => StatusCode::BAD_REQUEST,
+        QueryError::IneligibleSender(_) => StatusCode::PAYMENT_REQUIRED,
+        QueryError::UnpricedQuery(_) => StatusCode::BAD_REQUEST,
+        QueryError::InsufficientReceiptValue(..) => StatusCode::PAYMENT_REQUIRED,
+        QueryError::DuplicateReceipt(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let error = IndexerError::new(IndexerErrorCode::IE032, Some(IndexerErrorCause::new(error)));
+    metrics::INDEXER_ERROR
+        .with_label_values(&[&error.code().to_string()])
+        .inc();
+    (status, Json(error.to_string())).into_response()
+}
@@ -0,0 +1,272 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use axum::extract::ws::{self, WebSocket};
+use futures_util::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::{
+    self, client::IntoClientRequest, http::HeaderValue, protocol::frame::coding::CloseCode,
+};
+use tracing::{debug, warn};
+
+use crate::{common::types::SubgraphDeploymentID, config::SubscriptionsConfig, metrics};
+
+/// WebSocket subprotocol of graph node subscriptions
+pub const GRAPHQL_WS_PROTOCOL: &str = "graphql-ws";
+
+/// Proxies `graphql-ws` subscriptions from clients to graph node, one upstream WebSocket
+/// per client connection, up to a maximum number of concurrent connections.
+#[derive(Debug, Clone)]
+pub struct SubscriptionProxy {
+    graph_node_endpoint: String,
+    max_connections: usize,
+    connections: Arc<AtomicUsize>,
+}
+
+/// Holds one of the proxy's connection slots until dropped
+#[derive(Debug)]
+pub struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+        metrics::SUBSCRIPTION_CONNECTIONS.dec();
+    }
+}
+
+impl SubscriptionProxy {
+    pub fn new(config: &SubscriptionsConfig) -> Self {
+        SubscriptionProxy {
+            graph_node_endpoint: config.graph_node_endpoint.clone(),
+            max_connections: config.max_connections,
+            connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Reserves a connection slot, unless all of them are taken
+    pub fn acquire(&self) -> Option<ConnectionSlot> {
+        self.connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |connections| {
+                (connections < self.max_connections).then_some(connections + 1)
+            })
+            .ok()?;
+        metrics::SUBSCRIPTION_CONNECTIONS.inc();
+        Some(ConnectionSlot(self.connections.clone()))
+    }
+
+    /// Relays messages between the client and the deployment's graph node subscriptions
+    /// endpoint until either side closes the connection
+    pub async fn proxy(
+        &self,
+        mut client: WebSocket,
+        deployment: SubgraphDeploymentID,
+        _slot: ConnectionSlot,
+    ) {
+        let deployment_label = deployment.ipfs_hash();
+        let url = format!(
+            "{}/subgraphs/id/{}",
+            self.graph_node_endpoint.trim_end_matches('/'),
+            deployment_label
+        );
+
+        let upstream = match url.as_str().into_client_request() {
+            Ok(mut request) => {
+                request.headers_mut().insert(
+                    "Sec-WebSocket-Protocol",
+                    HeaderValue::from_static(GRAPHQL_WS_PROTOCOL),
+                );
+                tokio_tungstenite::connect_async(request).await
+            }
+            Err(e) => Err(e),
+        };
+        let upstream = match upstream {
+            Ok((upstream, _)) => upstream,
+            Err(e) => {
+                warn!(
+                    "Failed to connect to graph node subscriptions at {}: {}",
+                    url, e
+                );
+                let _ = client.send(ws::Message::Close(None)).await;
+                return;
+            }
+        };
+
+        let (mut client_sender, mut client_receiver) = client.split();
+        let (mut upstream_sender, mut upstream_receiver) = upstream.split();
+        let mut subscriptions = ActiveSubscriptions::new(deployment_label);
+
+        // Pings are answered on each side of the proxy, everything else is relayed
+        loop {
+            tokio::select! {
+                message = client_receiver.next() => {
+                    let message = match message {
+                        Some(Ok(message)) => message,
+                        _ => break,
+                    };
+                    if let ws::Message::Text(text) = &message {
+                        subscriptions.update(text);
+                    }
+                    let close = matches!(message, ws::Message::Close(_));
+                    if let Some(message) = to_upstream(message) {
+                        if upstream_sender.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                    if close {
+                        break;
+                    }
+                }
+                message = upstream_receiver.next() => {
+                    let message = match message {
+                        Some(Ok(message)) => message,
+                        _ => break,
+                    };
+                    if let tungstenite::Message::Text(text) = &message {
+                        subscriptions.update(text);
+                    }
+                    let close = matches!(message, tungstenite::Message::Close(_));
+                    if let Some(message) = to_client(message) {
+                        if client_sender.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                    if close {
+                        break;
+                    }
+                }
+            }
+        }
+        debug!(
+            "Closed subscriptions connection for deployment {}",
+            subscriptions.deployment
+        );
+    }
+}
+
+fn to_upstream(message: ws::Message) -> Option<tungstenite::Message> {
+    match message {
+        ws::Message::Text(text) => Some(tungstenite::Message::Text(text)),
+        ws::Message::Binary(data) => Some(tungstenite::Message::Binary(data)),
+        ws::Message::Close(frame) => Some(tungstenite::Message::Close(frame.map(|frame| {
+            tungstenite::protocol::CloseFrame {
+                code: CloseCode::from(frame.code),
+                reason: frame.reason,
+            }
+        }))),
+        ws::Message::Ping(_) | ws::Message::Pong(_) => None,
+    }
+}
+
+fn to_client(message: tungstenite::Message) -> Option<ws::Message> {
+    match message {
+        tungstenite::Message::Text(text) => Some(ws::Message::Text(text)),
+        tungstenite::Message::Binary(data) => Some(ws::Message::Binary(data)),
+        tungstenite::Message::Close(frame) => {
+            Some(ws::Message::Close(frame.map(|frame| ws::CloseFrame {
+                code: frame.code.into(),
+                reason: frame.reason,
+            })))
+        }
+        _ => None,
+    }
+}
+
+/// Subscriptions running on a connection, tracked from the `start`, `stop` and `complete`
+/// messages of the `graphql-ws` protocol
+struct ActiveSubscriptions {
+    deployment: String,
+    ids: HashSet<String>,
+}
+
+impl ActiveSubscriptions {
+    fn new(deployment: String) -> Self {
+        ActiveSubscriptions {
+            deployment,
+            ids: HashSet::new(),
+        }
+    }
+
+    fn update(&mut self, message: &str) {
+        let message: serde_json::Value = match serde_json::from_str(message) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        let id = match message.get("id").and_then(|id| id.as_str()) {
+            Some(id) => id,
+            None => return,
+        };
+
+        let gauge = metrics::ACTIVE_SUBSCRIPTIONS.with_label_values(&[&self.deployment]);
+        match message.get("type").and_then(|t| t.as_str()) {
+            Some("start") => {
+                if self.ids.insert(id.to_string()) {
+                    gauge.inc();
+                }
+            }
+            Some("stop") | Some("complete") => {
+                if self.ids.remove(id) {
+                    gauge.dec();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Drop for ActiveSubscriptions {
+    fn drop(&mut self) {
+        metrics::ACTIVE_SUBSCRIPTIONS
+            .with_label_values(&[&self.deployment])
+            .sub(self.ids.len() as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_connections() {
+        let proxy = SubscriptionProxy::new(&SubscriptionsConfig {
+            graph_node_endpoint: "ws://localhost:8001".to_string(),
+            max_connections: 2,
+        });
+
+        let first = proxy.acquire().unwrap();
+        let _second = proxy.acquire().unwrap();
+        assert!(proxy.acquire().is_none());
+
+        drop(first);
+        assert!(proxy.acquire().is_some());
+    }
+
+    #[test]
+    fn tracks_active_subscriptions() {
+        let deployment = "QmTrackActiveSubscriptions".to_string();
+        let active = || {
+            metrics::ACTIVE_SUBSCRIPTIONS
+                .with_label_values(&[&deployment])
+                .get()
+        };
+
+        let mut subscriptions = ActiveSubscriptions::new(deployment.clone());
+        subscriptions.update(r#"{"type": "connection_init", "payload": {}}"#);
+        subscriptions
+            .update(r#"{"type": "start", "id": "1", "payload": {"query": "subscription { a }"}}"#);
+        subscriptions
+            .update(r#"{"type": "start", "id": "2", "payload": {"query": "subscription { b }"}}"#);
+        subscriptions.update(r#"{"type": "data", "id": "1", "payload": {"data": {"a": 1}}}"#);
+        assert_eq!(active(), 2);
+
+        subscriptions.update(r#"{"type": "stop", "id": "1"}"#);
+        subscriptions.update(r#"{"type": "stop", "id": "1"}"#);
+        assert_eq!(active(), 1);
+
+        // Subscriptions still running when the connection closes are no longer active
+        drop(subscriptions);
+        assert_eq!(active(), 0);
+    }
+}
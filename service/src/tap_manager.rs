@@ -1,14 +1,33 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
+use alloy_primitives::Address;
 use alloy_sol_types::Eip712Domain;
 use log::error;
-use sqlx::{types::BigDecimal, PgPool};
+use sqlx::{postgres::PgRow, types::BigDecimal, PgPool, Row};
 use tap_core::tap_manager::SignedReceipt;
+use tokio::sync::Mutex;
 
-use crate::{allocation_monitor, escrow_monitor, query_processor::QueryError};
+use crate::{
+    allocation_monitor, common::types::SubgraphDeploymentID, escrow_monitor,
+    query_processor::QueryError,
+};
+
+/// Number of new receipts whose senders are recovered per database query
+const RECEIPTS_PAGE_SIZE: i64 = 1000;
+
+/// Sender and value of each stored receipt, so that the sender of a receipt is only
+/// recovered once. Only the receipts still stored are kept.
+#[derive(Debug, Default)]
+struct ReceiptSenders {
+    /// Highest receipt ID loaded
+    last_id: i64,
+    /// Sender and value of the receipts by receipt ID, grouped by allocation ID as stored
+    allocations: HashMap<String, HashMap<i64, (Address, u128)>>,
+}
 
 #[derive(Clone, Debug)]
 pub struct TapManager {
@@ -16,6 +35,7 @@ pub struct TapManager {
     escrow_monitor: escrow_monitor::EscrowMonitor,
     pgpool: PgPool,
     domain_separator: Arc<Eip712Domain>,
+    receipt_senders: Arc<Mutex<ReceiptSenders>>,
 }
 
 impl TapManager {
@@ -30,82 +50,246 @@ impl TapManager {
             escrow_monitor,
             pgpool,
             domain_separator: Arc::new(domain_separator),
+            receipt_senders: Arc::new(Mutex::new(ReceiptSenders::default())),
         }
     }
 
-    /// Checks that the receipt refers to eligible allocation ID and TAP sender.
-    ///
-    /// If the receipt is valid, it is stored in the database.
+    /// Recovers the sender (signer) of a JSON-serialized receipt, if it can be parsed and the
+    /// sender is eligible. Anyone can sign a receipt, so the sender of a receipt is only
+    /// meaningful once it is known to have funds in escrow.
+    pub async fn eligible_receipt_sender(&self, receipt: &str) -> Option<Address> {
+        let receipt: SignedReceipt = serde_json::from_str(receipt).ok()?;
+        let sender = receipt
+            .recover_signer(self.domain_separator.as_ref())
+            .ok()?;
+        self.escrow_monitor
+            .is_sender_eligible(&sender)
+            .await
+            .then_some(sender)
+    }
+
+    /// Checks that the receipt refers to an eligible allocation ID for the queried deployment, and to an
+    /// eligible TAP sender, without storing it.
     ///
     /// The rest of the TAP receipt checks are expected to be performed out-of-band by the receipt aggregate requester
     /// service.
-    pub async fn verify_and_store_receipt(&self, receipt: SignedReceipt) -> Result<(), QueryError> {
+    pub async fn verify_receipt(
+        &self,
+        subgraph_deployment_id: &SubgraphDeploymentID,
+        receipt: &SignedReceipt,
+    ) -> Result<(), QueryError> {
         let allocation_id = &receipt.message.allocation_id;
-        if !self
+        match self
             .allocation_monitor
-            .is_allocation_eligible(allocation_id)
+            .allocation_deployment(allocation_id)
             .await
         {
-            return Err(QueryError::Other(anyhow::Error::msg(format!(
-                "Receipt's allocation ID ({}) is not eligible for this indexer",
-                allocation_id
-            ))));
+            None => {
+                return Err(QueryError::IneligibleAllocation(allocation_id.to_string()));
+            }
+            Some(deployment) if &deployment.id != subgraph_deployment_id => {
+                return Err(QueryError::WrongAllocationDeployment(
+                    allocation_id.to_string(),
+                    deployment.id.ipfs_hash(),
+                    subgraph_deployment_id.ipfs_hash(),
+                ));
+            }
+            Some(deployment) if deployment.is_denied() => {
+                return Err(QueryError::DeniedDeployment(deployment.id.ipfs_hash()));
+            }
+            Some(_) => {}
         }
 
         let receipt_signer = receipt
             .recover_signer(self.domain_separator.as_ref())
-            .map_err(|e| {
-                error!("Failed to recover receipt signer: {}", e);
-                QueryError::Other(anyhow::Error::from(e))
-            })?;
+            .map_err(|e| QueryError::InvalidReceipt(e.to_string()))?;
         if !self
             .escrow_monitor
             .is_sender_eligible(&receipt_signer)
             .await
         {
-            return Err(QueryError::Other(anyhow::Error::msg(format!(
-                "Receipt's sender ({}) is not eligible for this indexer",
-                receipt_signer
-            ))));
+            return Err(QueryError::IneligibleSender(receipt_signer.to_string()));
         }
 
+        Ok(())
+    }
+
+    /// Stores verified receipts in a single transaction, so that either all of them or none
+    /// are stored.
+    pub async fn store_receipts(&self, receipts: Vec<SignedReceipt>) -> Result<(), QueryError> {
         // TODO: consider doing this in another async task to avoid slowing down the paid query flow.
-        sqlx::query!(
-            r#"
+        let mut tx = self.pgpool.begin().await.map_err(store_error)?;
+        for receipt in receipts {
+            sqlx::query!(
+                r#"
                 INSERT INTO scalar_tap_receipts (allocation_id, timestamp_ns, receipt)
                 VALUES ($1, $2, $3)
             "#,
-            format!("{:?}", allocation_id)
-                .strip_prefix("0x")
-                .unwrap()
-                .to_owned(),
-            BigDecimal::from(receipt.message.timestamp_ns),
-            serde_json::to_value(receipt).map_err(|e| QueryError::Other(anyhow::Error::from(e)))?
-        )
-        .execute(&self.pgpool)
-        .await
-        .map_err(|e| {
-            error!("Failed to store receipt: {}", e);
-            QueryError::Other(anyhow::Error::from(e))
-        })?;
+                format!("{:?}", receipt.message.allocation_id)
+                    .strip_prefix("0x")
+                    .unwrap()
+                    .to_owned(),
+                BigDecimal::from(receipt.message.timestamp_ns),
+                serde_json::to_value(receipt)
+                    .map_err(|e| QueryError::Other(anyhow::Error::from(e)))?
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(store_error)?;
+        }
+        tx.commit().await.map_err(store_error)?;
 
         Ok(())
     }
+
+    /// Number and total value of the stored receipts of each sender. Receipts are removed once
+    /// aggregated into a RAV, so these are the receipts not yet aggregated.
+    ///
+    /// Senders are recovered only for the receipts stored since the last call. The receipt IDs
+    /// of each allocation are compared with the database to pick up removed receipts and
+    /// receipts committed after ones with higher IDs.
+    pub async fn outstanding_receipts(
+        &self,
+    ) -> Result<HashMap<Address, OutstandingReceipts>, anyhow::Error> {
+        let mut receipt_senders = self.receipt_senders.lock().await;
+
+        loop {
+            let rows = sqlx::query(
+                r#"
+                    SELECT id, allocation_id, receipt
+                    FROM scalar_tap_receipts
+                    WHERE id > $1
+                    ORDER BY id
+                    LIMIT $2
+                "#,
+            )
+            .bind(receipt_senders.last_id)
+            .bind(RECEIPTS_PAGE_SIZE)
+            .fetch_all(&self.pgpool)
+            .await?;
+
+            for row in &rows {
+                let id = self.cache_receipt(&mut receipt_senders, row)?;
+                receipt_senders.last_id = id;
+            }
+            if (rows.len() as i64) < RECEIPTS_PAGE_SIZE {
+                break;
+            }
+        }
+
+        // Receipts are removed once aggregated, and IDs may be committed out of order, so
+        // the cached receipts of each allocation are made to match the stored ones
+        let stored = sqlx::query(
+            r#"
+                SELECT allocation_id, array_agg(id) AS ids
+                FROM scalar_tap_receipts
+                WHERE id <= $1
+                GROUP BY allocation_id
+            "#,
+        )
+        .bind(receipt_senders.last_id)
+        .fetch_all(&self.pgpool)
+        .await?
+        .iter()
+        .map(|row| Ok((row.try_get("allocation_id")?, row.try_get("ids")?)))
+        .collect::<Result<HashMap<String, Vec<i64>>, sqlx::Error>>()?;
+
+        receipt_senders
+            .allocations
+            .retain(|allocation_id, _| stored.contains_key(allocation_id));
+        let mut missing = vec![];
+        for (allocation_id, ids) in stored {
+            let ids = ids.into_iter().collect::<HashSet<i64>>();
+            let receipts = receipt_senders
+                .allocations
+                .entry(allocation_id)
+                .or_default();
+            receipts.retain(|id, _| ids.contains(id));
+            missing.extend(ids.into_iter().filter(|id| !receipts.contains_key(id)));
+        }
+        if !missing.is_empty() {
+            let rows = sqlx::query(
+                r#"
+                    SELECT id, allocation_id, receipt
+                    FROM scalar_tap_receipts
+                    WHERE id = ANY($1)
+                "#,
+            )
+            .bind(&missing)
+            .fetch_all(&self.pgpool)
+            .await?;
+            for row in &rows {
+                self.cache_receipt(&mut receipt_senders, row)?;
+            }
+        }
+
+        let mut outstanding: HashMap<Address, OutstandingReceipts> = HashMap::new();
+        for (sender, value) in receipt_senders
+            .allocations
+            .values()
+            .flat_map(|receipts| receipts.values())
+        {
+            let totals = outstanding.entry(*sender).or_default();
+            totals.count += 1;
+            totals.value += value;
+        }
+        Ok(outstanding)
+    }
+
+    /// Recovers the sender of a stored receipt and caches it, returning the receipt ID
+    fn cache_receipt(
+        &self,
+        receipt_senders: &mut ReceiptSenders,
+        row: &PgRow,
+    ) -> Result<i64, anyhow::Error> {
+        let id: i64 = row.try_get("id")?;
+        let allocation_id: String = row.try_get("allocation_id")?;
+        let sender = self.receipt_sender_and_value(row.try_get("receipt")?)?;
+        receipt_senders
+            .allocations
+            .entry(allocation_id)
+            .or_default()
+            .insert(id, sender);
+        Ok(id)
+    }
+
+    fn receipt_sender_and_value(
+        &self,
+        receipt: serde_json::Value,
+    ) -> Result<(Address, u128), anyhow::Error> {
+        let receipt: SignedReceipt = serde_json::from_value(receipt)?;
+        let sender = receipt.recover_signer(self.domain_separator.as_ref())?;
+        Ok((sender, receipt.message.value))
+    }
+}
+
+fn store_error(e: sqlx::Error) -> QueryError {
+    error!("Failed to store receipt: {}", e);
+    QueryError::Other(anyhow::Error::from(e))
+}
+
+/// Stored receipts of a sender
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OutstandingReceipts {
+    pub count: u64,
+    pub value: u128,
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
     use std::str::FromStr;
 
     use alloy_primitives::Address;
     use alloy_sol_types::{eip712_domain, Eip712Domain};
     use ethers::signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer};
+    use ethers_core::types::U256;
     use sqlx::postgres::PgListener;
 
     use tap_core::tap_manager::SignedReceipt;
     use tap_core::{eip_712_signed_message::EIP712SignedMessage, tap_receipt::Receipt};
 
     use crate::allocation_monitor::AllocationMonitor;
+    use crate::common::allocation::SubgraphDeployment;
 
     use super::*;
 
@@ -171,7 +355,17 @@ mod test {
 
         // Mock allocation monitor
         let mut mock_allocation_monitor = AllocationMonitor::faux();
-        faux::when!(mock_allocation_monitor.is_allocation_eligible).then_return(true);
+        let subgraph_deployment_id =
+            SubgraphDeploymentID::new("QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ").unwrap();
+        faux::when!(mock_allocation_monitor.allocation_deployment).then_return(Some(
+            SubgraphDeployment {
+                id: subgraph_deployment_id.clone(),
+                denied_at: Some(0),
+                staked_tokens: U256::from(0),
+                signalled_tokens: U256::from(0),
+                query_fees_amount: U256::from(0),
+            },
+        ));
 
         // Mock escrow monitor
         let mut mock_escrow_monitor = escrow_monitor::EscrowMonitor::faux();
@@ -185,7 +379,11 @@ mod test {
         );
 
         tap_manager
-            .verify_and_store_receipt(signed_receipt.clone())
+            .verify_receipt(&subgraph_deployment_id, &signed_receipt)
+            .await
+            .unwrap();
+        tap_manager
+            .store_receipts(vec![signed_receipt.clone()])
             .await
             .unwrap();
 
@@ -210,4 +408,248 @@ mod test {
         assert_eq!(notification_payload["timestamp_ns"], u64::MAX);
         assert!(notification_payload["id"].is_u64());
     }
+
+    #[tokio::test]
+    async fn test_rejects_receipt_for_other_deployment() {
+        let allocation_id =
+            Address::from_str("0xdeadbeefcafebabedeadbeefcafebabedeadbeef").unwrap();
+        let allocation_deployment_id =
+            SubgraphDeploymentID::new("QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ").unwrap();
+        let queried_deployment_id =
+            SubgraphDeploymentID::new("QmVhiE4nax9i86UBnBmQCYDzvjWuwHShYh7aspGPQhU5Sj").unwrap();
+
+        let mut mock_allocation_monitor = AllocationMonitor::faux();
+        faux::when!(mock_allocation_monitor.allocation_deployment).then_return(Some(
+            SubgraphDeployment {
+                id: allocation_deployment_id.clone(),
+                denied_at: None,
+                staked_tokens: U256::from(0),
+                signalled_tokens: U256::from(0),
+                query_fees_amount: U256::from(0),
+            },
+        ));
+        let mut mock_escrow_monitor = escrow_monitor::EscrowMonitor::faux();
+        faux::when!(mock_escrow_monitor.is_sender_eligible).then_return(true);
+
+        // The receipt is rejected before reaching the database
+        let tap_manager = TapManager::new(
+            PgPool::connect_lazy("postgres://localhost/unused").unwrap(),
+            mock_allocation_monitor,
+            mock_escrow_monitor,
+            domain(),
+        );
+
+        let result = tap_manager
+            .verify_receipt(
+                &queried_deployment_id,
+                &create_signed_receipt(allocation_id, 1, 1, 1).await,
+            )
+            .await;
+        match result {
+            Err(QueryError::WrongAllocationDeployment(allocation, deployment, queried)) => {
+                assert_eq!(allocation, allocation_id.to_string());
+                assert_eq!(deployment, allocation_deployment_id.ipfs_hash());
+                assert_eq!(queried, queried_deployment_id.ipfs_hash());
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejects_ineligible_allocations_and_senders() {
+        let allocation_id =
+            Address::from_str("0xdeadbeefcafebabedeadbeefcafebabedeadbeef").unwrap();
+        let subgraph_deployment_id =
+            SubgraphDeploymentID::new("QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ").unwrap();
+        let receipt = create_signed_receipt(allocation_id, 1, 1, 1).await;
+
+        // Unknown allocation
+        let mut mock_allocation_monitor = AllocationMonitor::faux();
+        faux::when!(mock_allocation_monitor.allocation_deployment).then_return(None);
+        let mut mock_escrow_monitor = escrow_monitor::EscrowMonitor::faux();
+        faux::when!(mock_escrow_monitor.is_sender_eligible).then_return(true);
+        let tap_manager = TapManager::new(
+            PgPool::connect_lazy("postgres://localhost/unused").unwrap(),
+            mock_allocation_monitor,
+            mock_escrow_monitor,
+            domain(),
+        );
+        match tap_manager
+            .verify_receipt(&subgraph_deployment_id, &receipt)
+            .await
+        {
+            Err(QueryError::IneligibleAllocation(allocation)) => {
+                assert_eq!(allocation, allocation_id.to_string())
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        // Sender without funds in escrow
+        let mut mock_allocation_monitor = AllocationMonitor::faux();
+        faux::when!(mock_allocation_monitor.allocation_deployment).then_return(Some(
+            SubgraphDeployment {
+                id: subgraph_deployment_id.clone(),
+                denied_at: None,
+                staked_tokens: U256::from(0),
+                signalled_tokens: U256::from(0),
+                query_fees_amount: U256::from(0),
+            },
+        ));
+        let mut mock_escrow_monitor = escrow_monitor::EscrowMonitor::faux();
+        faux::when!(mock_escrow_monitor.is_sender_eligible).then_return(false);
+        let tap_manager = TapManager::new(
+            PgPool::connect_lazy("postgres://localhost/unused").unwrap(),
+            mock_allocation_monitor,
+            mock_escrow_monitor,
+            domain(),
+        );
+        let (_, sender) = keys();
+        match tap_manager
+            .verify_receipt(&subgraph_deployment_id, &receipt)
+            .await
+        {
+            Err(QueryError::IneligibleSender(receipt_sender)) => {
+                assert_eq!(receipt_sender, sender.to_string())
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[ignore]
+    #[sqlx::test]
+    async fn test_stores_all_receipts_or_none(pgpool: PgPool) {
+        let allocation_id =
+            Address::from_str("0xdeadbeefcafebabedeadbeefcafebabedeadbeef").unwrap();
+        let tap_manager = TapManager::new(
+            pgpool.clone(),
+            AllocationMonitor::faux(),
+            escrow_monitor::EscrowMonitor::faux(),
+            domain(),
+        );
+
+        // The second receipt fails to insert, after the first one was
+        sqlx::query(
+            "ALTER TABLE scalar_tap_receipts ADD CONSTRAINT test_timestamp CHECK (timestamp_ns < 100)",
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        assert!(tap_manager
+            .store_receipts(vec![
+                create_signed_receipt(allocation_id, 1, 1, 10).await,
+                create_signed_receipt(allocation_id, 2, 200, 10).await,
+            ])
+            .await
+            .is_err());
+
+        let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM scalar_tap_receipts")
+            .fetch_one(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(stored, 0);
+    }
+
+    #[ignore]
+    #[sqlx::test]
+    async fn test_outstanding_receipts(pgpool: PgPool) {
+        let allocation_id =
+            Address::from_str("0xdeadbeefcafebabedeadbeefcafebabedeadbeef").unwrap();
+        let subgraph_deployment_id =
+            SubgraphDeploymentID::new("QmcPHxcC2ZN7m79XfYZ77YmF4t9UCErv87a9NFKrSLWKtJ").unwrap();
+
+        let mut mock_allocation_monitor = AllocationMonitor::faux();
+        faux::when!(mock_allocation_monitor.allocation_deployment).then_return(Some(
+            SubgraphDeployment {
+                id: subgraph_deployment_id.clone(),
+                denied_at: None,
+                staked_tokens: U256::from(0),
+                signalled_tokens: U256::from(0),
+                query_fees_amount: U256::from(0),
+            },
+        ));
+        let mut mock_escrow_monitor = escrow_monitor::EscrowMonitor::faux();
+        faux::when!(mock_escrow_monitor.is_sender_eligible).then_return(true);
+
+        let tap_manager = TapManager::new(
+            pgpool.clone(),
+            mock_allocation_monitor,
+            mock_escrow_monitor,
+            domain(),
+        );
+
+        for (nonce, value) in [(1, 10), (2, 32)] {
+            tap_manager
+                .store_receipts(vec![
+                    create_signed_receipt(allocation_id, nonce, nonce, value).await,
+                ])
+                .await
+                .unwrap();
+        }
+
+        let (_, sender) = keys();
+        let outstanding = tap_manager.outstanding_receipts().await.unwrap();
+        assert_eq!(outstanding.len(), 1);
+        assert_eq!(
+            outstanding[&sender],
+            OutstandingReceipts {
+                count: 2,
+                value: 42
+            }
+        );
+
+        // New receipts are added and receipts aggregated into a RAV are removed
+        tap_manager
+            .store_receipts(vec![create_signed_receipt(allocation_id, 3, 3, 100).await])
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM scalar_tap_receipts WHERE timestamp_ns = 1")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let outstanding = tap_manager.outstanding_receipts().await.unwrap();
+        assert_eq!(
+            outstanding[&sender],
+            OutstandingReceipts {
+                count: 2,
+                value: 132
+            }
+        );
+
+        // A receipt committed late with a lower ID replaces a removed one, leaving the count
+        // of receipts unchanged
+        sqlx::query("DELETE FROM scalar_tap_receipts WHERE timestamp_ns = 2")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        sqlx::query(
+            r#"
+                INSERT INTO scalar_tap_receipts (id, allocation_id, timestamp_ns, receipt)
+                VALUES (1, $1, 4, $2)
+            "#,
+        )
+        .bind(
+            format!("{:?}", allocation_id)
+                .strip_prefix("0x")
+                .unwrap()
+                .to_owned(),
+        )
+        .bind(serde_json::to_value(create_signed_receipt(allocation_id, 4, 4, 7).await).unwrap())
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        let outstanding = tap_manager.outstanding_receipts().await.unwrap();
+        assert_eq!(
+            outstanding[&sender],
+            OutstandingReceipts {
+                count: 2,
+                value: 107
+            }
+        );
+
+        sqlx::query("DELETE FROM scalar_tap_receipts")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        assert!(tap_manager.outstanding_receipts().await.unwrap().is_empty());
+    }
 }